@@ -3,7 +3,7 @@ use glam::IVec3;
 use lazy_static::lazy_static;
 use crate::fname;
 use crate::fname::FName;
-use crate::world::{World, IBlockState, Dimension};
+use crate::world::{World, BlockState, IBlockState, Dimension};
 use crate::geom::BlockPos;
 use crate::{CommonFNames, make_a_hash_map, make_a_hash_set};
 
@@ -223,3 +223,8 @@ pub fn get_fluid(state: &IBlockState) -> Fluid {
         Fluid::Empty
     }
 }
+
+pub fn is_air(state: &BlockState) -> bool {
+    let block = &state.block;
+    block == &CommonFNames.AIR || block == &CommonFNames.CAVE_AIR || block == &CommonFNames.VOID_AIR
+}
@@ -0,0 +1,143 @@
+use ahash::AHashMap;
+use lazy_static::lazy_static;
+use crate::fname;
+use crate::fname::FName;
+use crate::geom::{Axis, Direction};
+use crate::world::{BlockState, IBlockState};
+
+/// How the value of a block state property changes when the block is rotated or mirrored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PropertyTransform {
+    /// A direction, e.g. `facing=north`.
+    Direction,
+    /// `x`, `y` or `z`, where `x` and `z` swap on quarter turns.
+    Axis,
+    /// One of 16 directions starting from south, e.g. signs, banners and skulls.
+    Rotation16,
+    /// The property is named after a horizontal direction, e.g. fence and redstone connections. The value moves to the
+    /// property of the transformed direction.
+    Connection,
+    /// Stair shapes, which swap left and right when mirrored, and rail shapes, which are made of directions.
+    Shape,
+    /// `left` and `right`, which swap when mirrored, e.g. door hinges and double chests.
+    Handedness,
+    /// Two directions joined by an underscore, e.g. `orientation=down_east` of jigsaws.
+    Orientation,
+}
+
+lazy_static! {
+    static ref PROPERTY_TRANSFORMS: AHashMap<FName, PropertyTransform> = {
+        let mut map = AHashMap::new();
+        map.insert(fname::from_str("facing"), PropertyTransform::Direction);
+        map.insert(fname::from_str("axis"), PropertyTransform::Axis);
+        map.insert(fname::from_str("rotation"), PropertyTransform::Rotation16);
+        for dir in Direction::HORIZONTAL {
+            map.insert(fname::from_str(dir.name()), PropertyTransform::Connection);
+        }
+        map.insert(fname::from_str("shape"), PropertyTransform::Shape);
+        map.insert(fname::from_str("hinge"), PropertyTransform::Handedness);
+        map.insert(fname::from_str("type"), PropertyTransform::Handedness);
+        map.insert(fname::from_str("orientation"), PropertyTransform::Orientation);
+        map
+    };
+}
+
+/// Mirrors across the X axis if `mirror` is set, then rotates by `rotation` quarter turns clockwise when viewed from
+/// above.
+pub fn transform_direction(mut dir: Direction, rotation: u8, mirror: bool) -> Direction {
+    if mirror && dir.axis() == Axis::X {
+        dir = dir.opposite();
+    }
+    for _ in 0..rotation % 4 {
+        dir = dir.rotate_y_clockwise();
+    }
+    dir
+}
+
+/// Transforms the properties of a block state which depend on direction, in the same way as
+/// [`transform_direction`].
+pub fn transform_block_state(state: &IBlockState, rotation: u8, mirror: bool) -> IBlockState {
+    if (rotation % 4 == 0 && !mirror) || state.properties.is_empty() {
+        return state.clone();
+    }
+    let mut new_state = BlockState::new(&state.block);
+    for (key, value) in &state.properties {
+        let transform = match PROPERTY_TRANSFORMS.get(key) {
+            Some(transform) => *transform,
+            None => {
+                new_state.properties.insert(key.clone(), value.clone());
+                continue;
+            }
+        };
+        let value_str = value.to_nice_string();
+        match transform {
+            PropertyTransform::Connection => {
+                let dir = Direction::from_name(&key.to_nice_string()).unwrap();
+                new_state.properties.insert(fname::from_str(transform_direction(dir, rotation, mirror).name()), value.clone());
+            }
+            _ => {
+                let new_value = transform_value(transform, &value_str, rotation, mirror)
+                    .map(|new_value| fname::from_str(&new_value))
+                    .unwrap_or_else(|| value.clone());
+                new_state.properties.insert(key.clone(), new_value);
+            }
+        }
+    }
+    IBlockState::new(new_state)
+}
+
+fn transform_value(transform: PropertyTransform, value: &str, rotation: u8, mirror: bool) -> Option<String> {
+    let transform_dir = |name: &str| Direction::from_name(name).map(|dir| transform_direction(dir, rotation, mirror));
+    match transform {
+        PropertyTransform::Direction => transform_dir(value).map(|dir| dir.name().to_owned()),
+        PropertyTransform::Axis => match value {
+            "x" if rotation % 2 == 1 => Some("z".to_owned()),
+            "z" if rotation % 2 == 1 => Some("x".to_owned()),
+            _ => None,
+        },
+        PropertyTransform::Rotation16 => {
+            let value = value.parse::<u32>().ok()?;
+            let value = if mirror { (16 - value) % 16 } else { value };
+            Some(((value + 4 * (rotation % 4) as u32) % 16).to_string())
+        }
+        PropertyTransform::Connection => None,
+        PropertyTransform::Shape => {
+            if let Some(side) = value.strip_prefix("inner_").or_else(|| value.strip_prefix("outer_")) {
+                // stairs, which keep their shape when rotated
+                if !mirror {
+                    return None;
+                }
+                return Some(format!("{}_{}", &value[..5], swap_handedness(side)?));
+            }
+            if let Some(dir) = value.strip_prefix("ascending_") {
+                return Some(format!("ascending_{}", transform_dir(dir)?.name()));
+            }
+            // flat rails, the two directions they connect
+            let (first, second) = value.split_once('_')?;
+            let (first, second) = (transform_dir(first)?, transform_dir(second)?);
+            let (first, second) = if first.axis() == Axis::Z || (second.axis() != Axis::Z && first == Direction::West) {
+                (first, second)
+            } else {
+                (second, first)
+            };
+            Some(match (first, second) {
+                (Direction::South, Direction::North) => "north_south".to_owned(),
+                (Direction::West, Direction::East) => "east_west".to_owned(),
+                _ => format!("{}_{}", first.name(), second.name()),
+            })
+        }
+        PropertyTransform::Handedness => if mirror { swap_handedness(value).map(str::to_owned) } else { None },
+        PropertyTransform::Orientation => {
+            let (front, top) = value.split_once('_')?;
+            Some(format!("{}_{}", transform_dir(front)?.name(), transform_dir(top)?.name()))
+        }
+    }
+}
+
+fn swap_handedness(value: &str) -> Option<&'static str> {
+    match value {
+        "left" => Some("right"),
+        "right" => Some("left"),
+        _ => None,
+    }
+}
@@ -1,3 +1,27 @@
+pub const V1_21_10: u32 = 4556;
+pub const V1_21_9: u32 = 4554;
+pub const V1_21_8: u32 = 4440;
+pub const V1_21_7: u32 = 4438;
+pub const V1_21_6: u32 = 4435;
+pub const V1_21_5: u32 = 4325;
+pub const V1_21_4: u32 = 4189;
+pub const V1_21_3: u32 = 4082;
+pub const V1_21_2: u32 = 4080;
+pub const V1_21_1: u32 = 3955;
+pub const V1_21: u32 = 3953;
+pub const V1_20_6: u32 = 3839;
+pub const V1_20_5: u32 = 3837;
+pub const V1_20_4: u32 = 3700;
+pub const V1_20_3: u32 = 3698;
+pub const V1_20_2: u32 = 3578;
+pub const V1_20_1: u32 = 3465;
+pub const V1_20: u32 = 3463;
+pub const V1_19_4: u32 = 3337;
+pub const V1_19_3: u32 = 3218;
+pub const V1_19_2: u32 = 3120;
+pub const V1_19_1: u32 = 3117;
+pub const V1_19: u32 = 3105;
+pub const V1_18_2: u32 = 2975;
 pub const V1_18_1: u32 = 2865;
 pub const V1_18: u32 = 2860;
 pub const V1_17_1: u32 = 2730;
@@ -33,4 +57,30 @@ pub const V1_9_3: u32 = 183;
 pub const V1_9_2: u32 = 176;
 pub const V1_9_1: u32 = 175;
 pub const V1_9: u32 = 169;
-pub const V1_8: u32 = 99;
\ No newline at end of file
+pub const V1_8: u32 = 99;
+
+lazy_static::lazy_static! {
+    static ref VERSIONS: Vec<(String, u32)> = {
+        let mut versions: Vec<(String, u32)> = include_str!("../../res/versions.csv").lines()
+            .map(|line| line.split('#').next().unwrap().trim())
+            .filter(|line| !line.is_empty())
+            // the header
+            .skip(1)
+            .map(|line| {
+                let (name, id) = line.split_once(',').unwrap();
+                (name.to_owned(), id.parse().unwrap())
+            })
+            .collect();
+        versions.sort_by_key(|(_, id)| std::cmp::Reverse(*id));
+        versions
+    };
+}
+
+/// The versions we know the data version of, with their data versions, newest first.
+pub fn get_versions() -> &'static [(String, u32)] {
+    &VERSIONS
+}
+
+pub fn get_version_name(data_version: u32) -> Option<&'static str> {
+    VERSIONS.iter().find(|(_, id)| *id == data_version).map(|(name, _)| name.as_str())
+}
@@ -0,0 +1,101 @@
+use ahash::AHashMap;
+use lazy_static::lazy_static;
+use crate::convert::data_versions::V1_13;
+use crate::convert::registries;
+use crate::fname;
+use crate::fname::FName;
+use crate::world::{BlockState, IBlockState, IBlockStateExtensions};
+
+lazy_static! {
+    // 1.13 block states of pre-flattening numeric IDs and data values
+    static ref LEGACY_BLOCK_STATES: AHashMap<(u16, u8), IBlockState> = {
+        let json: AHashMap<String, String> = serde_json::from_str(include_str!("../../res/legacy_block_states.json"))
+            .expect("Failed to parse legacy block states");
+        json.into_iter().map(|(key, state)| {
+            let (id, data) = key.split_once(':').expect("Legacy block state key should be id:data");
+            let state: BlockState = state.parse().unwrap_or_else(|err| panic!("Invalid legacy block state \"{}\": {}", state, err));
+            ((id.parse().unwrap(), data.parse().unwrap()), IBlockState::new(state))
+        }).collect()
+    };
+
+    // the legacy states of each block, lowest numeric ID and data value first
+    static ref LEGACY_STATES_BY_BLOCK: AHashMap<FName, Vec<((u16, u8), IBlockState)>> = {
+        let mut states: Vec<_> = LEGACY_BLOCK_STATES.iter().map(|(key, state)| (*key, state.clone())).collect();
+        states.sort_by_key(|(key, _)| *key);
+        let mut by_block: AHashMap<FName, Vec<_>> = AHashMap::new();
+        for (key, state) in states {
+            by_block.entry(state.block.clone()).or_default().push((key, state));
+        }
+        by_block
+    };
+}
+
+/// Maps a numeric block ID and data value to its block state in 1.13, falling back to data value 0 of the same block
+/// for unused data values. Returns `None` for unknown IDs.
+pub fn flatten_block_state(id: u16, data: u8) -> Option<IBlockState> {
+    LEGACY_BLOCK_STATES.get(&(id, data & 15))
+        .or_else(|| LEGACY_BLOCK_STATES.get(&(id, 0)))
+        .cloned()
+}
+
+/// Maps a 1.13 block state back to its numeric block ID and data value. States that weren't stored before the
+/// flattening, such as fence connections, use the legacy state of the block with the most matching properties. Blocks
+/// that didn't exist yet become air.
+pub fn unflatten_block_state(state: &IBlockState) -> (u16, u8) {
+    let legacy_states = match LEGACY_STATES_BY_BLOCK.get(&state.block) {
+        Some(legacy_states) => legacy_states,
+        None => return (0, 0),
+    };
+    let matching_properties = |legacy_state: &IBlockState| {
+        state.properties.iter().filter(|(key, value)| legacy_state.properties.get(*key) == Some(*value)).count()
+    };
+    // the first of the best matches, which has the lowest ID and data value
+    legacy_states.iter()
+        .rev()
+        .max_by_key(|(_, legacy_state)| matching_properties(legacy_state))
+        .map(|(key, _)| *key)
+        .unwrap_or((0, 0))
+}
+
+/// Like [`unflatten_block_state`], but for a block state of the prevailing version.
+pub fn downgrade_to_legacy_block_state(state: &IBlockState, prevailing_version: u32) -> (u16, u8) {
+    unflatten_block_state(&registries::rename_block_state(state, prevailing_version, V1_13))
+}
+
+/// Like [`flatten_block_state`], but renamed to the prevailing version.
+pub fn upgrade_legacy_block_state(id: u16, data: u8, prevailing_version: u32) -> Option<IBlockState> {
+    flatten_block_state(id, data).map(|state| registries::rename_block_state(&state, V1_13, prevailing_version))
+}
+
+/// Before the flattening, the two halves of doors and tall plants each stored part of the state. Given the flattened
+/// states of a block and the block above it, returns the fixed up states of both if they form such a pair.
+pub fn fix_split_block(lower: &IBlockState, upper: &IBlockState) -> Option<(IBlockState, IBlockState)> {
+    let half = fname::from_str("half");
+    if lower.properties.get(&half) != Some(&fname::from_str("lower")) || upper.properties.get(&half) != Some(&fname::from_str("upper")) {
+        return None;
+    }
+    if lower.block.to_nice_string().ends_with("_door") {
+        if upper.block != lower.block {
+            return None;
+        }
+        let mut new_lower = lower.clone();
+        let mut new_upper = upper.clone();
+        for key in ["hinge", "powered"] {
+            if let Some(value) = upper.properties.get(&fname::from_str(key)) {
+                new_lower = new_lower.with_property(fname::from_str(key), value.clone());
+            }
+        }
+        for key in ["facing", "open"] {
+            if let Some(value) = lower.properties.get(&fname::from_str(key)) {
+                new_upper = new_upper.with_property(fname::from_str(key), value.clone());
+            }
+        }
+        return Some((new_lower, new_upper));
+    }
+    // the upper half of tall plants is always flattened to a sunflower
+    const DOUBLE_PLANTS: [&str; 6] = ["sunflower", "lilac", "tall_grass", "large_fern", "rose_bush", "peony"];
+    if upper.block == fname::from_str("sunflower") && DOUBLE_PLANTS.contains(&lower.block.to_nice_string().as_str()) {
+        return Some((lower.clone(), lower.with_property(half, fname::from_str("upper"))));
+    }
+    None
+}
@@ -0,0 +1,278 @@
+use std::collections::BTreeMap;
+use nbt::Value;
+use crate::convert::registries;
+use crate::fname::FName;
+use crate::util::ResourceLocation;
+
+// the keys of block entity and entity NBT which hold an item stack or a list of them
+const ITEM_KEYS: [&str; 7] = ["Items", "Item", "item", "Book", "RecordItem", "ArmorItems", "HandItems"];
+
+fn into_map(value: Value) -> Option<BTreeMap<String, Value>> {
+    match value {
+        Value::Compound(compound) => Some(compound.into_iter().collect()),
+        _ => None,
+    }
+}
+
+fn from_map(map: BTreeMap<String, Value>) -> Value {
+    Value::Compound(map.into_iter().collect())
+}
+
+// applies `f` to the value if it is a compound
+fn modify_compound(value: &mut Value, f: impl FnOnce(&mut BTreeMap<String, Value>)) {
+    if let Value::Compound(compound) = value {
+        let mut map = std::mem::take(compound).into_iter().collect();
+        f(&mut map);
+        *compound = map.into_iter().collect();
+    }
+}
+
+fn get_int(value: &Value) -> Option<i32> {
+    match *value {
+        Value::Byte(v) => Some(v as i32),
+        Value::Short(v) => Some(v as i32),
+        Value::Int(v) => Some(v),
+        Value::Long(v) => Some(v as i32),
+        _ => None,
+    }
+}
+
+/// Converts an item stack from before 1.20.5, where the count is a byte and the rest of the data is in `tag`, to its
+/// data components. Tags without a component are kept in `minecraft:custom_data`.
+pub fn upgrade_item_stack(item: &mut BTreeMap<String, Value>) {
+    if let Some(count) = item.remove("Count") {
+        item.insert("count".to_owned(), Value::Int(get_int(&count).unwrap_or(1)));
+    }
+    let mut tag = match item.remove("tag").and_then(into_map) {
+        Some(tag) => tag,
+        None => return,
+    };
+    let mut components = BTreeMap::new();
+    if let Some(damage) = tag.remove("Damage").as_ref().and_then(get_int) {
+        if damage != 0 {
+            components.insert("minecraft:damage".to_owned(), Value::Int(damage));
+        }
+    }
+    if let Some(repair_cost) = tag.remove("RepairCost").as_ref().and_then(get_int) {
+        components.insert("minecraft:repair_cost".to_owned(), Value::Int(repair_cost));
+    }
+    if let Some(custom_model_data) = tag.remove("CustomModelData").as_ref().and_then(get_int) {
+        components.insert("minecraft:custom_model_data".to_owned(), Value::Int(custom_model_data));
+    }
+    if tag.remove("Unbreakable").as_ref().and_then(get_int).unwrap_or(0) != 0 {
+        components.insert("minecraft:unbreakable".to_owned(), from_map(BTreeMap::new()));
+    }
+    for (tag_name, component_name) in [("Enchantments", "minecraft:enchantments"), ("StoredEnchantments", "minecraft:stored_enchantments")] {
+        if let Some(Value::List(enchantments)) = tag.remove(tag_name) {
+            let levels: BTreeMap<_, _> = enchantments.into_iter().filter_map(into_map).filter_map(|enchantment| {
+                match (enchantment.get("id"), enchantment.get("lvl").and_then(get_int)) {
+                    (Some(Value::String(id)), Some(level)) => Some((id.clone(), Value::Int(level))),
+                    _ => None,
+                }
+            }).collect();
+            let mut component = BTreeMap::new();
+            component.insert("levels".to_owned(), from_map(levels));
+            components.insert(component_name.to_owned(), from_map(component));
+        }
+    }
+    if let Some(mut display) = tag.remove("display").and_then(into_map) {
+        if let Some(name) = display.remove("Name") {
+            components.insert("minecraft:custom_name".to_owned(), name);
+        }
+        if let Some(lore) = display.remove("Lore") {
+            components.insert("minecraft:lore".to_owned(), lore);
+        }
+        if let Some(color) = display.remove("color") {
+            let mut component = BTreeMap::new();
+            component.insert("rgb".to_owned(), color);
+            components.insert("minecraft:dyed_color".to_owned(), from_map(component));
+        }
+        if !display.is_empty() {
+            tag.insert("display".to_owned(), from_map(display));
+        }
+    }
+    if let Some(mut block_entity_tag) = tag.remove("BlockEntityTag").and_then(into_map) {
+        if let Some(Value::List(items)) = block_entity_tag.remove("Items") {
+            let container = items.into_iter().filter_map(into_map).map(|mut item| {
+                let slot = item.remove("Slot").as_ref().and_then(get_int).unwrap_or(0);
+                upgrade_item_stack(&mut item);
+                let mut entry = BTreeMap::new();
+                entry.insert("slot".to_owned(), Value::Int(slot));
+                entry.insert("item".to_owned(), from_map(item));
+                from_map(entry)
+            }).collect();
+            components.insert("minecraft:container".to_owned(), Value::List(container));
+        }
+        if !block_entity_tag.is_empty() {
+            components.insert("minecraft:block_entity_data".to_owned(), from_map(block_entity_tag));
+        }
+    }
+    if !tag.is_empty() {
+        components.insert("minecraft:custom_data".to_owned(), from_map(tag));
+    }
+    if !components.is_empty() {
+        item.insert("components".to_owned(), from_map(components));
+    }
+}
+
+/// The inverse of [`upgrade_item_stack`]. Components that have no equivalent before 1.20.5 are lost.
+pub fn downgrade_item_stack(item: &mut BTreeMap<String, Value>) {
+    if let Some(count) = item.remove("count") {
+        item.insert("Count".to_owned(), Value::Byte(get_int(&count).unwrap_or(1).clamp(i8::MIN as i32, i8::MAX as i32) as i8));
+    } else if item.contains_key("id") {
+        item.insert("Count".to_owned(), Value::Byte(1));
+    }
+    let mut components = match item.remove("components").and_then(into_map) {
+        Some(components) => components,
+        None => return,
+    };
+    let mut tag = components.remove("minecraft:custom_data").and_then(into_map).unwrap_or_default();
+    if let Some(damage) = components.remove("minecraft:damage") {
+        tag.insert("Damage".to_owned(), damage);
+    }
+    if let Some(repair_cost) = components.remove("minecraft:repair_cost") {
+        tag.insert("RepairCost".to_owned(), repair_cost);
+    }
+    if let Some(custom_model_data) = components.remove("minecraft:custom_model_data") {
+        tag.insert("CustomModelData".to_owned(), custom_model_data);
+    }
+    if components.remove("minecraft:unbreakable").is_some() {
+        tag.insert("Unbreakable".to_owned(), Value::Byte(1));
+    }
+    for (tag_name, component_name) in [("Enchantments", "minecraft:enchantments"), ("StoredEnchantments", "minecraft:stored_enchantments")] {
+        let levels = components.remove(component_name)
+            .and_then(into_map)
+            .and_then(|mut component| component.remove("levels"))
+            .and_then(into_map);
+        if let Some(levels) = levels {
+            let enchantments = levels.into_iter().map(|(id, level)| {
+                let mut enchantment = BTreeMap::new();
+                enchantment.insert("id".to_owned(), Value::String(id));
+                enchantment.insert("lvl".to_owned(), Value::Short(get_int(&level).unwrap_or(1) as i16));
+                from_map(enchantment)
+            }).collect();
+            tag.insert(tag_name.to_owned(), Value::List(enchantments));
+        }
+    }
+    let mut display = tag.remove("display").and_then(into_map).unwrap_or_default();
+    if let Some(name) = components.remove("minecraft:custom_name") {
+        display.insert("Name".to_owned(), name);
+    }
+    if let Some(lore) = components.remove("minecraft:lore") {
+        display.insert("Lore".to_owned(), lore);
+    }
+    if let Some(color) = components.remove("minecraft:dyed_color").and_then(into_map).and_then(|mut component| component.remove("rgb")) {
+        display.insert("color".to_owned(), color);
+    }
+    if !display.is_empty() {
+        tag.insert("display".to_owned(), from_map(display));
+    }
+    let mut block_entity_tag = components.remove("minecraft:block_entity_data").and_then(into_map).unwrap_or_default();
+    if let Some(Value::List(container)) = components.remove("minecraft:container") {
+        let items = container.into_iter().filter_map(into_map).filter_map(|mut entry| {
+            let slot = entry.remove("slot").as_ref().and_then(get_int).unwrap_or(0);
+            let mut item = entry.remove("item").and_then(into_map)?;
+            downgrade_item_stack(&mut item);
+            item.insert("Slot".to_owned(), Value::Byte(slot as i8));
+            Some(from_map(item))
+        }).collect();
+        block_entity_tag.insert("Items".to_owned(), Value::List(items));
+    }
+    if !block_entity_tag.is_empty() {
+        tag.insert("BlockEntityTag".to_owned(), from_map(block_entity_tag));
+    }
+    if !tag.is_empty() {
+        item.insert("tag".to_owned(), from_map(tag));
+    }
+}
+
+/// Renames the item of an item stack if `item` has its id, and the items nested in it, such as the contents of a
+/// shulker box or a bundle, in the item formats from both before and since 1.20.5.
+pub fn rename_item_stack(item: &mut BTreeMap<String, Value>, from_version: u32, to_version: u32) {
+    if from_version == to_version {
+        return;
+    }
+    if let Some(Value::String(id)) = item.get_mut("id") {
+        if let Ok(location) = id.parse::<ResourceLocation>() {
+            *id = registries::rename_item(&FName::new(location), from_version, to_version).to_string();
+        }
+    }
+    let rename = |item: &mut BTreeMap<String, Value>| rename_item_stack(item, from_version, to_version);
+    let rename_nested = |data: &mut BTreeMap<String, Value>| rename_nested_item_stacks(data, from_version, to_version);
+    // bundles keep their items in the tag itself, containers in their block entity tag
+    if let Some(tag) = item.get_mut("tag") {
+        modify_compound(tag, |tag| {
+            rename_nested(tag);
+            if let Some(block_entity_tag) = tag.get_mut("BlockEntityTag") {
+                modify_compound(block_entity_tag, rename_nested);
+            }
+        });
+    }
+    if let Some(components) = item.get_mut("components") {
+        modify_compound(components, |components| {
+            if let Some(block_entity_data) = components.get_mut("minecraft:block_entity_data") {
+                modify_compound(block_entity_data, rename_nested);
+            }
+            if let Some(Value::List(container)) = components.get_mut("minecraft:container") {
+                for entry in container {
+                    modify_compound(entry, |entry| {
+                        if let Some(item) = entry.get_mut("item") {
+                            modify_compound(item, rename);
+                        }
+                    });
+                }
+            }
+            for key in ["minecraft:bundle_contents", "minecraft:charged_projectiles"] {
+                if let Some(Value::List(items)) = components.get_mut(key) {
+                    for item in items {
+                        modify_compound(item, rename);
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Applies `f` to the item stacks in the NBT of a block entity or entity.
+fn for_each_item_stack(data: &mut BTreeMap<String, Value>, f: &dyn Fn(&mut BTreeMap<String, Value>)) {
+    for key in ITEM_KEYS {
+        let value = match data.remove(key) {
+            Some(value) => value,
+            None => continue,
+        };
+        let convert = |value: Value| match value {
+            Value::Compound(compound) => {
+                let mut item: BTreeMap<_, _> = compound.into_iter().collect();
+                // empty compounds are empty equipment slots
+                if item.contains_key("id") {
+                    f(&mut item);
+                }
+                from_map(item)
+            }
+            value => value,
+        };
+        let value = match value {
+            Value::List(items) => Value::List(items.into_iter().map(convert).collect()),
+            value => convert(value),
+        };
+        data.insert(key.to_owned(), value);
+    }
+}
+
+/// Converts the item stacks in the NBT of a block entity or entity to 1.20.5.
+pub fn upgrade_nested_item_stacks(data: &mut BTreeMap<String, Value>) {
+    for_each_item_stack(data, &upgrade_item_stack);
+}
+
+/// Converts the item stacks in the NBT of a block entity or entity to before 1.20.5.
+pub fn downgrade_nested_item_stacks(data: &mut BTreeMap<String, Value>) {
+    for_each_item_stack(data, &downgrade_item_stack);
+}
+
+/// Renames the items of the item stacks in the NBT of a block entity or entity, and of the item stacks nested in them.
+pub fn rename_nested_item_stacks(data: &mut BTreeMap<String, Value>, from_version: u32, to_version: u32) {
+    if from_version == to_version {
+        return;
+    }
+    for_each_item_stack(data, &|item| rename_item_stack(item, from_version, to_version));
+}
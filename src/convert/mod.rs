@@ -1,4 +1,7 @@
+pub mod block_transforms;
 pub mod data_versions;
+pub mod flattening;
+pub mod item_components;
 pub mod registries;
 
 use std::collections::BTreeMap;
@@ -1,10 +1,13 @@
 #![allow(clippy::type_complexity)]
 
+use std::{fs, io};
+use std::collections::BTreeMap;
 use std::hash::Hash;
 use ahash::AHashMap;
 use lazy_static::lazy_static;
-use crate::convert::data_versions::*;
-use crate::{CommonFNames, fname};
+use log::warn;
+use serde::Deserialize;
+use crate::convert::data_versions::get_versions;
 use crate::fname::FName;
 use crate::util;
 use crate::world::{IBlockState, IBlockStateExtensions};
@@ -75,109 +78,168 @@ impl<R: Rename<T>, T: Clone> Table<R, T> {
     }
 }
 
-macro_rules! make_table {
-    ($($version:expr => {$($down_name:expr => $up_name:expr),*$(,)*}),*$(,)*) => {
-        {
-            let mut table = Table::default();
-            $(
-                let mut entry: Entry<AHashMap<FName, FName>, FName> = Entry {
-                    down_version: $version,
-                    ..Default::default()
-                };
-                $(
-                    let down_name = fname::from_str($down_name);
-                    let up_name = fname::from_str($up_name);
-                    entry.down_renames.insert(up_name.clone(), down_name.clone());
-                    entry.up_renames.insert(down_name, up_name);
-                )*
-                table.table.push(entry);
-            )*
-            table.table.sort_by_key(|entry| entry.down_version);
-            table
-        }
-    }
+// a file of renames, either built in or in the renames folder next to the config
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct SerializedRenameFile {
+    blocks: Vec<SerializedRenames>,
+    items: Vec<SerializedRenames>,
+    biomes: Vec<SerializedRenames>,
+    block_states: Vec<SerializedStateRules>,
 }
 
-lazy_static! {
-    static ref BLOCK_RENAMES: Table = make_table! {
-        V1_16_5 => {
-            "grass_path" => "dirt_path",
-            "cauldron" => "water_cauldron",
+#[derive(Deserialize)]
+struct SerializedRenames {
+    /// The name of the last version with the old names.
+    version: String,
+    /// Pairs of old and new names. When several old names become the same new name, the last one is used going down.
+    #[serde(default)]
+    renames: Vec<(FName, FName)>,
+    /// Pairs of names that don't exist at or before the version and the names that replace them going down.
+    #[serde(default)]
+    added: Vec<(FName, FName)>,
+}
+
+#[derive(Deserialize)]
+struct SerializedStateRules {
+    version: String,
+    #[serde(default)]
+    up: Vec<StateRule>,
+    #[serde(default)]
+    down: Vec<StateRule>,
+}
+
+/// Changes the block states of a block that have all the given properties, counting missing properties as their
+/// defaults. Up rules apply after the block renames of the same version, down rules before them.
+#[derive(Clone, Deserialize)]
+struct StateRule {
+    block: FName,
+    #[serde(default)]
+    properties: BTreeMap<FName, FName>,
+    /// The values of properties the state doesn't have, when matching `properties`.
+    #[serde(default)]
+    defaults: BTreeMap<FName, FName>,
+    #[serde(default)]
+    set_block: Option<FName>,
+    #[serde(default)]
+    set_properties: BTreeMap<FName, FName>,
+    #[serde(default)]
+    remove_properties: Vec<FName>,
+}
+
+// extra renames, e.g. for modded registries, in the same format as res/renames.json
+const RENAMES_DIR: &str = "quickedit_renames";
+
+fn load_rename_files() -> Vec<SerializedRenameFile> {
+    let mut files = vec![serde_json::from_str(include_str!("../../res/renames.json")).expect("Failed to parse built in renames")];
+    let mut paths: Vec<_> = match fs::read_dir(RENAMES_DIR) {
+        Ok(entries) => entries.flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+            .collect(),
+        Err(e) => {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("Failed to open renames folder: {}", e);
+            }
+            Vec::new()
         }
     };
+    // later files take precedence, so give them a predictable order
+    paths.sort();
+    for path in paths {
+        let file = fs::File::open(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|file| serde_json::from_reader(io::BufReader::new(file)).map_err(|e| e.to_string()));
+        match file {
+            Ok(file) => files.push(file),
+            Err(e) => warn!("Failed to load renames from {}: {}", path.display(), e),
+        }
+    }
+    files
+}
+
+fn get_down_version(version_name: &str) -> Option<u32> {
+    let version = get_versions().iter().find(|(name, _)| name == version_name).map(|(_, id)| *id);
+    if version.is_none() {
+        warn!("Unknown version {} in renames", version_name);
+    }
+    version
+}
 
-    static ref ITEM_RENAMES: Table = make_table! {
-        V1_16_5 => {
-            "grass_path" => "dirt_path",
+fn make_table<'a>(all_renames: impl Iterator<Item = &'a SerializedRenames>) -> Table {
+    let mut table = Table::default();
+    for renames in all_renames {
+        let down_version = match get_down_version(&renames.version) {
+            Some(version) => version,
+            None => continue,
+        };
+        let index = match table.table.binary_search_by_key(&down_version, |entry| entry.down_version) {
+            Ok(index) => index,
+            Err(index) => {
+                table.table.insert(index, Entry { down_version, ..Default::default() });
+                index
+            }
+        };
+        let entry = &mut table.table[index];
+        for (down_name, up_name) in &renames.renames {
+            entry.down_renames.insert(up_name.clone(), down_name.clone());
+            entry.up_renames.insert(down_name.clone(), up_name.clone());
         }
-    };
+        for (added_name, fallback_name) in &renames.added {
+            entry.down_renames.insert(added_name.clone(), fallback_name.clone());
+        }
+    }
+    table
+}
 
-    static ref BIOME_RENAMES: Table = make_table! {
-        V1_17_1 => {
-            "badlands_plateau" => "badlands",
-            "bamboo_jungle_hills" => "bamboo_jungle",
-            "birch_forest_hills" => "birch_forest",
-            "dark_forest_hills" => "dark_forest",
-            "desert_hills" => "desert",
-            "desert_lakes" => "desert",
-            "giant_spruce_taiga_hills" => "old_growth_spruce_taiga",
-            "giant_spruce_taiga" => "old_growth_spruce_taiga",
-            "giant_tree_taiga_hills" => "old_growth_pine_taiga",
-            "giant_tree_taiga" => "old_growth_pine_taiga",
-            "gravelly_mountains" => "windswept_gravelly_hills",
-            "jungle_edge" => "sparse_jungle",
-            "jungle_hills" => "jungle",
-            "modified_badlands_plateau" => "badlands",
-            "modified_gravelly_mountains" => "windswept_gravelly_hills",
-            "modified_jungle_edge" => "sparse_jungle",
-            "modified_jungle" => "jungle",
-            "modified_wooded_badlands_plateau" => "wooded_badlands",
-            "mountain_edge" => "windswept_hills",
-            "mountains" => "windswept_hills",
-            "mushroom_field_shore" => "mushroom_fields",
-            "shattered_savanna" => "windswept_savanna",
-            "shattered_savanna_plateau" => "windswept_savanna",
-            "snowy_mountains" => "snowy_plains",
-            "snowy_taiga_hills" => "snowy_taiga",
-            "snowy_taiga_mountains" => "snowy_taiga",
-            "snowy_tundra" => "snowy_plains",
-            "stone_shore" => "stony_shore",
-            "swamp_hills" => "swamp",
-            "taiga_hills" => "taiga",
-            "taiga_mountains" => "taiga",
-            "tall_birch_forest" => "old_growth_birch_forest",
-            "tall_birch_hills" => "old_growth_birch_forest",
-            "wooded_badlands_plateau" => "wooded_badlands",
-            "wooded_hills" => "forest",
-            "wooded_mountains" => "windswept_forest",
-            "lofty_peaks" => "jagged_peaks",
-            "snowcapped_peaks" => "frozen_peaks",
+fn apply_state_rules(rules: &[StateRule], state: &IBlockState) -> IBlockState {
+    let mut state = state.clone();
+    for rule in rules {
+        let matches = rule.properties.iter().all(|(key, value)| state.properties.get(key).or_else(|| rule.defaults.get(key)) == Some(value));
+        if state.block != rule.block || !matches {
+            continue;
         }
-    };
+        let mut new_state = (*state).clone();
+        if let Some(block) = &rule.set_block {
+            new_state.block = block.clone();
+        }
+        for key in &rule.remove_properties {
+            new_state.properties.remove(key);
+        }
+        new_state.properties.extend(rule.set_properties.iter().map(|(key, value)| (key.clone(), value.clone())));
+        state = IBlockState::new(new_state);
+    }
+    state
+}
+
+lazy_static! {
+    static ref RENAME_FILES: Vec<SerializedRenameFile> = load_rename_files();
+
+    static ref BLOCK_RENAMES: Table = make_table(RENAME_FILES.iter().flat_map(|file| &file.blocks));
+
+    static ref ITEM_RENAMES: Table = make_table(RENAME_FILES.iter().flat_map(|file| &file.items));
+
+    static ref BIOME_RENAMES: Table = make_table(RENAME_FILES.iter().flat_map(|file| &file.biomes));
 
     static ref BLOCK_STATE_RENAMES: Table<Box<dyn (Fn(&IBlockState) -> IBlockState) + Sync + Send>, IBlockState> = {
-        macro_rules! make_state_table {
-            ($($version:expr, $up_fn:ident, $down_fn:ident);*$(;)*) => {
-                {
-                    let mut table: Table<Box<dyn (Fn(&IBlockState) -> IBlockState) + Sync + Send>, IBlockState> = Table::default();
-                    $(
-                        table.table.push(Entry {
-                            down_version: $version,
-                            up_renames: Box::new($up_fn as fn(&IBlockState) -> IBlockState),
-                            down_renames: Box::new($down_fn as fn(&IBlockState) -> IBlockState),
-                            _phantom: std::marker::PhantomData,
-                        });
-                    )*
-                    table
-                }
+        let mut rules_by_version: BTreeMap<u32, (Vec<StateRule>, Vec<StateRule>)> = BTreeMap::new();
+        for rules in RENAME_FILES.iter().flat_map(|file| &file.block_states) {
+            if let Some(down_version) = get_down_version(&rules.version) {
+                let (up_rules, down_rules) = rules_by_version.entry(down_version).or_default();
+                up_rules.extend(rules.up.iter().cloned());
+                down_rules.extend(rules.down.iter().cloned());
             }
         }
 
-        let mut table = make_state_table! {
-            V1_16_5, state_upgrade_16_5, state_downgrade_16_5;
-        };
-
-        table.table.sort_by_key(|entry| entry.down_version);
+        let mut table: Table<Box<dyn (Fn(&IBlockState) -> IBlockState) + Sync + Send>, IBlockState> = Table::default();
+        for (down_version, (up_rules, down_rules)) in rules_by_version {
+            table.table.push(Entry {
+                down_version,
+                up_renames: Box::new(move |state| apply_state_rules(&up_rules, state)),
+                down_renames: Box::new(move |state| apply_state_rules(&down_rules, state)),
+                _phantom: std::marker::PhantomData,
+            });
+        }
 
         for block_rename in &BLOCK_RENAMES.table {
             let existing_index = table.table.binary_search_by_key(&block_rename.down_version, |entry| entry.down_version);
@@ -219,6 +281,10 @@ pub fn rename_block(name: &FName, from_version: u32, to_version: u32) -> FName {
     BLOCK_RENAMES.translate(name, from_version, to_version)
 }
 
+pub fn rename_block_state(state: &IBlockState, from_version: u32, to_version: u32) -> IBlockState {
+    BLOCK_STATE_RENAMES.translate(state, from_version, to_version)
+}
+
 pub fn rename_item(name: &FName, from_version: u32, to_version: u32) -> FName {
     ITEM_RENAMES.translate(name, from_version, to_version)
 }
@@ -226,16 +292,3 @@ pub fn rename_item(name: &FName, from_version: u32, to_version: u32) -> FName {
 pub fn rename_biome(name: &FName, from_version: u32, to_version: u32) -> FName {
     BIOME_RENAMES.translate(name, from_version, to_version)
 }
-
-fn state_upgrade_16_5(state: &IBlockState) -> IBlockState {
-    // cauldron was renamed to water_cauldron, rename back to cauldron if level is zero
-    if state.block == CommonFNames.WATER_CAULDRON && state.properties.get(&CommonFNames.LEVEL).unwrap_or(&CommonFNames.ZERO) == &CommonFNames.ZERO {
-        state.with_block(CommonFNames.CAULDRON.clone())
-    } else {
-        state.clone()
-    }
-}
-
-fn state_downgrade_16_5(state: &IBlockState) -> IBlockState {
-    state.clone()
-}
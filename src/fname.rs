@@ -50,6 +50,9 @@ common_fnames! {
 
     // common blocks
     AIR = "air";
+    CAVE_AIR = "cave_air";
+    VOID_AIR = "void_air";
+    STRUCTURE_VOID = "structure_void";
     STONE = "stone";
     GRASS = "grass";
     DIRT = "dirt";
@@ -77,9 +80,14 @@ common_fnames! {
     LEVEL = "level";
     ZERO = "0";
     ONE = "1";
+    TRUE = "true";
+    FALSE = "false";
     POWER = "power";
     SNOWY = "snowy";
     WATERLOGGED = "waterlogged";
+    FACING = "facing";
+    AXIS = "axis";
+    ROTATION = "rotation";
 
     // common biomes
     OCEAN = "ocean";
@@ -320,6 +320,32 @@ impl Direction {
         }
     }
 
+    pub fn name(self) -> &'static str {
+        match self {
+            Direction::North => "north",
+            Direction::South => "south",
+            Direction::East => "east",
+            Direction::West => "west",
+            Direction::Up => "up",
+            Direction::Down => "down",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Direction::ALL.iter().copied().find(|dir| dir.name() == name)
+    }
+
+    /// Rotates clockwise when viewed from above, leaving up and down unchanged.
+    pub fn rotate_y_clockwise(self) -> Self {
+        match self {
+            Direction::North => Direction::East,
+            Direction::East => Direction::South,
+            Direction::South => Direction::West,
+            Direction::West => Direction::North,
+            vertical => vertical,
+        }
+    }
+
     pub fn forward(self) -> BlockPos {
         match self {
             Direction::North => BlockPos::new(0, 0, -1),
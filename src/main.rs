@@ -30,6 +30,7 @@ mod convert;
 use std::path::PathBuf;
 use std::sync::{Mutex, OnceLock, RwLock, RwLockReadGuard};
 use std::{thread, time};
+use std::collections::BTreeMap;
 use std::collections::vec_deque::VecDeque;
 use egui::{FontData, FontDefinitions, FontFamily};
 use flexi_logger::Logger;
@@ -222,7 +223,7 @@ fn main() {
             let mut quit = false;
 
             let _ = egui_glium.run(&display, |egui_ctx| {
-                ui::run_ui(&ui_state, egui_ctx, &mut quit);
+                ui::run_ui(&mut ui_state, egui_ctx, &mut quit);
                 ui::tick(&mut ui_state, egui_ctx);
             });
 
@@ -266,8 +267,16 @@ fn main() {
                 }
             }
             event::Event::WindowEvent { event, .. } => {
-                if matches!(event, event::WindowEvent::CloseRequested | event::WindowEvent::Destroyed) {
-                    *control_flow = event_loop::ControlFlow::Exit;
+                match event {
+                    event::WindowEvent::CloseRequested => {
+                        if ui::request_exit(&mut ui_state) {
+                            *control_flow = event_loop::ControlFlow::Exit;
+                        }
+                    }
+                    event::WindowEvent::Destroyed => {
+                        *control_flow = event_loop::ControlFlow::Exit;
+                    }
+                    _ => {}
                 }
 
                 if !egui_glium.on_event(&event).consumed {
@@ -334,9 +343,15 @@ pub struct Config {
     pub window_width: u32,
     pub window_height: u32,
     pub last_open_path: PathBuf,
+    pub last_schematic_path: PathBuf,
     pub auto_open_world: Option<PathBuf>,
+    /// The compression to save chunks with, keyed by world directory. Other worlds keep the compression they use.
+    pub world_chunk_compression: BTreeMap<PathBuf, world::ChunkCompression>,
+    /// Whether to rebuild the villager points of interest of edited subchunks when saving.
+    pub rebuild_poi_on_save: bool,
     render_distance: u32,
     unloaded_render_distance: u32,
+    undo_memory_limit_mb: u32,
 }
 
 impl Config {
@@ -347,6 +362,11 @@ impl Config {
     pub fn unloaded_render_distance(&self) -> u32 {
         self.unloaded_render_distance.clamp(2, 64)
     }
+
+    /// The memory limit of the undo history, in bytes.
+    pub fn undo_memory_limit(&self) -> usize {
+        self.undo_memory_limit_mb.clamp(1, 65536) as usize * 1024 * 1024
+    }
 }
 
 impl Default for Config {
@@ -358,9 +378,13 @@ impl Default for Config {
                 .map(|p| p.join("saves"))
                 .filter(|p| p.exists())
                 .unwrap_or_else(|| PathBuf::from(".")),
+            last_schematic_path: PathBuf::from("."),
             auto_open_world: None,
+            world_chunk_compression: BTreeMap::new(),
+            rebuild_poi_on_save: true,
             render_distance: 16,
             unloaded_render_distance: 32,
+            undo_memory_limit_mb: 256,
         }
     }
 }
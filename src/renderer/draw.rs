@@ -1,16 +1,21 @@
+use std::cell::RefCell;
 use std::sync::Arc;
 use std::sync::atomic::Ordering;
 use approx::AbsDiffEq;
-use glam::{DVec3, IVec2, IVec3, Mat4, Vec3Swizzles};
+use glam::{DVec3, IVec2, IVec3, Mat4, Vec3, Vec3Swizzles};
 use glium::{Frame, Surface, uniform};
 use num_traits::FloatConst;
 use crate::fname::FName;
 use crate::geom::{ChunkPos, IVec2Extensions, IVec2RangeExtensions, IVec3RangeExtensions};
-use crate::{renderer, World};
-use crate::renderer::storage::ChunkStore;
+use crate::{renderer, util, World};
+use crate::convert::registries;
+use crate::renderer::bakery;
+use crate::renderer::storage::{BakedGeometry, ChunkStore};
 use crate::resources::Resources;
 use crate::util::{BlitVertex, FastDashMap, MainThreadStore, make_fast_dash_map};
-use crate::world::Dimension;
+use crate::world::{Dimension, Selection};
+use crate::world::clipboard::{Clipboard, PastePreview};
+use crate::world::schematic::Transform;
 
 const MAIN_VERT_SHADER: &str = include_str!("../../res/main.vsh");
 const MAIN_FRAG_SHADER: &str = include_str!("../../res/main.fsh");
@@ -19,6 +24,15 @@ const BLIT_FRAG_SHADER: &str = include_str!("../../res/blit.fsh");
 
 const EXISTING_CHUNK_COLOR_A: [f32; 3] = [1.0, 0.5, 0.0];
 const EXISTING_CHUNK_COLOR_B: [f32; 3] = [1.0, 1.0, 0.0];
+const SELECTION_COLOR: [f32; 3] = [1.0, 1.0, 1.0];
+const PASTE_PREVIEW_COLOR: [f32; 3] = [0.0, 1.0, 1.0];
+const PASTE_PREVIEW_ALPHA: f32 = 0.5;
+const ENTITY_COLOR: [f32; 3] = [1.0, 0.0, 1.0];
+// we don't know the size of each entity type, so draw them all the size of a player
+const ENTITY_HALF_WIDTH: f32 = 0.3;
+const ENTITY_HEIGHT: f32 = 1.8;
+// larger clipboards are only previewed by their outline, to keep the preview quick to build and draw
+const MAX_PASTE_PREVIEW_BLOCKS: usize = 65536;
 
 thread_local! {
     static DEFAULT_DRAW_PARAMS: glium::DrawParameters<'static> = glium::DrawParameters {
@@ -38,6 +52,14 @@ pub struct WorldRenderer {
     blit_shader_program: MainThreadStore<glium::Program>,
     block_atlas_texture: MainThreadStore<glium::texture::SrgbTexture2d>,
     pub(super) chunk_store: FastDashMap<FName, ChunkStore>,
+    paste_preview_geometry: MainThreadStore<RefCell<PastePreviewGeometry>>,
+}
+
+#[derive(Default)]
+struct PastePreviewGeometry {
+    // the clipboard generation and transform the geometry was built for
+    built_for: Option<(u64, Transform)>,
+    geometry: BakedGeometry,
 }
 
 impl WorldRenderer {
@@ -58,6 +80,7 @@ impl WorldRenderer {
                 glium::texture::SrgbTexture2d::with_mipmaps(renderer::get_display(), atlas_image, glium::texture::MipmapsOption::AutoGeneratedMipmapsMax(resources.mipmap_levels)).unwrap()
             }),
             chunk_store: make_fast_dash_map(),
+            paste_preview_geometry: MainThreadStore::default(),
         }
     }
 
@@ -65,6 +88,14 @@ impl WorldRenderer {
         true
     }
 
+    /// Rebuilds the geometry of every subchunk of the chunk, including those the chunk doesn't have, e.g. once its
+    /// changes were discarded.
+    pub fn mark_chunk_for_redraw(&self, dimension: &FName, chunk_pos: ChunkPos) {
+        if let Some(chunk_store) = self.chunk_store.get(dimension) {
+            chunk_store.mark_chunk_for_redraw(chunk_pos);
+        }
+    }
+
         pub fn render_world(&self, world: &World, target: &mut glium::Frame) {
         let (dimension, camera_pos, yaw, pitch) = {
             let camera = world.camera.read().unwrap();
@@ -74,6 +105,8 @@ impl WorldRenderer {
             Some(d) => d,
             None => return,
         };
+        let selection = world.selection.read().unwrap().as_ref().filter(|selection| selection.dimension == dimension).cloned();
+        let paste_preview = world.paste_preview.read().unwrap().as_ref().filter(|preview| preview.dimension == dimension).cloned();
 
         let current_chunk: IVec2 = camera_pos.xz().floor().as_ivec2() >> 4i8;
         let render_distance_chunks = crate::get_config().render_distance();
@@ -92,10 +125,10 @@ impl WorldRenderer {
         let camera_yaw = yaw.to_radians();
         let camera_pitch = pitch.to_radians();
         let view_matrix = Mat4::from_rotation_x(-camera_pitch) * Mat4::from_rotation_y(-camera_yaw);
-        let uniforms = |chunk_pos: ChunkPos| {
+        let uniforms_at = |origin: DVec3| {
             uniform! {
                 projection_matrix: projection.to_cols_array_2d(),
-                view_matrix: (view_matrix * Mat4::from_translation((DVec3::new((chunk_pos.x << 4) as f64, 0.0, (chunk_pos.y << 4) as f64) - camera_pos).as_vec3())).to_cols_array_2d(),
+                view_matrix: (view_matrix * Mat4::from_translation((origin - camera_pos).as_vec3())).to_cols_array_2d(),
                 tex: self.block_atlas_texture
                     .sampled()
                     .magnify_filter(glium::uniforms::MagnifySamplerFilter::Nearest)
@@ -107,6 +140,7 @@ impl WorldRenderer {
                 gamma: 1.0f32,
             }
         };
+        let uniforms = |chunk_pos: ChunkPos| uniforms_at(DVec3::new((chunk_pos.x << 4) as f64, 0.0, (chunk_pos.y << 4) as f64));
 
         self.render_existing_chunks(world, dimension, target, &uniforms(current_chunk), current_chunk);
 
@@ -144,6 +178,18 @@ impl WorldRenderer {
                 target, &self.shader_program, &uniforms(*pos), &alpha_params
             );
         }
+
+        self.render_entities(dimension, &chunks_to_render, target, &uniforms(current_chunk), current_chunk);
+
+        if let Some(selection) = &selection {
+            self.render_selection(selection, target, &uniforms(current_chunk), current_chunk);
+        }
+
+        if let (Some(preview), Some(clipboard)) = (&paste_preview, crate::world::clipboard::get_clipboard()) {
+            self.render_paste_preview(world, preview, &clipboard, target, &uniforms_at(preview.origin.as_dvec3()), &alpha_params);
+            let (min, max) = Self::get_paste_preview_bounds(preview, &clipboard);
+            self.render_box(min, max, PASTE_PREVIEW_COLOR, target, &uniforms(current_chunk), current_chunk);
+        }
     }
 
     fn frustum_check(dimension: &Dimension, chunk_pos: ChunkPos, camera_pos: DVec3, yaw_radians: f32, pitch_radians: f32, fov_radians: f32) -> bool {
@@ -225,6 +271,142 @@ impl WorldRenderer {
         true
     }
 
+    fn render_selection<U>(&self, selection: &Selection, target: &mut Frame, uniforms: &U, current_chunk: IVec2)
+        where
+            U: glium::uniforms::Uniforms,
+    {
+        self.render_box(selection.min(), selection.max(), SELECTION_COLOR, target, uniforms, current_chunk);
+    }
+
+    /// Draws the outline of the blocks from `min` to `max` inclusive.
+    fn render_box<U>(&self, min: IVec3, max: IVec3, color: [f32; 3], target: &mut Frame, uniforms: &U, current_chunk: IVec2)
+        where
+            U: glium::uniforms::Uniforms,
+    {
+        // expand slightly so the lines aren't hidden inside the faces of the selected blocks
+        let origin = IVec3::new(current_chunk.x << 4, 0, current_chunk.y << 4);
+        let min = (min - origin).as_vec3() - 0.005;
+        let max = (max + IVec3::ONE - origin).as_vec3() + 0.005;
+        let mut vertices = Vec::with_capacity(8);
+        let mut indices = Vec::with_capacity(24);
+        Self::push_box_lines(min, max, color, &mut vertices, &mut indices);
+        let vertices = glium::VertexBuffer::new(renderer::get_display(), &vertices).unwrap();
+        let indices = glium::IndexBuffer::new(renderer::get_display(), glium::index::PrimitiveType::LinesList, &indices).unwrap();
+        let mut params = DEFAULT_DRAW_PARAMS.with(|params| params.clone());
+        params.line_width = Some(2.0);
+        target.draw(&vertices, &indices, &self.blit_shader_program, uniforms, &params).unwrap();
+    }
+
+    /// Adds the 12 edges of the box to the vertices and indices of a lines list.
+    fn push_box_lines(min: Vec3, max: Vec3, color: [f32; 3], vertices: &mut Vec<BlitVertex>, indices: &mut Vec<u32>) {
+        let first_index = vertices.len() as u32;
+        vertices.extend((IVec3::ZERO..=IVec3::ONE).iter().map(|corner| {
+            BlitVertex {
+                position: (min + (max - min) * corner.as_vec3()).to_array(),
+                color,
+            }
+        }));
+        // x varies fastest in the corner order, so the bits for x, y and z are 1, 2 and 4
+        for corner in 0..8u32 {
+            for axis_bit in [1, 2, 4] {
+                if corner & axis_bit == 0 {
+                    indices.push(first_index + corner);
+                    indices.push(first_index + (corner | axis_bit));
+                }
+            }
+        }
+    }
+
+    /// Draws a box at the position of each entity in the rendered chunks.
+    fn render_entities<T, U>(&self, dimension: &Dimension, chunks_to_render: &[(ChunkPos, T)], target: &mut Frame, uniforms: &U, current_chunk: IVec2)
+        where
+            U: glium::uniforms::Uniforms,
+    {
+        let origin = DVec3::new((current_chunk.x << 4) as f64, 0.0, (current_chunk.y << 4) as f64);
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        for (chunk_pos, _) in chunks_to_render {
+            let chunk = match dimension.get_chunk(*chunk_pos) {
+                Some(chunk) => chunk,
+                None => continue,
+            };
+            for entity in chunk.entities() {
+                let pos = (entity.pos - origin).as_vec3();
+                let min = pos - Vec3::new(ENTITY_HALF_WIDTH, 0.0, ENTITY_HALF_WIDTH);
+                let max = pos + Vec3::new(ENTITY_HALF_WIDTH, ENTITY_HEIGHT, ENTITY_HALF_WIDTH);
+                Self::push_box_lines(min, max, ENTITY_COLOR, &mut vertices, &mut indices);
+            }
+        }
+        if vertices.is_empty() {
+            return;
+        }
+        let vertices = glium::VertexBuffer::new(renderer::get_display(), &vertices).unwrap();
+        let indices = glium::IndexBuffer::new(renderer::get_display(), glium::index::PrimitiveType::LinesList, &indices).unwrap();
+        DEFAULT_DRAW_PARAMS.with(|params| {
+            target.draw(&vertices, &indices, &self.blit_shader_program, uniforms, params).unwrap();
+        });
+    }
+
+    fn get_paste_preview_bounds(preview: &PastePreview, clipboard: &Clipboard) -> (IVec3, IVec3) {
+        let schematic = &clipboard.schematic;
+        let corner1 = preview.transform.transform_pos(schematic.offset);
+        let corner2 = preview.transform.transform_pos(schematic.offset + schematic.size() - IVec3::ONE);
+        (preview.origin + corner1.min(corner2), preview.origin + corner1.max(corner2))
+    }
+
+    /// Draws the clipboard as translucent blocks, relative to the paste origin. The geometry is rebuilt whenever the
+    /// clipboard or the transform changes.
+    fn render_paste_preview<U>(&self, world: &World, preview: &PastePreview, clipboard: &Clipboard, target: &mut Frame, uniforms: &U, alpha_params: &glium::DrawParameters)
+        where
+            U: glium::uniforms::Uniforms,
+    {
+        let mut preview_geometry = (*self.paste_preview_geometry).borrow_mut();
+        let key = (clipboard.generation, preview.transform);
+        if preview_geometry.built_for != Some(key) {
+            let vertices = Self::build_paste_preview(world, clipboard, preview.transform);
+            preview_geometry.geometry.set_buffer_data(&vertices);
+            preview_geometry.built_for = Some(key);
+        }
+        let mut params = alpha_params.clone();
+        // the ghost shouldn't hide the world behind it
+        params.depth.write = false;
+        let blending_function = glium::BlendingFunction::Addition {
+            source: glium::LinearBlendingFactor::ConstantAlpha,
+            destination: glium::LinearBlendingFactor::OneMinusConstantAlpha,
+        };
+        params.blend = glium::Blend {
+            color: blending_function,
+            alpha: blending_function,
+            constant_value: (1.0, 1.0, 1.0, PASTE_PREVIEW_ALPHA),
+        };
+        preview_geometry.geometry.draw(target, &self.transparent_shader_program, uniforms, &params);
+    }
+
+    fn build_paste_preview(world: &World, clipboard: &Clipboard, transform: Transform) -> Vec<util::Vertex> {
+        let schematic = &clipboard.schematic;
+        if schematic.iter_solid_blocks().nth(MAX_PASTE_PREVIEW_BLOCKS).is_some() {
+            return Vec::new();
+        }
+        let world_version = world.data_version();
+        let mut vertices = Vec::new();
+        for (pos, state) in schematic.iter_solid_blocks() {
+            let state = transform.transform_block_state(&registries::rename_block_state(state, clipboard.data_version, world_version));
+            let pos = transform.transform_pos(schematic.offset + pos).as_vec3();
+            let baked_model = bakery::get_baked_model(world, &state);
+            for face in baked_model.faces.values() {
+                for quad in &face.quads {
+                    vertices.extend(quad.iter().map(|vertex| util::Vertex {
+                        position: (Vec3::from(vertex.position) + pos).to_array(),
+                        tex_coords: vertex.tex_coords,
+                        lightmap_coords: [1.0, 0.0],
+                        color: [1.0, 1.0, 1.0],
+                    }));
+                }
+            }
+        }
+        vertices
+    }
+
     fn render_existing_chunks<U>(&self, world: &World, dimension: &Dimension, target: &mut Frame, uniforms: &U, current_chunk: IVec2)
         where
             U: glium::uniforms::Uniforms,
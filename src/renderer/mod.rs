@@ -1,6 +1,7 @@
 mod bakery;
 pub mod draw;
 mod liquid;
+pub mod picking;
 mod storage;
 pub mod worker;
 
@@ -0,0 +1,88 @@
+use glam::{Affine3A, DVec3, IVec3, Quat, Vec3, Vec3Swizzles};
+use crate::{blocks, geom};
+use crate::geom::BlockPos;
+use crate::renderer::bakery;
+use crate::world::{Dimension, IBlockState, World};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockHit {
+    pub pos: BlockPos,
+    pub face: geom::Direction,
+}
+
+/// Steps through the blocks along the ray, returning the first block whose model covers the point where the ray enters
+/// it. Unloaded chunks are treated as empty.
+pub fn raycast(world: &World, dimension: &Dimension, origin: DVec3, direction: DVec3, max_distance: f64) -> Option<BlockHit> {
+    let direction = direction.normalize();
+    let mut pos = origin.floor().as_ivec3();
+    let step = IVec3::new(signum(direction.x), signum(direction.y), signum(direction.z));
+    let t_delta = direction.recip().abs();
+    let mut t_max = DVec3::ZERO;
+    for axis in 0..3 {
+        t_max[axis] = match step[axis] {
+            1 => (pos[axis] as f64 + 1.0 - origin[axis]) / direction[axis],
+            -1 => (origin[axis] - pos[axis] as f64) / -direction[axis],
+            _ => f64::INFINITY,
+        };
+    }
+
+    loop {
+        let axis = if t_max.x < t_max.y && t_max.x < t_max.z { 0 } else if t_max.y < t_max.z { 1 } else { 2 };
+        let t = t_max[axis];
+        if t > max_distance {
+            return None;
+        }
+        pos[axis] += step[axis];
+        t_max[axis] += t_delta[axis];
+
+        let mut forward = IVec3::ZERO;
+        forward[axis] = -step[axis];
+        let face = geom::Direction::from_vector(forward.as_vec3());
+        let state = match dimension.get_block_state(pos) {
+            Some(state) => state,
+            None => continue,
+        };
+        if blocks::is_air(&state) {
+            continue;
+        }
+        let hit_pos = origin + direction * t - pos.as_dvec3();
+        if hits_model(world, &state, face, hit_pos.as_vec3()) {
+            return Some(BlockHit { pos, face });
+        }
+    }
+}
+
+fn signum(n: f64) -> i32 {
+    if n > 0.0 {
+        1
+    } else if n < 0.0 {
+        -1
+    } else {
+        0
+    }
+}
+
+fn hits_model(world: &World, state: &IBlockState, face: geom::Direction, pos_in_block: Vec3) -> bool {
+    let model = bakery::get_baked_model(world, state);
+    // geometry inside the block doesn't have a collision mask, assume it fills the block
+    if model.faces.get(&None).map(|face| !face.quads.is_empty()).unwrap_or(false) {
+        return true;
+    }
+    let baked_face = match model.faces.get(&Some(face)) {
+        Some(face) => face,
+        None => return false,
+    };
+    // same face space as the collision mask in the bakery
+    let transform = Affine3A::from_translation(Vec3::new(0.5, 0.5, 0.5))
+        * Affine3A::from_quat(Quat::from_rotation_arc(face.forward().as_vec3(), Vec3::Z))
+        * Affine3A::from_translation(Vec3::new(-0.5, -0.5, -0.5));
+    let face_pos = transform.transform_point3(pos_in_block).xy();
+    let mut x = ((face_pos.x * 16.0).floor() as i32).clamp(0, 15);
+    let mut y = ((face_pos.y * 16.0).floor() as i32).clamp(0, 15);
+    if face.forward().dot(IVec3::ONE) == -1 {
+        x = 15 - x;
+        y = 15 - y;
+    }
+    let mask = 1 << (((y & 1) << 4) | x);
+    baked_face.collision_mask[(y >> 3) as usize][((y >> 1) & 3) as usize] & mask != 0
+}
@@ -151,6 +151,12 @@ impl ChunkStore {
         }
     }
 
+    pub(super) fn mark_chunk_for_redraw(&self, chunk_pos: ChunkPos) {
+        for subchunk in &mut *self.get(chunk_pos).subchunk_geometry.lock().unwrap() {
+            subchunk.dirty = true;
+        }
+    }
+
     fn get_index(&self, chunk_pos: ChunkPos) -> usize {
         let width = (self.render_distance * 2 + 1) as i32;
         let x = chunk_pos.x.rem_euclid(width) as usize;
@@ -105,7 +105,7 @@ fn build_subchunk_geometry(
         let built_chunk = chunk_store.get(chunk_pos);
         let mut subchunk_geometry_guard = built_chunk.subchunk_geometry.lock().unwrap();
         let mut subchunk_geometry = &mut (*subchunk_geometry_guard)[subchunk_index];
-        if chunk.subchunks[subchunk_index].as_ref().map(|subchunk| subchunk.needs_redraw.swap(false, Ordering::Acquire)).unwrap_or(false)
+        if chunk.get_subchunk(subchunk_index).map(|subchunk| subchunk.needs_redraw.swap(false, Ordering::Acquire)).unwrap_or(false)
             || subchunk_geometry.dirty
         {
             if stop() {
@@ -113,7 +113,7 @@ fn build_subchunk_geometry(
             }
             *chunk_changed = true;
             subchunk_geometry.clear();
-            if let Some(subchunk) = &chunk.subchunks[subchunk_index] {
+            if let Some(subchunk) = chunk.get_subchunk(subchunk_index) {
                 render_subchunk(world, dimension, chunk_pos, subchunk, subchunk_y, subchunk_geometry);
             }
             subchunk_geometry.dirty = false;
@@ -1,13 +1,14 @@
 use std::sync::Arc;
-use ahash::AHashMap;
+use std::collections::BTreeMap;
+use ahash::{AHashMap, AHashSet};
 use glam::Vec4Swizzles;
 use crate::fname::FName;
-use crate::minecraft;
+use crate::{blocks, CommonFNames, minecraft};
 use crate::renderer::BakedModel;
 use crate::resources::atlas::TextureAtlas;
 use crate::resources::structs::{BlockModel, BlockstateFile, MultipartWhen, TintData, TransformedModel};
 use crate::util::FastDashMap;
-use crate::world::IBlockState;
+use crate::world::{BlockState, IBlockState};
 
 pub mod atlas;
 mod builtin;
@@ -92,6 +93,88 @@ impl Resources {
         Some(transformed_models)
     }
 
+    /// Checks the block state against the properties used by the blockstate file of the block, including properties
+    /// the state is missing. Blockstate files only mention properties that affect the model, and multipart files don't
+    /// list every value, so anything that can't be checked for certain is returned as a warning rather than an error.
+    pub fn validate_block_state(&self, state: &BlockState) -> Result<Vec<String>, String> {
+        if blocks::is_air(state) {
+            return Ok(Vec::new());
+        }
+        let blockstate = self.blockstates.get(&state.block)
+            .ok_or_else(|| format!("Unknown block {}", state.block.to_nice_string()))?;
+        let mut known_properties: AHashMap<&FName, AHashSet<&FName>> = AHashMap::new();
+        // the properties every variant mentions, without which no variant matches
+        let mut required_properties: AHashSet<&FName> = AHashSet::new();
+        let is_multipart = match blockstate {
+            BlockstateFile::Variants(variants) => {
+                for pair in &variants.pairs {
+                    for (key, value) in &pair.properties {
+                        known_properties.entry(key).or_default().insert(value);
+                    }
+                }
+                required_properties.extend(known_properties.keys().filter(|key| variants.pairs.iter().all(|pair| pair.properties.contains_key(**key))));
+                false
+            }
+            BlockstateFile::Multipart(cases) => {
+                fn add_when<'a>(when: &'a MultipartWhen, known_properties: &mut AHashMap<&'a FName, AHashSet<&'a FName>>) {
+                    match when {
+                        MultipartWhen::Union(union) => union.iter().for_each(|when| add_when(when, known_properties)),
+                        MultipartWhen::Intersection(intersection) => {
+                            for (key, values) in intersection {
+                                known_properties.entry(key).or_default().extend(values);
+                            }
+                        }
+                    }
+                }
+                for when in cases.iter().flat_map(|case| &case.when) {
+                    add_when(when, &mut known_properties);
+                }
+                true
+            }
+        };
+
+        let block_name = state.block.to_nice_string();
+        let mut warnings = Vec::new();
+        let properties: BTreeMap<_, _> = state.properties.iter().map(|(key, value)| (key.to_nice_string(), (key, value))).collect();
+        for (key_name, (key, value)) in properties {
+            let values = match known_properties.get(key) {
+                Some(values) => values,
+                None => {
+                    warnings.push(format!("Property {} is not used by the model of {}", key_name, block_name));
+                    continue;
+                }
+            };
+            let is_boolean = values.iter().all(|value| **value == CommonFNames.TRUE || **value == CommonFNames.FALSE);
+            if values.contains(value) || is_multipart && is_boolean && (*value == CommonFNames.TRUE || *value == CommonFNames.FALSE) {
+                continue;
+            }
+            let mut expected: Vec<_> = values.iter().map(|value| value.to_nice_string()).collect();
+            expected.sort();
+            let message = format!("Unexpected value {} for property {} of {}, expected one of: {}", value.to_nice_string(), key_name, block_name, expected.join(", "));
+            if is_multipart {
+                warnings.push(message);
+            } else {
+                return Err(message);
+            }
+        }
+
+        let mut missing_properties: Vec<_> = known_properties.iter()
+            .filter(|(key, _)| !state.properties.contains_key(**key))
+            .map(|(key, values)| (key.to_nice_string(), *key, values))
+            .collect();
+        missing_properties.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
+        for (key_name, key, values) in missing_properties {
+            let mut expected: Vec<_> = values.iter().map(|value| value.to_nice_string()).collect();
+            expected.sort();
+            let message = format!("Missing property {} of {}, expected one of: {}", key_name, block_name, expected.join(", "));
+            if required_properties.contains(key) {
+                return Err(message);
+            }
+            warnings.push(message);
+        }
+        Ok(warnings)
+    }
+
     pub fn get_biome_data(&self, biome: &FName) -> Option<&minecraft::BiomeData> {
         self.biomes.get(biome)
     }
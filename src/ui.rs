@@ -2,15 +2,134 @@ use glam::{DMat2, DVec2};
 use lazy_static::lazy_static;
 use winit::{dpi, event};
 use std::cell::RefCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use egui::Color32;
 use egui_glium::egui_winit::winit::window::CursorGrabMode;
-use log::info;
+use log::{info, warn};
 use crate::{minecraft, world, renderer};
+use crate::convert::data_versions;
 use crate::util::MainThreadStore;
+use crate::world::operations::{BlockPredicate, Operation, OperationProgress};
+use crate::world::region_tools::{AreaShape, KeepArea, PruneFilter, RegionToolProgress};
+use crate::world::schematic::{SchematicFormat, Transform};
 
 #[derive(Default)]
 pub struct UiState {
     key_states: KeyStates,
+    pending_close: Option<PendingClose>,
+    close_save: Option<CloseSave>,
+    operation: OperationInput,
+    operation_progress: Option<Arc<OperationProgress>>,
+    schematic: SchematicInput,
+    region_tools: RegionToolsInput,
+    players: PlayersInput,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PendingClose {
+    World,
+    Exit,
+}
+
+/// The saves started from the unsaved changes dialog. The worlds are closed once all of them have finished, unless one
+/// of them failed.
+struct CloseSave {
+    pending_close: PendingClose,
+    remaining: Arc<AtomicUsize>,
+    failed: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+struct OperationInput {
+    kind: OperationKind,
+    block: String,
+    from: String,
+    error: Option<String>,
+}
+
+struct SchematicInput {
+    export_format: SchematicFormat,
+    transform: Transform,
+    paste_air: bool,
+}
+
+impl Default for SchematicInput {
+    fn default() -> Self {
+        SchematicInput {
+            export_format: SchematicFormat::SpongeV3,
+            transform: Transform::default(),
+            paste_air: true,
+        }
+    }
+}
+
+#[derive(Default)]
+struct PlayersInput {
+    // listed when the panel is first shown, and again on refresh
+    players: Option<Vec<world::PlayerId>>,
+    edit: Option<PlayerEdit>,
+    error: Option<String>,
+}
+
+struct PlayerEdit {
+    id: world::PlayerId,
+    data: world::PlayerData,
+    dimension: String,
+    inventory: Vec<ItemInput>,
+    ender_items: Vec<ItemInput>,
+}
+
+struct ItemInput {
+    slot: i8,
+    id: String,
+    stack: world::ItemStack,
+}
+
+#[derive(Default)]
+struct RegionToolsInput {
+    repair: bool,
+    recompression: Option<world::ChunkCompression>,
+    prune_uninhabited: bool,
+    min_inhabited_time: i64,
+    keep_areas: String,
+    min_status: Option<&'static str>,
+    prune_error: Option<String>,
+    convert_target: Option<u32>,
+    progress: Option<Arc<RegionToolProgress>>,
+    /// Whether the world has to be reopened once the tool has finished.
+    close_when_finished: bool,
+    report: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+enum OperationKind {
+    #[default]
+    Fill,
+    Replace,
+    Walls,
+    Outline,
+    Hollow,
+}
+
+impl OperationKind {
+    const ALL: [OperationKind; 5] = [
+        OperationKind::Fill,
+        OperationKind::Replace,
+        OperationKind::Walls,
+        OperationKind::Outline,
+        OperationKind::Hollow,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            OperationKind::Fill => "Fill",
+            OperationKind::Replace => "Replace",
+            OperationKind::Walls => "Walls",
+            OperationKind::Outline => "Outline",
+            OperationKind::Hollow => "Hollow",
+        }
+    }
 }
 
 #[derive(Default)]
@@ -20,7 +139,7 @@ struct KeyStates {
     mouse_dy: f64,
 }
 
-pub fn run_ui(_state: &UiState, egui_ctx: &egui::Context, _quit: &mut bool) {
+pub fn run_ui(state: &mut UiState, egui_ctx: &egui::Context, quit: &mut bool) {
     let (x, y, z, yaw, pitch) = {
         let worlds = world::WORLDS.read().unwrap();
         match worlds.last() {
@@ -31,20 +150,786 @@ pub fn run_ui(_state: &UiState, egui_ctx: &egui::Context, _quit: &mut bool) {
             None => (0.0, 0.0, 0.0, 0.0, 0.0),
         }
     };
+    // region tools rewrite the region files, so nothing can be edited or saved until they've finished
+    let region_tool_running = is_region_tool_running();
     egui::TopBottomPanel::top("top_panel").show(egui_ctx, |ui| {
         if ui.button("Open")
             .clicked()
         {
             open_clicked();
         }
+        if ui.add_enabled(!region_tool_running, egui::Button::new("Save"))
+            .clicked()
+        {
+            save_clicked();
+        }
+        if ui.button("Close")
+            .clicked()
+        {
+            close_clicked(state);
+        }
+        let (can_undo, can_redo) = match world::WORLDS.read().unwrap().last() {
+            // an undo or redo is in progress if the history is locked
+            Some(world) => world.history.try_lock().map(|history| (history.can_undo(), history.can_redo())).unwrap_or((false, false)),
+            None => (false, false),
+        };
+        if ui.add_enabled(can_undo && !region_tool_running, egui::Button::new("Undo"))
+            .clicked()
+        {
+            history_clicked(false);
+        }
+        if ui.add_enabled(can_redo && !region_tool_running, egui::Button::new("Redo"))
+            .clicked()
+        {
+            history_clicked(true);
+        }
     });
+    if !egui_ctx.wants_keyboard_input() && !region_tool_running && egui_ctx.input().modifiers.command {
+        if egui_ctx.input().key_pressed(egui::Key::Z) {
+            history_clicked(egui_ctx.input().modifiers.shift);
+        } else if egui_ctx.input().key_pressed(egui::Key::Y) {
+            history_clicked(true);
+        }
+    }
+    if !egui_ctx.wants_keyboard_input() && !region_tool_running {
+        handle_clipboard_keys(state, egui_ctx);
+    }
+    if let Some(pending_close) = state.pending_close {
+        show_unsaved_changes_dialog(state, egui_ctx, pending_close, quit);
+    }
+    if state.close_save.is_some() {
+        show_close_save_progress(state, egui_ctx, quit);
+    }
+    if state.region_tools.report.is_some() {
+        show_region_report(state, egui_ctx);
+    }
+    let has_world = !world::WORLDS.read().unwrap().is_empty();
+    let selection = world::WORLDS.read().unwrap().last().and_then(|world| world.selection.read().unwrap().clone());
     egui::SidePanel::left("left_panel").show(egui_ctx, |ui| {
         ui.with_layout(egui::Layout::top_down(egui::Align::LEFT), |ui| {
             ui.colored_label(
                 Color32::WHITE,
                 format!("Pos: {:.2}, {:.2}, {:.2}, yaw: {:.2}, pitch: {:.2}", x, y, z, yaw, pitch).as_str()
             );
+            if let Some(selection) = &selection {
+                let (min, max, size) = (selection.min(), selection.max(), selection.size());
+                ui.colored_label(
+                    Color32::WHITE,
+                    format!("Selection: {}, {}, {} to {}, {}, {}", min.x, min.y, min.z, max.x, max.y, max.z).as_str()
+                );
+                ui.colored_label(
+                    Color32::WHITE,
+                    format!("Size: {}x{}x{}, volume: {}", size.x, size.y, size.z, selection.volume()).as_str()
+                );
+                ui.separator();
+                ui.add_enabled_ui(!region_tool_running, |ui| show_operation_panel(state, ui, selection));
+            }
+            if has_world {
+                ui.separator();
+                ui.add_enabled_ui(!region_tool_running, |ui| {
+                    show_clipboard_panel(state, ui, selection.as_ref());
+                    ui.separator();
+                    show_schematic_panel(state, ui, selection.as_ref());
+                });
+                ui.separator();
+                show_region_tools_panel(state, ui);
+                ui.separator();
+                ui.add_enabled_ui(!region_tool_running, |ui| {
+                    show_level_properties_panel(ui);
+                    ui.separator();
+                    show_players_panel(state, ui);
+                });
+            }
+        });
+    });
+}
+
+fn show_operation_panel(state: &mut UiState, ui: &mut egui::Ui, selection: &world::Selection) {
+    if let Some(progress) = &state.operation_progress {
+        if progress.is_finished() {
+            info!("Changed {} blocks", progress.changed_blocks());
+            state.operation_progress = None;
+        } else {
+            ui.add(egui::ProgressBar::new(progress.fraction()).show_percentage());
+            ui.label(format!("{} blocks changed", progress.changed_blocks()));
+            if ui.button("Cancel").clicked() {
+                progress.cancel();
+            }
+            return;
+        }
+    }
+
+    let input = &mut state.operation;
+    egui::ComboBox::from_label("Operation")
+        .selected_text(input.kind.name())
+        .show_ui(ui, |ui| {
+            for kind in OperationKind::ALL {
+                ui.selectable_value(&mut input.kind, kind, kind.name());
+            }
+        });
+    if input.kind == OperationKind::Replace {
+        ui.label("From, e.g. oak_log or *[waterlogged=true]:");
+        ui.text_edit_singleline(&mut input.from);
+    }
+    if input.kind != OperationKind::Hollow {
+        ui.label("Block, e.g. stone or oak_log[axis=y]:");
+        ui.text_edit_singleline(&mut input.block);
+    }
+    if let Some(error) = &input.error {
+        ui.colored_label(Color32::RED, error);
+    }
+    if ui.button("Apply").clicked() {
+        let worlds = world::WORLDS.read().unwrap();
+        let world = match worlds.last() {
+            Some(world) => world,
+            None => return,
+        };
+        let parse_block_state = |s: &str| -> Result<world::IBlockState, String> {
+            let state: world::BlockState = s.parse().map_err(|err: world::BlockStateParseError| err.to_string())?;
+            for warning in world.resources.validate_block_state(&state)? {
+                warn!("{}", warning);
+            }
+            Ok(world::IBlockState::new(state))
+        };
+        let operation: Result<Operation, String> = try {
+            let block = || parse_block_state(&input.block);
+            match input.kind {
+                OperationKind::Fill => Operation::Fill(block()?),
+                OperationKind::Replace => Operation::Replace {
+                    from: input.from.parse::<BlockPredicate>().map_err(|err| err.to_string())?,
+                    to: block()?,
+                },
+                OperationKind::Walls => Operation::Walls(block()?),
+                OperationKind::Outline => Operation::Outline(block()?),
+                OperationKind::Hollow => Operation::Hollow,
+            }
+        };
+        match operation {
+            Ok(operation) => {
+                input.error = None;
+                state.operation_progress = Some(world::operations::run_operation(world, selection.clone(), operation));
+            }
+            Err(err) => input.error = Some(err),
+        }
+    }
+}
+
+fn show_clipboard_panel(state: &mut UiState, ui: &mut egui::Ui, selection: Option<&world::Selection>) {
+    if is_pasting() {
+        ui.label("R to rotate, M to mirror, Enter or click to place, Escape to cancel");
+        ui.horizontal(|ui| {
+            if ui.button("Place").clicked() {
+                place_paste(state.schematic.paste_air);
+            }
+            if ui.button("Cancel").clicked() {
+                cancel_paste();
+            }
+        });
+        return;
+    }
+    ui.horizontal(|ui| {
+        if ui.add_enabled(selection.is_some(), egui::Button::new("Copy")).clicked() {
+            copy_clicked(false);
+        }
+        if ui.add_enabled(selection.is_some(), egui::Button::new("Cut")).clicked() {
+            copy_clicked(true);
+        }
+        if ui.add_enabled(world::clipboard::get_clipboard().is_some(), egui::Button::new("Paste")).clicked() {
+            start_paste(state.schematic.transform);
+        }
+    });
+}
+
+fn handle_clipboard_keys(state: &mut UiState, egui_ctx: &egui::Context) {
+    if egui_ctx.input().modifiers.command {
+        if egui_ctx.input().key_pressed(egui::Key::C) {
+            copy_clicked(false);
+        } else if egui_ctx.input().key_pressed(egui::Key::X) {
+            copy_clicked(true);
+        } else if egui_ctx.input().key_pressed(egui::Key::V) {
+            start_paste(state.schematic.transform);
+        }
+    } else if is_pasting() {
+        let transform = &mut state.schematic.transform;
+        if egui_ctx.input().key_pressed(egui::Key::R) {
+            transform.rotation = (transform.rotation + 1) % 4;
+        }
+        if egui_ctx.input().key_pressed(egui::Key::M) {
+            transform.mirror = !transform.mirror;
+        }
+        if egui_ctx.input().key_pressed(egui::Key::Enter) {
+            place_paste(state.schematic.paste_air);
+        } else if egui_ctx.input().key_pressed(egui::Key::Escape) {
+            cancel_paste();
+        }
+    }
+}
+
+fn copy_clicked(cut: bool) {
+    let worlds = world::WORLDS.read().unwrap();
+    let world = match worlds.last() {
+        Some(world) => world,
+        None => return,
+    };
+    let selection = match world.selection.read().unwrap().clone() {
+        Some(selection) => selection,
+        None => return,
+    };
+    if cut {
+        world::clipboard::cut(world, selection);
+    } else {
+        world::clipboard::copy(world, selection);
+    }
+}
+
+fn is_pasting() -> bool {
+    world::WORLDS.read().unwrap().last().map(|world| world.paste_preview.read().unwrap().is_some()).unwrap_or(false)
+}
+
+/// Shows the clipboard as a ghost which follows the block the camera is looking at, until it's placed or cancelled.
+fn start_paste(transform: Transform) {
+    if world::clipboard::get_clipboard().is_none() {
+        return;
+    }
+    let worlds = world::WORLDS.read().unwrap();
+    if let Some(world) = worlds.last() {
+        let (dimension, origin) = {
+            let camera = world.camera.read().unwrap();
+            (camera.dimension.clone(), camera.pos.floor().as_ivec3())
+        };
+        *world.paste_preview.write().unwrap() = Some(world::clipboard::PastePreview { dimension, origin, transform });
+    }
+}
+
+fn place_paste(paste_air: bool) {
+    let worlds = world::WORLDS.read().unwrap();
+    if let Some(world) = worlds.last() {
+        if world.is_region_tool_running() {
+            return;
+        }
+        let preview = world.paste_preview.write().unwrap().take();
+        if let Some(preview) = preview {
+            world::clipboard::paste(world, preview.dimension, preview.origin, preview.transform, paste_air);
+        }
+    }
+}
+
+fn cancel_paste() {
+    if let Some(world) = world::WORLDS.read().unwrap().last() {
+        *world.paste_preview.write().unwrap() = None;
+    }
+}
+
+fn show_schematic_panel(state: &mut UiState, ui: &mut egui::Ui, selection: Option<&world::Selection>) {
+    let input = &mut state.schematic;
+    if let Some(selection) = selection {
+        egui::ComboBox::from_label("Export format")
+            .selected_text(input.export_format.name())
+            .show_ui(ui, |ui| {
+                for format in SchematicFormat::ALL {
+                    ui.selectable_value(&mut input.export_format, format, format.name());
+                }
+            });
+        if ui.button("Export selection").clicked() {
+            export_clicked(selection.clone(), input.export_format);
+        }
+    }
+    egui::ComboBox::from_label("Rotation")
+        .selected_text(format!("{}°", input.transform.rotation as u32 * 90))
+        .show_ui(ui, |ui| {
+            for rotation in 0..4 {
+                ui.selectable_value(&mut input.transform.rotation, rotation, format!("{}°", rotation as u32 * 90));
+            }
+        });
+    ui.checkbox(&mut input.transform.mirror, "Mirror");
+    // also used when pasting the clipboard
+    ui.checkbox(&mut input.paste_air, "Paste air");
+    if ui.button("Import schematic").clicked() {
+        import_clicked(selection, input.transform, input.paste_air);
+    }
+}
+
+fn show_region_tools_panel(state: &mut UiState, ui: &mut egui::Ui) {
+    let input = &mut state.region_tools;
+    if let Some(progress) = &input.progress {
+        if progress.is_finished() {
+            input.report = progress.take_report();
+            input.progress = None;
+            if std::mem::take(&mut input.close_when_finished) {
+                close_world();
+            }
+        } else {
+            ui.add(egui::ProgressBar::new(progress.fraction()).show_percentage());
+            if ui.button("Cancel").clicked() {
+                progress.cancel();
+            }
+            return;
+        }
+    }
+
+    let worlds = world::WORLDS.read().unwrap();
+    let world = match worlds.last() {
+        Some(world) => world,
+        None => return,
+    };
+    let mut rebuild_poi = crate::get_config().rebuild_poi_on_save;
+    if ui.checkbox(&mut rebuild_poi, "Rebuild villager POI on save").changed() {
+        crate::modify_config(|config| config.rebuild_poi_on_save = rebuild_poi);
+    }
+    // repairing and compacting rewrite region files, which would conflict with saving modified chunks
+    let can_repair = !world.has_unsaved_changes() && !world.is_read_only();
+    ui.add_enabled(can_repair, egui::Checkbox::new(&mut input.repair, "Repair"));
+    if ui.button("Check region files").clicked() {
+        input.progress = Some(world::region_tools::check_regions(world, input.repair && can_repair));
+    }
+    let compression_name = |compression: Option<world::ChunkCompression>| match compression {
+        Some(compression) => format!("{:?}", compression),
+        None => "Keep".to_owned(),
+    };
+    egui::ComboBox::from_label("Recompress")
+        .selected_text(compression_name(input.recompression))
+        .show_ui(ui, |ui| {
+            for compression in [None, Some(world::ChunkCompression::Zlib), Some(world::ChunkCompression::Gzip), Some(world::ChunkCompression::Lz4), Some(world::ChunkCompression::None)] {
+                ui.selectable_value(&mut input.recompression, compression, compression_name(compression));
+            }
+        });
+    if ui.add_enabled(can_repair, egui::Button::new("Compact region files")).clicked() {
+        input.progress = Some(world::region_tools::compact_regions(world, input.recompression));
+    }
+
+    ui.horizontal(|ui| {
+        ui.checkbox(&mut input.prune_uninhabited, "Inhabited for less than");
+        ui.add(egui::DragValue::new(&mut input.min_inhabited_time).clamp_range(0..=i64::MAX).suffix(" ticks"));
+    });
+    egui::ComboBox::from_label("Generated less than")
+        .selected_text(input.min_status.unwrap_or("Any status"))
+        .show_ui(ui, |ui| {
+            ui.selectable_value(&mut input.min_status, None, "Any status");
+            for status in world::region_tools::STATUS_ORDER {
+                ui.selectable_value(&mut input.min_status, Some(status), status);
+            }
+        });
+    ui.label("Keep areas, one per line, e.g. x1 z1 x2 z2 or the_nether x z radius:");
+    ui.text_edit_multiline(&mut input.keep_areas);
+    if let Some(error) = &input.prune_error {
+        ui.colored_label(Color32::RED, error);
+    }
+    if ui.add_enabled(can_repair, egui::Button::new("Prune chunks")).clicked() {
+        match parse_keep_areas(&input.keep_areas) {
+            Ok(keep_areas) => {
+                let filter = PruneFilter {
+                    min_inhabited_time: input.prune_uninhabited.then_some(input.min_inhabited_time),
+                    keep_areas,
+                    min_status: input.min_status.map(str::to_owned),
+                };
+                if filter.min_inhabited_time.is_none() && filter.keep_areas.is_empty() && filter.min_status.is_none() {
+                    input.prune_error = Some("Nothing to prune by".to_owned());
+                } else {
+                    input.prune_error = None;
+                    input.progress = Some(world::region_tools::prune_chunks(world, filter));
+                }
+            }
+            Err(err) => input.prune_error = Some(err),
+        }
+    }
+
+    let version_name = |data_version: Option<u32>| match data_version {
+        Some(data_version) => data_versions::get_version_name(data_version).unwrap_or("Unknown").to_owned(),
+        None => "Choose a version".to_owned(),
+    };
+    egui::ComboBox::from_label("Convert to")
+        .selected_text(version_name(input.convert_target))
+        .show_ui(ui, |ui| {
+            for (name, data_version) in data_versions::get_versions() {
+                ui.selectable_value(&mut input.convert_target, Some(*data_version), name);
+            }
+        });
+    let convert_target = input.convert_target.filter(|&target| target != world.data_version());
+    if ui.add_enabled(can_repair && convert_target.is_some(), egui::Button::new("Convert world")).clicked() {
+        // the world is loaded with the resources and height of its old version, so it has to be reopened
+        input.close_when_finished = true;
+        input.progress = Some(world::region_tools::convert_world(world, convert_target.unwrap()));
+    }
+}
+
+const DIFFICULTY_NAMES: [&str; 4] = ["Peaceful", "Easy", "Normal", "Hard"];
+const GAME_TYPE_NAMES: [&str; 4] = ["Survival", "Creative", "Adventure", "Spectator"];
+
+fn show_level_properties_panel(ui: &mut egui::Ui) {
+    let worlds = world::WORLDS.read().unwrap();
+    let world = match worlds.last() {
+        Some(world) => world,
+        None => return,
+    };
+    let mut data = world.level_dat();
+    let mut changed = false;
+    egui::CollapsingHeader::new("World properties").show(ui, |ui| {
+        ui.horizontal(|ui| {
+            ui.label("Name:");
+            changed |= ui.text_edit_singleline(&mut data.level_name).changed();
+        });
+        ui.horizontal(|ui| {
+            ui.label("Spawn:");
+            changed |= ui.add(egui::DragValue::new(&mut data.spawn_x)).changed();
+            changed |= ui.add(egui::DragValue::new(&mut data.spawn_y)).changed();
+            changed |= ui.add(egui::DragValue::new(&mut data.spawn_z)).changed();
+            if let Some(angle) = &mut data.spawn_angle {
+                changed |= ui.add(egui::DragValue::new(angle).clamp_range(-180.0..=180.0).suffix("°")).changed();
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Day time:");
+            changed |= ui.add(egui::DragValue::new(&mut data.day_time).clamp_range(0..=i64::MAX).suffix(" ticks")).changed();
+        });
+        ui.horizontal(|ui| {
+            changed |= ui.checkbox(&mut data.raining, "Raining").changed();
+            changed |= ui.checkbox(&mut data.thundering, "Thundering").changed();
+        });
+        ui.horizontal(|ui| {
+            ui.label("Clear weather for:");
+            changed |= ui.add(egui::DragValue::new(&mut data.clear_weather_time).clamp_range(0..=i32::MAX).suffix(" ticks")).changed();
+        });
+        egui::ComboBox::from_label("Difficulty")
+            .selected_text(DIFFICULTY_NAMES.get(data.difficulty as usize).copied().unwrap_or("Unknown"))
+            .show_ui(ui, |ui| {
+                for (difficulty, name) in DIFFICULTY_NAMES.iter().enumerate() {
+                    changed |= ui.selectable_value(&mut data.difficulty, difficulty as i8, *name).changed();
+                }
+            });
+        changed |= ui.checkbox(&mut data.difficulty_locked, "Difficulty locked").changed();
+        egui::ComboBox::from_label("Game mode")
+            .selected_text(GAME_TYPE_NAMES.get(data.game_type as usize).copied().unwrap_or("Unknown"))
+            .show_ui(ui, |ui| {
+                for (game_type, name) in GAME_TYPE_NAMES.iter().enumerate() {
+                    changed |= ui.selectable_value(&mut data.game_type, game_type as i32, *name).changed();
+                }
+            });
+        changed |= ui.checkbox(&mut data.hardcore, "Hardcore").changed();
+        ui.horizontal(|ui| {
+            ui.label("World border center:");
+            let mut center_x = data.border_center_x.unwrap_or(0.0);
+            let mut center_z = data.border_center_z.unwrap_or(0.0);
+            if ui.add(egui::DragValue::new(&mut center_x)).changed() | ui.add(egui::DragValue::new(&mut center_z)).changed() {
+                data.border_center_x = Some(center_x);
+                data.border_center_z = Some(center_z);
+                changed = true;
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("World border size:");
+            let mut size = data.border_size.unwrap_or(world::LevelDatData::DEFAULT_BORDER_SIZE);
+            if ui.add(egui::DragValue::new(&mut size).clamp_range(1.0..=world::LevelDatData::DEFAULT_BORDER_SIZE)).changed() {
+                data.border_size = Some(size);
+                changed = true;
+            }
+        });
+        egui::CollapsingHeader::new("Game rules").show(ui, |ui| {
+            egui::Grid::new("game_rules").show(ui, |ui| {
+                for (name, value) in &mut data.game_rules {
+                    ui.label(name.as_str());
+                    match value.parse::<bool>() {
+                        Ok(mut enabled) => {
+                            if ui.checkbox(&mut enabled, "").changed() {
+                                *value = enabled.to_string();
+                                changed = true;
+                            }
+                        }
+                        Err(_) => changed |= ui.text_edit_singleline(value).changed(),
+                    }
+                    ui.end_row();
+                }
+            });
+        });
+    });
+    if changed {
+        world.edit_level_dat(|level_dat| *level_dat = data);
+    }
+}
+
+fn show_players_panel(state: &mut UiState, ui: &mut egui::Ui) {
+    let worlds = world::WORLDS.read().unwrap();
+    let world = match worlds.last() {
+        Some(world) => world,
+        None => return,
+    };
+    let input = &mut state.players;
+    egui::CollapsingHeader::new("Players").show(ui, |ui| {
+        if input.players.is_none() || ui.button("Refresh").clicked() {
+            match world.list_players() {
+                Ok(players) => input.players = Some(players),
+                Err(err) => {
+                    input.players = Some(Vec::new());
+                    input.error = Some(format!("Failed to list players: {}", err));
+                }
+            }
+        }
+        let selected = input.edit.as_ref().map(|edit| edit.id);
+        let mut new_selection = selected;
+        egui::ComboBox::from_label("Player")
+            .selected_text(selected.map(|id| id.to_string()).unwrap_or_else(|| "None".to_owned()))
+            .show_ui(ui, |ui| {
+                for id in input.players.iter().flatten() {
+                    ui.selectable_value(&mut new_selection, Some(*id), id.to_string());
+                }
+            });
+        if new_selection != selected {
+            if let Some(id) = new_selection {
+                match world.get_player(id) {
+                    Ok(data) => {
+                        input.edit = Some(PlayerEdit::new(id, data));
+                        input.error = None;
+                    }
+                    Err(err) => input.error = Some(format!("Failed to read player: {}", err)),
+                }
+            }
+        }
+        if let Some(error) = &input.error {
+            ui.colored_label(Color32::RED, error);
+        }
+        let edit = match &mut input.edit {
+            Some(edit) => edit,
+            None => return,
+        };
+
+        ui.horizontal(|ui| {
+            ui.label("Pos:");
+            ui.add(egui::DragValue::new(&mut edit.data.pos.x).speed(0.1));
+            ui.add(egui::DragValue::new(&mut edit.data.pos.y).speed(0.1));
+            ui.add(egui::DragValue::new(&mut edit.data.pos.z).speed(0.1));
+        });
+        ui.horizontal(|ui| {
+            ui.label("Dimension:");
+            ui.text_edit_singleline(&mut edit.dimension);
+        });
+        ui.horizontal(|ui| {
+            ui.label("XP level:");
+            ui.add(egui::DragValue::new(&mut edit.data.xp_level).clamp_range(0..=i32::MAX));
+            ui.label("Progress:");
+            ui.add(egui::DragValue::new(&mut edit.data.xp_progress).clamp_range(0.0..=1.0).speed(0.01));
+        });
+        ui.horizontal(|ui| {
+            ui.label("Total XP:");
+            ui.add(egui::DragValue::new(&mut edit.data.xp_total).clamp_range(0..=i32::MAX));
+        });
+        let abilities = &mut edit.data.abilities;
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut abilities.may_fly, "May fly");
+            ui.checkbox(&mut abilities.flying, "Flying");
+            ui.checkbox(&mut abilities.invulnerable, "Invulnerable");
+        });
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut abilities.instabuild, "Instant build");
+            ui.checkbox(&mut abilities.may_build, "May build");
+        });
+        ui.horizontal(|ui| {
+            ui.label("Fly speed:");
+            ui.add(egui::DragValue::new(&mut abilities.fly_speed).speed(0.01));
+            ui.label("Walk speed:");
+            ui.add(egui::DragValue::new(&mut abilities.walk_speed).speed(0.01));
+        });
+        egui::CollapsingHeader::new("Inventory").show(ui, |ui| show_item_inputs(ui, "inventory", &mut edit.inventory));
+        egui::CollapsingHeader::new("Ender chest").show(ui, |ui| show_item_inputs(ui, "ender_items", &mut edit.ender_items));
+
+        if ui.button("Teleport camera to player").clicked() {
+            world.camera.write().unwrap().teleport_to_player(&edit.data);
+        }
+        let move_to_camera = ui.button("Move player to camera").clicked();
+        if move_to_camera {
+            let camera = world.camera.read().unwrap();
+            edit.data.move_to_camera(&camera);
+            edit.dimension = camera.dimension.to_nice_string();
+        }
+        if ui.button("Apply").clicked() || move_to_camera {
+            input.error = edit.apply(world).err();
+        }
+    });
+}
+
+fn show_item_inputs(ui: &mut egui::Ui, id: &str, items: &mut Vec<ItemInput>) {
+    let mut removed = None;
+    egui::Grid::new(id).show(ui, |ui| {
+        for (index, item) in items.iter_mut().enumerate() {
+            ui.add(egui::DragValue::new(&mut item.slot).prefix("Slot "));
+            ui.text_edit_singleline(&mut item.id);
+            ui.add(egui::DragValue::new(&mut item.stack.count).clamp_range(1..=99));
+            if ui.button("Remove").clicked() {
+                removed = Some(index);
+            }
+            ui.end_row();
+        }
+    });
+    if let Some(index) = removed {
+        items.remove(index);
+    }
+    if ui.button("Add item").clicked() {
+        // the first free slot of the main inventory
+        let slot = (0..36).find(|slot| items.iter().all(|item| item.slot != *slot)).unwrap_or(0);
+        items.push(ItemInput::new(slot, world::ItemStack {
+            id: crate::fname::from_str("stone"),
+            count: 1,
+            data: Default::default(),
+        }));
+    }
+}
+
+impl ItemInput {
+    fn new(slot: i8, stack: world::ItemStack) -> Self {
+        ItemInput { slot, id: stack.id.to_nice_string(), stack }
+    }
+}
+
+impl PlayerEdit {
+    fn new(id: world::PlayerId, data: world::PlayerData) -> Self {
+        let to_inputs = |items: &std::collections::BTreeMap<i8, world::ItemStack>| {
+            items.iter().map(|(slot, stack)| ItemInput::new(*slot, stack.clone())).collect()
+        };
+        PlayerEdit {
+            id,
+            dimension: data.dimension.to_nice_string(),
+            inventory: to_inputs(&data.inventory),
+            ender_items: to_inputs(&data.ender_items),
+            data,
+        }
+    }
+
+    fn apply(&mut self, world: &world::World) -> Result<(), String> {
+        let parse_name = |name: &str| name.parse::<crate::util::ResourceLocation>().map(crate::fname::FName::new);
+        let from_inputs = |items: &[ItemInput]| {
+            items.iter().map(|item| {
+                let mut stack = item.stack.clone();
+                stack.id = parse_name(&item.id)?;
+                Ok((item.slot, stack))
+            }).collect::<Result<_, String>>()
+        };
+        self.data.dimension = parse_name(&self.dimension)?;
+        self.data.inventory = from_inputs(&self.inventory)?;
+        self.data.ender_items = from_inputs(&self.ender_items)?;
+        let data = self.data.clone();
+        world.edit_player(self.id, |player| *player = data).map_err(|err| format!("Failed to edit player: {}", err))
+    }
+}
+
+fn parse_keep_areas(text: &str) -> Result<Vec<KeepArea>, String> {
+    text.lines().filter(|line| !line.trim().is_empty()).map(|line| {
+        let mut parts: Vec<&str> = line.split_whitespace().collect();
+        let dimension = match parts.first() {
+            Some(part) if part.parse::<i32>().is_err() => {
+                let dimension = part.parse::<crate::util::ResourceLocation>()?;
+                parts.remove(0);
+                Some(crate::fname::FName::new(dimension))
+            }
+            _ => None,
+        };
+        let coords = parts.iter()
+            .map(|part| part.parse::<i32>().map_err(|_| format!("Invalid number \"{}\"", part)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let shape = match coords[..] {
+            [x1, z1, x2, z2] => AreaShape::Rect {
+                min: glam::IVec2::new(x1.min(x2), z1.min(z2)),
+                max: glam::IVec2::new(x1.max(x2), z1.max(z2)),
+            },
+            [x, z, radius] => AreaShape::Circle { center: glam::IVec2::new(x, z), radius },
+            _ => return Err(format!("Expected a rectangle or a circle, got \"{}\"", line)),
+        };
+        Ok(KeepArea { dimension, shape })
+    }).collect()
+}
+
+fn show_region_report(state: &mut UiState, egui_ctx: &egui::Context) {
+    let mut open = true;
+    egui::Window::new("Region files")
+        .collapsible(false)
+        .show(egui_ctx, |ui| {
+            egui::ScrollArea::vertical().max_height(400.0).show(ui, |ui| {
+                ui.label(state.region_tools.report.as_deref().unwrap_or_default());
+            });
+            if ui.button("Close").clicked() {
+                open = false;
+            }
+        });
+    if !open {
+        state.region_tools.report = None;
+    }
+}
+
+fn export_clicked(selection: world::Selection, format: SchematicFormat) {
+    let location = crate::get_config().last_schematic_path.clone();
+    let path = native_dialog::FileDialog::new()
+        .set_location(&location)
+        .add_filter(format.name(), &[format.extension()])
+        .show_save_single_file();
+    let path = match path {
+        Ok(Some(path)) => path.with_extension(format.extension()),
+        _ => return,
+    };
+    if let Some(parent_path) = path.parent() {
+        crate::modify_config(|config| {
+            config.last_schematic_path = parent_path.to_path_buf();
+        });
+    }
+    let worlds = world::WORLDS.read().unwrap();
+    if let Some(world) = worlds.last() {
+        world.spawn_worker(move |world, _| {
+            let schematic = match world::schematic::Schematic::from_selection(&world, &selection) {
+                Ok(schematic) => schematic,
+                Err(err) => {
+                    warn!("Failed to export selection: {}", err);
+                    return;
+                }
+            };
+            match world::schematic::write_schematic(&path, &schematic, format, world.data_version()) {
+                Ok(()) => info!("Exported selection to {}", path.display()),
+                Err(err) => warn!("Failed to export selection: {}", err),
+            }
+        });
+    }
+}
+
+/// Places the schematic at the minimum corner of the selection if there is one in the camera's dimension, otherwise at
+/// the camera.
+fn import_clicked(selection: Option<&world::Selection>, transform: Transform, paste_air: bool) {
+    let location = crate::get_config().last_schematic_path.clone();
+    let path = native_dialog::FileDialog::new()
+        .set_location(&location)
+        .add_filter("Schematic", &world::schematic::SCHEMATIC_EXTENSIONS)
+        .show_open_single_file();
+    let path = match path {
+        Ok(Some(path)) => path,
+        _ => return,
+    };
+    if let Some(parent_path) = path.parent() {
+        crate::modify_config(|config| {
+            config.last_schematic_path = parent_path.to_path_buf();
         });
+    }
+    let worlds = world::WORLDS.read().unwrap();
+    let world = match worlds.last() {
+        Some(world) => world,
+        None => return,
+    };
+    let (dimension_id, origin) = {
+        let camera = world.camera.read().unwrap();
+        match selection {
+            Some(selection) if selection.dimension == camera.dimension => (camera.dimension.clone(), selection.min()),
+            _ => (camera.dimension.clone(), camera.pos.floor().as_ivec3()),
+        }
+    };
+    world.spawn_worker(move |world, _| {
+        let schematic = match world::schematic::read_schematic(&path, world.data_version()) {
+            Ok(schematic) => schematic,
+            Err(err) => {
+                warn!("Failed to import {}: {}", path.display(), err);
+                return;
+            }
+        };
+        let dimension = match world.get_dimension(&dimension_id) {
+            Some(dimension) => dimension,
+            None => return,
+        };
+        let transaction = world::EditTransaction::new(dimension);
+        let changed_blocks = schematic.place(&world, &transaction, origin, transform, paste_air);
+        world.history.lock().unwrap().push(transaction);
+        info!("Imported {}, changed {} blocks", path.display(), changed_blocks);
     });
 }
 
@@ -66,6 +951,152 @@ fn open_clicked() {
     }
 }
 
+fn is_region_tool_running() -> bool {
+    world::WORLDS.read().unwrap().last().map(|world| world.is_region_tool_running()).unwrap_or(false)
+}
+
+fn save_clicked() {
+    let worlds = world::WORLDS.read().unwrap();
+    if let Some(world) = worlds.last() {
+        world.spawn_worker(|world, _| {
+            match world.save() {
+                Ok(saved_chunks) => info!("Saved {} chunks", saved_chunks),
+                Err(err) => warn!("Failed to save world: {}", err),
+            }
+        });
+    }
+}
+
+fn history_clicked(redo: bool) {
+    let worlds = world::WORLDS.read().unwrap();
+    if let Some(world) = worlds.last() {
+        world.spawn_worker(move |world, _| {
+            let mut history = world.history.lock().unwrap();
+            if redo {
+                history.redo(&world);
+            } else {
+                history.undo(&world);
+            }
+        });
+    }
+}
+
+fn close_clicked(state: &mut UiState) {
+    if state.close_save.is_some() {
+        return;
+    }
+    let has_unsaved_changes = match world::WORLDS.read().unwrap().last() {
+        Some(world) => world.has_unsaved_changes(),
+        None => return,
+    };
+    if has_unsaved_changes {
+        state.pending_close = Some(PendingClose::World);
+    } else {
+        close_world();
+    }
+}
+
+fn close_world() {
+    let world = world::WORLDS.write().unwrap().pop();
+    if let Some(world) = world {
+        // dropping a world waits for its workers, which need the main thread to keep ticking
+        std::thread::spawn(move || drop(world));
+    }
+}
+
+/// Called when the window is about to close. Returns whether it's ok to exit straight away, otherwise the user is
+/// asked what to do with their unsaved changes first.
+pub fn request_exit(state: &mut UiState) -> bool {
+    if state.close_save.is_some() {
+        return false;
+    }
+    let has_unsaved_changes = world::WORLDS.read().unwrap().iter().any(|world| world.has_unsaved_changes());
+    if has_unsaved_changes {
+        state.pending_close = Some(PendingClose::Exit);
+        false
+    } else {
+        true
+    }
+}
+
+fn show_unsaved_changes_dialog(state: &mut UiState, egui_ctx: &egui::Context, pending_close: PendingClose, quit: &mut bool) {
+    let dirty_chunks: usize = {
+        let worlds = world::WORLDS.read().unwrap();
+        match pending_close {
+            PendingClose::World => worlds.last().map(|world| world.dirty_chunk_count()).unwrap_or(0),
+            PendingClose::Exit => worlds.iter().map(|world| world.dirty_chunk_count()).sum(),
+        }
+    };
+    egui::Window::new("Unsaved changes")
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+        .show(egui_ctx, |ui| {
+            ui.label(format!("{} chunks modified, save or discard?", dirty_chunks));
+            ui.horizontal(|ui| {
+                let save = ui.button("Save").clicked();
+                let discard = ui.button("Discard").clicked();
+                let cancel = ui.button("Cancel").clicked();
+                if save || discard {
+                    state.pending_close = None;
+                    let worlds = world::WORLDS.read().unwrap();
+                    let worlds_to_close = match pending_close {
+                        PendingClose::World => worlds.last().into_iter().collect::<Vec<_>>(),
+                        PendingClose::Exit => worlds.iter().collect(),
+                    };
+                    if save {
+                        let remaining = Arc::new(AtomicUsize::new(worlds_to_close.len()));
+                        let failed = Arc::new(AtomicBool::new(false));
+                        for world in worlds_to_close {
+                            let (remaining, failed) = (remaining.clone(), failed.clone());
+                            world.spawn_worker(move |world, _| {
+                                if let Err(err) = world.save() {
+                                    warn!("Failed to save world: {}", err);
+                                    failed.store(true, Ordering::Release);
+                                }
+                                remaining.fetch_sub(1, Ordering::AcqRel);
+                            });
+                        }
+                        state.close_save = Some(CloseSave { pending_close, remaining, failed });
+                        return;
+                    }
+                    for world in worlds_to_close {
+                        world.discard_changes();
+                    }
+                    drop(worlds);
+                    match pending_close {
+                        PendingClose::World => close_world(),
+                        PendingClose::Exit => *quit = true,
+                    }
+                } else if cancel {
+                    state.pending_close = None;
+                }
+            });
+        });
+}
+
+fn show_close_save_progress(state: &mut UiState, egui_ctx: &egui::Context, quit: &mut bool) {
+    let close_save = state.close_save.as_ref().unwrap();
+    if close_save.remaining.load(Ordering::Acquire) == 0 {
+        // keep everything open rather than losing the changes that failed to save
+        if !close_save.failed.load(Ordering::Acquire) {
+            match close_save.pending_close {
+                PendingClose::World => close_world(),
+                PendingClose::Exit => *quit = true,
+            }
+        }
+        state.close_save = None;
+        return;
+    }
+    egui::Window::new("Saving")
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+        .show(egui_ctx, |ui| {
+            ui.label("Saving changes before closing...");
+        });
+}
+
 lazy_static! {
     static ref WINDOW_SIZE: MainThreadStore<RefCell<Option<(u32, u32)>>> = MainThreadStore::new(RefCell::new(None));
 }
@@ -88,10 +1119,15 @@ pub fn handle_event(ui_state: &mut UiState, event: &event::WindowEvent) {
     match event {
         event::WindowEvent::MouseInput {
             state: event::ElementState::Pressed,
-            button: event::MouseButton::Left,
+            button: button @ (event::MouseButton::Left | event::MouseButton::Right),
             ..
         } => {
-            if !ui_state.key_states.mouse_grabbed {
+            if ui_state.key_states.mouse_grabbed && *button == event::MouseButton::Left && is_pasting() {
+                place_paste(ui_state.schematic.paste_air);
+            } else if ui_state.key_states.mouse_grabbed {
+                // left click sets the first corner of the selection and right click sets the second, like the wooden axe
+                select_corner(*button == event::MouseButton::Right);
+            } else if *button == event::MouseButton::Left {
                 ui_state.key_states.mouse_grabbed = true;
                 if renderer::get_display().gl_window().window().set_cursor_grab(CursorGrabMode::Locked).is_ok() {
                     renderer::get_display().gl_window().window().set_cursor_visible(false);
@@ -112,6 +1148,39 @@ pub fn handle_event(ui_state: &mut UiState, event: &event::WindowEvent) {
     }
 }
 
+const PICK_DISTANCE: f64 = 256.0;
+
+fn select_corner(second: bool) {
+    let worlds = world::WORLDS.read().unwrap();
+    let world = match worlds.last() {
+        Some(world) => world,
+        None => return,
+    };
+    let (dimension_id, pos, forward) = {
+        let camera = world.camera.read().unwrap();
+        (camera.dimension.clone(), camera.pos, camera.forward())
+    };
+    let dimension = match world.get_dimension(&dimension_id) {
+        Some(dimension) => dimension,
+        None => return,
+    };
+    let hit = match renderer::picking::raycast(world, &dimension, pos, forward, PICK_DISTANCE) {
+        Some(hit) => hit,
+        None => return,
+    };
+    let mut selection = world.selection.write().unwrap();
+    match &mut *selection {
+        Some(selection) if selection.dimension == dimension_id => {
+            if second {
+                selection.corner2 = hit.pos;
+            } else {
+                selection.corner1 = hit.pos;
+            }
+        }
+        _ => *selection = Some(world::Selection::new(dimension_id, hit.pos)),
+    }
+}
+
 pub fn handle_device_event(ui_state: &mut UiState, event: &event::DeviceEvent) {
     match event {
         event::DeviceEvent::MouseMotion { delta: (x, y) } => {
@@ -147,6 +1216,33 @@ pub fn tick(ui_state: &mut UiState, egui_ctx: &egui::Context) {
     if ui_state.key_states.mouse_grabbed {
         handle_camera(ui_state, egui_ctx);
     }
+    update_paste_preview(ui_state.schematic.transform);
+}
+
+// how far in front of the camera to paste when it isn't looking at a block
+const PASTE_DISTANCE: f64 = 8.0;
+
+/// Moves the paste preview to the block in front of the face the camera is looking at.
+fn update_paste_preview(transform: Transform) {
+    let worlds = world::WORLDS.read().unwrap();
+    let world = match worlds.last() {
+        Some(world) => world,
+        None => return,
+    };
+    if world.paste_preview.read().unwrap().is_none() {
+        return;
+    }
+    let (dimension_id, pos, forward) = {
+        let camera = world.camera.read().unwrap();
+        (camera.dimension.clone(), camera.pos, camera.forward())
+    };
+    let origin = world.get_dimension(&dimension_id)
+        .and_then(|dimension| renderer::picking::raycast(world, &dimension, pos, forward, PICK_DISTANCE))
+        .map(|hit| hit.pos + hit.face.forward())
+        .unwrap_or_else(|| (pos + forward.normalize() * PASTE_DISTANCE).floor().as_ivec3());
+    if let Some(preview) = &mut *world.paste_preview.write().unwrap() {
+        *preview = world::clipboard::PastePreview { dimension: dimension_id, origin, transform };
+    }
 }
 
 fn handle_camera(ui_state: &mut UiState, egui_ctx: &egui::Context) {
@@ -0,0 +1,118 @@
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use glam::{IVec3, Vec3Swizzles};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use rayon::prelude::*;
+use crate::CommonFNames;
+use crate::fname::FName;
+use crate::geom::{BlockPos, ChunkPos, IVec2RangeExtensions};
+use crate::world::{BlockState, EditTransaction, IBlockState, operations, Selection, World};
+use crate::world::operations::Operation;
+use crate::world::schematic::{Schematic, Transform};
+use crate::world::workers::WorldRef;
+
+/// Blocks copied from a world, which can be pasted into any open world.
+pub struct Clipboard {
+    pub schematic: Schematic,
+    /// The data version of the world the blocks were copied from.
+    pub data_version: u32,
+    /// Different for each copy, so that previews know when to rebuild.
+    pub generation: u64,
+}
+
+lazy_static! {
+    static ref CLIPBOARD: RwLock<Option<Arc<Clipboard>>> = RwLock::new(None);
+}
+
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Where the clipboard would be pasted, shown as a ghost in the renderer until it's placed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PastePreview {
+    pub dimension: FName,
+    pub origin: BlockPos,
+    pub transform: Transform,
+}
+
+pub fn get_clipboard() -> Option<Arc<Clipboard>> {
+    CLIPBOARD.read().unwrap().clone()
+}
+
+/// Copies the blocks, biomes, block entities and entities in the selection to the clipboard on a worker.
+pub fn copy(world: &WorldRef, selection: Selection) {
+    world.spawn_worker(move |world, _| {
+        copy_now(&world, &selection);
+    });
+}
+
+/// Copies the selection to the clipboard, then replaces it with air as a single entry in the undo history.
+pub fn cut(world: &WorldRef, selection: Selection) {
+    world.spawn_worker(move |world, _| {
+        if !copy_now(&world, &selection) {
+            return;
+        }
+        let dimension = match world.get_dimension(&selection.dimension) {
+            Some(dimension) => dimension,
+            None => return,
+        };
+        let min = selection.min().max(IVec3::new(i32::MIN, dimension.min_y, i32::MIN));
+        let max = selection.max().min(IVec3::new(i32::MAX, dimension.max_y, i32::MAX));
+        if min.y > max.y {
+            return;
+        }
+        let operation = Operation::Fill(IBlockState::new(BlockState::new(&CommonFNames.AIR)));
+        let transaction = EditTransaction::new(dimension);
+        let chunks: Vec<ChunkPos> = ((min.xz() >> 4i8)..=(max.xz() >> 4i8)).iter().collect();
+        chunks.into_par_iter().for_each(|chunk_pos| {
+            operations::apply_to_chunk(&world, &transaction, &operation, &selection, min, max, chunk_pos);
+        });
+        world.history.lock().unwrap().push(transaction);
+    });
+}
+
+fn copy_now(world: &World, selection: &Selection) -> bool {
+    let schematic = match Schematic::from_selection(world, selection) {
+        Ok(schematic) => schematic,
+        Err(err) => {
+            warn!("Failed to copy selection: {}", err);
+            return false;
+        }
+    };
+    let size = schematic.size();
+    *CLIPBOARD.write().unwrap() = Some(Arc::new(Clipboard {
+        schematic,
+        data_version: world.data_version(),
+        generation: NEXT_GENERATION.fetch_add(1, Ordering::Relaxed),
+    }));
+    info!("Copied {}x{}x{} blocks", size.x, size.y, size.z);
+    true
+}
+
+/// Pastes the clipboard into the dimension on a worker, renaming its blocks and biomes if it was copied from a world
+/// of a different version.
+pub fn paste(world: &WorldRef, dimension: FName, origin: BlockPos, transform: Transform, paste_air: bool) {
+    let clipboard = match get_clipboard() {
+        Some(clipboard) => clipboard,
+        None => return,
+    };
+    world.spawn_worker(move |world, _| {
+        let dimension = match world.get_dimension(&dimension) {
+            Some(dimension) => dimension,
+            None => return,
+        };
+        let renamed_schematic;
+        let schematic = if clipboard.data_version == world.data_version() {
+            &clipboard.schematic
+        } else {
+            let mut schematic = clipboard.schematic.clone();
+            schematic.rename(clipboard.data_version, world.data_version());
+            renamed_schematic = schematic;
+            &renamed_schematic
+        };
+        let transaction = EditTransaction::new(dimension);
+        let changed_blocks = schematic.place(&world, &transaction, origin, transform, paste_air);
+        world.history.lock().unwrap().push(transaction);
+        info!("Pasted clipboard, changed {} blocks", changed_blocks);
+    });
+}
@@ -0,0 +1,207 @@
+use std::io;
+use std::io::{Cursor, Read, Write};
+use byteorder::{BigEndian, ByteOrder, LittleEndian, ReadBytesExt};
+use flate2::{read, write};
+use serde::{Deserialize, Serialize};
+
+/// The compression types of chunks in region files, as stored in the chunk header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum ChunkCompression {
+    #[serde(rename = "gzip")]
+    Gzip,
+    #[serde(rename = "zlib")]
+    Zlib,
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "lz4")]
+    Lz4,
+}
+
+/// Followed by the name of a compression type added by a mod, which we can't read.
+const CUSTOM_COMPRESSION_ID: u8 = 127;
+
+impl ChunkCompression {
+    pub fn id(self) -> u8 {
+        match self {
+            ChunkCompression::Gzip => 1,
+            ChunkCompression::Zlib => 2,
+            ChunkCompression::None => 3,
+            ChunkCompression::Lz4 => 4,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(ChunkCompression::Gzip),
+            2 => Some(ChunkCompression::Zlib),
+            3 => Some(ChunkCompression::None),
+            4 => Some(ChunkCompression::Lz4),
+            _ => None,
+        }
+    }
+}
+
+/// Returns a reader of the decompressed chunk data, given the compression type from the chunk header.
+pub(super) fn decompress<'a>(compression_id: u8, data: &'a [u8]) -> io::Result<Box<dyn Read + 'a>> {
+    let compression = match ChunkCompression::from_id(compression_id) {
+        Some(compression) => compression,
+        None if compression_id == CUSTOM_COMPRESSION_ID => {
+            let mut cursor = Cursor::new(data);
+            let len = cursor.read_u16::<BigEndian>()? as usize;
+            let name = data.get(2..2 + len).map(String::from_utf8_lossy).unwrap_or_default();
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unsupported custom compression type \"{}\"", name)));
+        }
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown compression type")),
+    };
+    Ok(match compression {
+        ChunkCompression::Gzip => Box::new(read::GzDecoder::new(data)),
+        ChunkCompression::Zlib => Box::new(read::ZlibDecoder::new(data)),
+        ChunkCompression::None => Box::new(data),
+        ChunkCompression::Lz4 => Box::new(Cursor::new(decompress_lz4_blocks(data)?)),
+    })
+}
+
+pub(super) fn compress(compression: ChunkCompression, data: &[u8]) -> io::Result<Vec<u8>> {
+    match compression {
+        ChunkCompression::Gzip => {
+            let mut encoder = write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        ChunkCompression::Zlib => {
+            let mut encoder = write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        ChunkCompression::None => Ok(data.to_vec()),
+        ChunkCompression::Lz4 => Ok(compress_lz4_blocks(data)),
+    }
+}
+
+// Minecraft uses the block stream format of lz4-java's LZ4BlockOutputStream, rather than the standard LZ4 frame format.
+// Each block has a header of the magic, a token, the compressed and decompressed lengths and a checksum, and the stream
+// ends with an empty block.
+const LZ4_MAGIC: &[u8; 8] = b"LZ4Block";
+const LZ4_HEADER_LENGTH: usize = LZ4_MAGIC.len() + 13;
+const LZ4_METHOD_RAW: u8 = 0x10;
+const LZ4_METHOD_LZ4: u8 = 0x20;
+const LZ4_BLOCK_SIZE: usize = 1 << 16;
+const LZ4_CHECKSUM_SEED: u32 = 0x9747b28c;
+
+fn lz4_checksum(data: &[u8]) -> u32 {
+    xxhash_rust::xxh32::xxh32(data, LZ4_CHECKSUM_SEED) & 0x0fff_ffff
+}
+
+fn decompress_lz4_blocks(mut data: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid_data = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut result = Vec::new();
+    loop {
+        if data.len() < LZ4_HEADER_LENGTH || &data[..LZ4_MAGIC.len()] != LZ4_MAGIC {
+            return Err(invalid_data("LZ4 block header is missing or truncated".to_owned()));
+        }
+        let header = &data[LZ4_MAGIC.len()..LZ4_HEADER_LENGTH];
+        let method = header[0] & 0xf0;
+        let compressed_len = LittleEndian::read_i32(&header[1..]);
+        let decompressed_len = LittleEndian::read_i32(&header[5..]);
+        let checksum = LittleEndian::read_u32(&header[9..]);
+        data = &data[LZ4_HEADER_LENGTH..];
+        // checked before allocating anything, the lengths come straight from the file
+        let (compressed_len, decompressed_len) = match (usize::try_from(compressed_len), usize::try_from(decompressed_len)) {
+            (Ok(compressed_len), Ok(decompressed_len)) if compressed_len <= LZ4_BLOCK_SIZE && decompressed_len <= LZ4_BLOCK_SIZE => (compressed_len, decompressed_len),
+            _ => return Err(invalid_data(format!("Invalid LZ4 block lengths {} compressed and {} decompressed", compressed_len, decompressed_len))),
+        };
+        if decompressed_len == 0 {
+            return Ok(result);
+        }
+        let block = data.get(..compressed_len).ok_or_else(|| invalid_data(format!("LZ4 block of {} bytes is truncated", compressed_len)))?;
+        data = &data[compressed_len..];
+        let start = result.len();
+        match method {
+            LZ4_METHOD_RAW => result.extend_from_slice(block),
+            LZ4_METHOD_LZ4 => {
+                let decompressed = lz4_flex::block::decompress(block, decompressed_len)
+                    .map_err(|err| invalid_data(format!("Invalid LZ4 block: {}", err)))?;
+                result.extend_from_slice(&decompressed);
+            }
+            _ => return Err(invalid_data(format!("Unknown LZ4 block compression method {}", method))),
+        }
+        if result.len() - start != decompressed_len {
+            return Err(invalid_data(format!("Expected LZ4 block to have {} bytes, found {}", decompressed_len, result.len() - start)));
+        }
+        if lz4_checksum(&result[start..]) != checksum {
+            return Err(invalid_data("LZ4 block checksum mismatch".to_owned()));
+        }
+    }
+}
+
+fn compress_lz4_blocks(data: &[u8]) -> Vec<u8> {
+    // the compression level is derived from the block size, as in lz4-java
+    let level = (usize::BITS - (LZ4_BLOCK_SIZE - 1).leading_zeros() - 10) as u8;
+    let mut result = Vec::with_capacity(data.len() / 2);
+    let mut write_block = |method: u8, block: &[u8], decompressed: &[u8]| {
+        result.extend_from_slice(LZ4_MAGIC);
+        result.push(method | level);
+        let mut lengths = [0; 12];
+        LittleEndian::write_i32(&mut lengths[0..], block.len() as i32);
+        LittleEndian::write_i32(&mut lengths[4..], decompressed.len() as i32);
+        LittleEndian::write_u32(&mut lengths[8..], if decompressed.is_empty() { 0 } else { lz4_checksum(decompressed) });
+        result.extend_from_slice(&lengths);
+        result.extend_from_slice(block);
+    };
+    for chunk in data.chunks(LZ4_BLOCK_SIZE) {
+        let compressed = lz4_flex::block::compress(chunk);
+        if compressed.len() < chunk.len() {
+            write_block(LZ4_METHOD_LZ4, &compressed, chunk);
+        } else {
+            write_block(LZ4_METHOD_RAW, chunk, chunk);
+        }
+    }
+    write_block(LZ4_METHOD_RAW, &[], &[]);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decompress_all(compression: ChunkCompression, data: &[u8]) -> Vec<u8> {
+        let mut result = Vec::new();
+        decompress(compression.id(), data).unwrap().read_to_end(&mut result).unwrap();
+        result
+    }
+
+    #[test]
+    fn lz4_round_trips_across_blocks() {
+        // repetitive enough to compress, followed by bytes that don't, spanning several blocks
+        let mut data: Vec<u8> = (0..LZ4_BLOCK_SIZE * 2).map(|i| (i % 7) as u8).collect();
+        let mut state = 0x1234_5678u32;
+        data.extend((0..LZ4_BLOCK_SIZE + 100).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }));
+        let compressed = compress(ChunkCompression::Lz4, &data).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress_all(ChunkCompression::Lz4, &compressed), data);
+    }
+
+    #[test]
+    fn lz4_round_trips_empty_data() {
+        let compressed = compress(ChunkCompression::Lz4, &[]).unwrap();
+        assert_eq!(compressed.len(), LZ4_HEADER_LENGTH);
+        assert!(decompress_all(ChunkCompression::Lz4, &compressed).is_empty());
+    }
+
+    #[test]
+    fn lz4_rejects_corrupted_blocks() {
+        let data: Vec<u8> = (0..1000).map(|i| (i % 13) as u8).collect();
+        let mut compressed = compress(ChunkCompression::Lz4, &data).unwrap();
+        // the stored checksum no longer matches
+        compressed[LZ4_HEADER_LENGTH - 1] ^= 0x01;
+        assert!(decompress(ChunkCompression::Lz4.id(), &compressed).is_err());
+        // the end marker is cut off
+        let compressed = compress(ChunkCompression::Lz4, &data).unwrap();
+        assert!(decompress(ChunkCompression::Lz4.id(), &compressed[..compressed.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,284 @@
+use std::collections::VecDeque;
+use std::mem;
+use std::sync::Arc;
+use glam::{IVec3, Vec3Swizzles};
+use log::warn;
+use crate::fname::FName;
+use crate::geom::BlockPos;
+use crate::util::{FastDashMap, make_fast_dash_map};
+use crate::world::{BlockEntity, Dimension, IBlockState, World};
+use crate::world::palette::{BiomeData, BlockData};
+
+/// The positions changed within one subchunk, with their values before and after the edit.
+struct SubchunkChange {
+    block_mask: [u64; 64],
+    old_blocks: BlockData,
+    new_blocks: BlockData,
+    biome_mask: u64,
+    old_biomes: BiomeData,
+    new_biomes: BiomeData,
+    // whether the subchunk didn't exist before the edit, in which case undoing it removes the subchunk again
+    created: bool,
+}
+
+impl SubchunkChange {
+    fn new(created: bool) -> Self {
+        SubchunkChange {
+            block_mask: [0; 64],
+            old_blocks: BlockData::direct_init(Vec::new(), Vec::new()),
+            new_blocks: BlockData::direct_init(Vec::new(), Vec::new()),
+            biome_mask: 0,
+            old_biomes: BiomeData::direct_init(Vec::new(), Vec::new()),
+            new_biomes: BiomeData::direct_init(Vec::new(), Vec::new()),
+            created,
+        }
+    }
+
+    fn estimated_size(&self) -> usize {
+        mem::size_of::<Self>()
+            + self.old_blocks.estimated_size()
+            + self.new_blocks.estimated_size()
+            + self.old_biomes.estimated_size()
+            + self.new_biomes.estimated_size()
+    }
+}
+
+/// Records the block and biome changes made through it, so that they can be undone and redone later. Edits may be
+/// made from multiple threads at once.
+pub struct EditTransaction {
+    dimension: Arc<Dimension>,
+    // keyed by subchunk position, i.e. block position >> 4
+    changes: FastDashMap<IVec3, SubchunkChange>,
+    // the block entity before and after the edit
+    block_entity_changes: FastDashMap<BlockPos, (Option<BlockEntity>, Option<BlockEntity>)>,
+}
+
+impl EditTransaction {
+    pub fn new(dimension: Arc<Dimension>) -> Self {
+        EditTransaction {
+            dimension,
+            changes: make_fast_dash_map(),
+            block_entity_changes: make_fast_dash_map(),
+        }
+    }
+
+    pub fn dimension(&self) -> &Arc<Dimension> {
+        &self.dimension
+    }
+
+    pub fn dimension_id(&self) -> &FName {
+        &self.dimension.id
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty() && self.block_entity_changes.is_empty()
+    }
+
+    /// Sets the block state through [`Dimension::set_block_state`], recording the change. Changing the block removes
+    /// its block entity.
+    pub fn set_block_state(&self, pos: BlockPos, state: &IBlockState) -> Option<IBlockState> {
+        let existed = self.dimension.has_subchunk(pos);
+        let old_state = self.dimension.set_block_state(pos, state)?;
+        if old_state.block != state.block {
+            self.set_block_entity(pos, None);
+        }
+        if old_state != *state {
+            let created = !existed && self.dimension.has_subchunk(pos);
+            let mut change = self.changes.entry(pos >> IVec3::splat(4)).or_insert_with(|| SubchunkChange::new(created));
+            let (x, y, z) = ((pos.x & 15) as usize, (pos.y & 15) as usize, (pos.z & 15) as usize);
+            let index = y << 8 | z << 4 | x;
+            if change.block_mask[index >> 6] & (1 << (index & 63)) == 0 {
+                change.block_mask[index >> 6] |= 1 << (index & 63);
+                change.old_blocks.set(x, y, z, &old_state);
+            }
+            change.new_blocks.set(x, y, z, state);
+        }
+        Some(old_state)
+    }
+
+    /// Sets the biome through [`Dimension::set_biome`], recording the change.
+    pub fn set_biome(&self, pos: BlockPos, biome: &FName) -> Option<FName> {
+        let existed = self.dimension.has_subchunk(pos);
+        let old_biome = self.dimension.set_biome(pos, biome)?;
+        // setting a biome creates the subchunk even if the biome doesn't change
+        let created = !existed && self.dimension.has_subchunk(pos);
+        if old_biome != *biome || created {
+            let mut change = self.changes.entry(pos >> IVec3::splat(4)).or_insert_with(|| SubchunkChange::new(created));
+            let (x, y, z) = (((pos.x & 15) >> 2) as usize, ((pos.y & 15) >> 2) as usize, ((pos.z & 15) >> 2) as usize);
+            let index = y << 4 | z << 2 | x;
+            if old_biome != *biome {
+                if change.biome_mask & (1 << index) == 0 {
+                    change.biome_mask |= 1 << index;
+                    change.old_biomes.set(x, y, z, &old_biome);
+                }
+                change.new_biomes.set(x, y, z, biome);
+            }
+        }
+        Some(old_biome)
+    }
+
+    /// Sets or removes the block entity through [`Dimension::set_block_entity`], recording the change.
+    pub fn set_block_entity(&self, pos: BlockPos, block_entity: Option<BlockEntity>) -> Option<Option<BlockEntity>> {
+        if block_entity.is_none() && self.dimension.get_block_entity(pos).is_none() {
+            // don't record anything for removing a block entity that isn't there
+            return self.dimension.get_chunk(pos.xz() >> 4i8).map(|_| None);
+        }
+        let old_block_entity = self.dimension.set_block_entity(pos, block_entity.clone())?;
+        let new_block_entity = self.dimension.get_block_entity(pos);
+        self.block_entity_changes.entry(pos)
+            .and_modify(|(_, new)| *new = new_block_entity.clone())
+            .or_insert_with(|| (old_block_entity.clone(), new_block_entity));
+        Some(old_block_entity)
+    }
+
+    pub fn estimated_size(&self) -> usize {
+        mem::size_of::<Self>()
+            + self.changes.iter().map(|change| mem::size_of::<IVec3>() + change.value().estimated_size()).sum::<usize>()
+            + self.block_entity_changes.iter().map(|change| {
+                let (old, new) = change.value();
+                mem::size_of::<(BlockPos, Option<BlockEntity>, Option<BlockEntity>)>()
+                    + old.as_ref().map(estimated_block_entity_size).unwrap_or(0)
+                    + new.as_ref().map(estimated_block_entity_size).unwrap_or(0)
+            }).sum::<usize>()
+    }
+
+    fn apply(&self, world: &World, undo: bool) {
+        for entry in self.changes.iter() {
+            let subchunk_pos = *entry.key();
+            let change = entry.value();
+            if self.dimension.load_chunk(world, subchunk_pos.xz()).is_none() {
+                warn!("Could not load chunk {} to {} changes", subchunk_pos.xz(), if undo { "undo" } else { "redo" });
+                continue;
+            }
+            let (blocks, biomes) = if undo {
+                (&change.old_blocks, &change.old_biomes)
+            } else {
+                (&change.new_blocks, &change.new_biomes)
+            };
+            let origin = subchunk_pos << IVec3::splat(4);
+            for index in 0..4096 {
+                if change.block_mask[index >> 6] & (1 << (index & 63)) != 0 {
+                    let (x, y, z) = (index & 15, index >> 8, (index >> 4) & 15);
+                    self.dimension.set_block_state(origin + IVec3::new(x as i32, y as i32, z as i32), blocks.get(x, y, z));
+                }
+            }
+            for index in 0..64 {
+                if change.biome_mask & (1 << index) != 0 {
+                    let (x, y, z) = (index & 3, index >> 4, (index >> 2) & 3);
+                    self.dimension.set_biome(origin + IVec3::new(x as i32, y as i32, z as i32) * 4, biomes.get(x, y, z));
+                }
+            }
+            if undo && change.created {
+                self.dimension.remove_subchunk(origin);
+            }
+        }
+        // after the blocks, which would otherwise remove them again
+        for entry in self.block_entity_changes.iter() {
+            let pos = *entry.key();
+            let (old, new) = entry.value();
+            if self.dimension.load_chunk(world, pos.xz() >> 4i8).is_none() {
+                warn!("Could not load chunk {} to {} block entity changes", pos.xz() >> 4i8, if undo { "undo" } else { "redo" });
+                continue;
+            }
+            self.dimension.set_block_entity(pos, if undo { old.clone() } else { new.clone() });
+        }
+    }
+}
+
+fn estimated_block_entity_size(block_entity: &BlockEntity) -> usize {
+    block_entity.data.iter().map(|(key, value)| key.len() + estimated_nbt_size(value)).sum()
+}
+
+fn estimated_nbt_size(value: &nbt::Value) -> usize {
+    mem::size_of::<nbt::Value>() + match value {
+        nbt::Value::ByteArray(array) => array.len(),
+        nbt::Value::IntArray(array) => array.len() * 4,
+        nbt::Value::LongArray(array) => array.len() * 8,
+        nbt::Value::String(string) => string.len(),
+        nbt::Value::List(list) => list.iter().map(estimated_nbt_size).sum(),
+        nbt::Value::Compound(compound) => compound.iter().map(|(key, value)| key.len() + estimated_nbt_size(value)).sum(),
+        _ => 0,
+    }
+}
+
+/// The undo and redo stacks of a world. The oldest transactions are forgotten once the undo memory limit in the config
+/// is exceeded.
+#[derive(Default)]
+pub struct EditHistory {
+    undo_stack: VecDeque<(EditTransaction, usize)>,
+    redo_stack: Vec<(EditTransaction, usize)>,
+    memory_usage: usize,
+}
+
+impl EditHistory {
+    pub fn push(&mut self, transaction: EditTransaction) {
+        if transaction.is_empty() {
+            return;
+        }
+        for (_, size) in self.redo_stack.drain(..) {
+            self.memory_usage -= size;
+        }
+        let size = transaction.estimated_size();
+        self.memory_usage += size;
+        self.undo_stack.push_back((transaction, size));
+        self.trim();
+    }
+
+    /// Forgets the oldest transactions until the undo and redo stacks fit in the memory limit together. The redo stack
+    /// is only trimmed once the undo stack is empty, starting from the transaction that would be redone last.
+    fn trim(&mut self) {
+        let limit = crate::get_config().undo_memory_limit();
+        while self.memory_usage > limit {
+            let size = if let Some((_, size)) = self.undo_stack.pop_front() {
+                size
+            } else if !self.redo_stack.is_empty() {
+                self.redo_stack.remove(0).1
+            } else {
+                break;
+            };
+            self.memory_usage -= size;
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo_stack.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo_stack.is_empty()
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.memory_usage
+    }
+
+    /// Reverts the most recent transaction, returning false if there was nothing to undo.
+    pub fn undo(&mut self, world: &World) -> bool {
+        match self.undo_stack.pop_back() {
+            Some(entry) => {
+                entry.0.apply(world, true);
+                self.redo_stack.push(entry);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Reapplies the most recently undone transaction, returning false if there was nothing to redo.
+    pub fn redo(&mut self, world: &World) -> bool {
+        match self.redo_stack.pop() {
+            Some(entry) => {
+                entry.0.apply(world, false);
+                self.undo_stack.push_back(entry);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+        self.memory_usage = 0;
+    }
+}
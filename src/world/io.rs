@@ -1,29 +1,37 @@
-use std::{io, time};
+use std::{fs, io, time};
+use std::collections::BTreeMap;
 use std::io::Cursor;
 use std::mem::MaybeUninit;
-use std::path::PathBuf;
-use std::sync::{Arc, RwLock};
-use std::sync::atomic::AtomicBool;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 use ahash::AHashMap;
 use byteorder::{BigEndian, ReadBytesExt};
 use dashmap::mapref::entry::Entry;
 use dashmap::try_result::TryResult;
 use flate2::read;
-use glam::IVec2;
+use glam::{DVec3, IVec2, Vec2};
 use log::warn;
 use positioned_io_preview::{RandomAccessFile, ReadAt, ReadBytesAtExt};
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serializer};
 use crate::{CommonFNames, convert, World};
-use crate::convert::{ConvertInto, VersionedSerde};
+use crate::convert::{ConvertInto, data_versions, item_components, registries, VersionedSerde};
 use crate::fname::FName;
-use crate::geom::ChunkPos;
+use crate::geom::{BlockPos, ChunkPos};
 use crate::util::FastDashRefMut;
-use crate::world::{BlockState, Chunk, Dimension, IBlockState, Subchunk, versioned_io};
+use crate::world::{BlockEntity, BlockState, Chunk, Dimension, Entity, IBlockState, ScheduledTick, Subchunk, versioned_io};
+use crate::world::compression;
+use crate::world::compression::ChunkCompression;
 use crate::world::palette::{BiomeData, BlockData};
+use crate::world::player::*;
+use crate::world::poi::SerializedPoiChunk;
+use crate::world::region;
+use crate::world::region::{RegionFile, RegionFormat, RegionKind};
 use crate::world::versioned_io::*;
 
 impl Dimension {
-    fn get_save_dir(&self, world: &World) -> PathBuf {
+    pub(super) fn get_save_dir(&self, world: &World) -> PathBuf {
         if self.id == CommonFNames.OVERWORLD {
             world.path.clone()
         } else if self.id == CommonFNames.THE_NETHER {
@@ -35,6 +43,10 @@ impl Dimension {
         }
     }
 
+    pub(super) fn get_region_path(&self, world: &World, kind: RegionKind, region_pos: IVec2) -> PathBuf {
+        self.get_save_dir(world).join(kind.dir_name()).join(region::get_region_file_name(region_pos, world.region_format))
+    }
+
         pub fn get_chunk(&self, pos: ChunkPos) -> Option<Arc<Chunk>> {
         self.chunks.get(&pos).map(|chunk| chunk.clone())
     }
@@ -69,8 +81,9 @@ impl Dimension {
         }).ok()
     }
 
+    /// Unloads the chunk at the given position, unless it has unsaved modifications.
         pub fn unload_chunk(&self, _world: &World, pos: ChunkPos) -> bool {
-        self.chunks.remove(&pos).is_some()
+        self.chunks.remove_if(&pos, |_, chunk| !chunk.is_dirty()).is_some()
     }
 
         pub fn try_does_chunk_exist(&self, world: &World, pos: ChunkPos) -> Option<bool> {
@@ -98,7 +111,7 @@ impl Dimension {
             if self.chunks.contains_key(&pos) {
                 return Ok(true);
             }
-            let region_file = match self.get_region_file(world, pos >> 5i8, now) {
+            let region_file = match self.get_region_file(world, RegionKind::Terrain, pos >> 5i8, now) {
                 Ok(file) => file.ok_or(())?,
                 Err(e) => {
                     if e.kind() != io::ErrorKind::NotFound {
@@ -120,13 +133,13 @@ impl Dimension {
         }).ok().map(|b| *b)
     }
 
-        fn get_region_file(&self, world: &World, region_pos: IVec2, now: bool) -> io::Result<Option<FastDashRefMut<IVec2, (RandomAccessFile, time::SystemTime)>>> {
+        fn get_region_file(&self, world: &World, kind: RegionKind, region_pos: IVec2, now: bool) -> io::Result<Option<FastDashRefMut<(RegionKind, IVec2), (RandomAccessFile, time::SystemTime)>>> {
         if now {
-            if let Entry::Occupied(entry) = self.region_file_cache.entry(region_pos) {
+            if let Entry::Occupied(entry) = self.region_file_cache.entry((kind, region_pos)) {
                 return Ok(Some(entry.into_ref()));
             }
         } else {
-            match self.region_file_cache.try_entry(region_pos) {
+            match self.region_file_cache.try_entry((kind, region_pos)) {
                 Some(Entry::Occupied(entry)) => return Ok(Some(entry.into_ref())),
                 Some(_) => {},
                 None => return Ok(None),
@@ -139,60 +152,81 @@ impl Dimension {
                 self.region_file_cache.remove(&first_accessed_pos);
             }
         }
-        let region_file_cache_entry = self.region_file_cache.entry(region_pos).or_try_insert_with::<io::Error>(|| {
-            let save_dir = self.get_save_dir(world);
-            let region_path = save_dir.join("region").join(format!("r.{}.{}.mca", region_pos.x, region_pos.y));
+        let region_file_cache_entry = self.region_file_cache.entry((kind, region_pos)).or_try_insert_with::<io::Error>(|| {
+            let region_path = self.get_region_path(world, kind, region_pos);
             let raf = RandomAccessFile::open(region_path)?;
             Ok((raf, time::SystemTime::now()))
         })?;
         Ok(Some(region_file_cache_entry))
     }
 
-    #[allow(clippy::let_and_return)] // for some reason it doesn't compile without this
-    fn read_chunk(&self, world: &World, pos: ChunkPos) -> io::Result<Option<Chunk>> {
-        let serialized_chunk: SerializedChunk = {
-            let region_file_cache_entry = match self.get_region_file(world, pos >> 5i8, true) {
-                Ok(entry) => entry.unwrap(),
-                Err(e) => {
-                    return if e.kind() == io::ErrorKind::NotFound {
-                        Ok(None)
-                    } else {
-                        Err(e)
-                    }
+    /// Replaces or deletes a region file on disk while no handle to it is cached. The vacant cache entry is held
+    /// meanwhile, so that loading a chunk can't cache a handle to the old file before it's replaced.
+    pub(super) fn replace_region_file<R>(&self, kind: RegionKind, region_pos: IVec2, replace: impl FnOnce() -> io::Result<R>) -> io::Result<R> {
+        loop {
+            match self.region_file_cache.entry((kind, region_pos)) {
+                Entry::Occupied(entry) => {
+                    entry.remove();
                 }
-            };
-            let raf = &region_file_cache_entry.0;
-
-            let mut sector_data: MaybeUninit<[u8; 4]> = MaybeUninit::uninit();
-            let sector_data = unsafe {
-                raf.read_exact_at((((pos.x & 31) | ((pos.y & 31) << 5)) << 2) as u64, &mut *sector_data.as_mut_ptr())?;
-                sector_data.assume_init()
-            };
-            if sector_data == [0, 0, 0, 0] {
-                return Ok(None);
+                Entry::Vacant(_entry) => return replace(),
             }
-            let offset = Cursor::new(sector_data).read_u24::<BigEndian>()? as u64 * 4096;
-            let size = sector_data[3] as usize * 4096;
-            if size < 5 {
-                return Err(io::Error::new(io::ErrorKind::InvalidData, "Chunk header is truncated"));
+        }
+    }
+
+    /// Reads the compressed data of a chunk from a region file, returning the compression type and the data.
+    pub(super) fn read_chunk_data(&self, world: &World, kind: RegionKind, pos: ChunkPos) -> io::Result<Option<(u8, Vec<u8>)>> {
+        let region_file_cache_entry = match self.get_region_file(world, kind, pos >> 5i8, true) {
+            Ok(entry) => entry.unwrap(),
+            Err(e) => {
+                return if e.kind() == io::ErrorKind::NotFound {
+                    Ok(None)
+                } else {
+                    Err(e)
+                }
             }
-            let mut buffer = Vec::with_capacity(size);
-            #[allow(clippy::uninit_vec)]
-            unsafe { buffer.set_len(size); }
-            raf.read_exact_at(offset, &mut buffer)?;
-            let mut cursor = Cursor::new(&buffer);
-            let m = cursor.read_i32::<BigEndian>()?;
-            let b = cursor.read_u8()?;
-            if m == 0 {
-                return Err(io::Error::new(io::ErrorKind::InvalidData, "Chunk is allocated, but stream is missing"));
+        };
+        let raf = &region_file_cache_entry.0;
+
+        let mut sector_data: MaybeUninit<[u8; 4]> = MaybeUninit::uninit();
+        let sector_data = unsafe {
+            raf.read_exact_at((((pos.x & 31) | ((pos.y & 31) << 5)) << 2) as u64, &mut *sector_data.as_mut_ptr())?;
+            sector_data.assume_init()
+        };
+        if sector_data == [0, 0, 0, 0] {
+            return Ok(None);
+        }
+        let offset = Cursor::new(sector_data).read_u24::<BigEndian>()? as u64 * 4096;
+        let size = sector_data[3] as usize * 4096;
+        if size < 5 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Chunk header is truncated"));
+        }
+        let mut buffer = Vec::with_capacity(size);
+        #[allow(clippy::uninit_vec)]
+        unsafe { buffer.set_len(size); }
+        raf.read_exact_at(offset, &mut buffer)?;
+        let mut cursor = Cursor::new(&buffer);
+        let m = cursor.read_i32::<BigEndian>()?;
+        let mut b = cursor.read_u8()?;
+        if m == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Chunk is allocated, but stream is missing"));
+        }
+        let mut cursor_pos = cursor.position() as usize;
+        if b & region::EXTERNAL_FLAG != 0 {
+            if m != 1 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Chunk has both internal and external streams"));
             }
-            if b & 128 != 0 {
-                if m != 1 {
-                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Chunk has both internal and external streams"));
+            // the whole external file is the compressed chunk, without a header
+            let external_path = self.get_save_dir(world).join(kind.dir_name()).join(region::get_external_chunk_file_name(pos));
+            buffer = match fs::read(&external_path) {
+                Ok(data) => data,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("External chunk file {} is missing", external_path.display())));
                 }
-                // TODO: read external chunks
-                return Err(io::Error::new(io::ErrorKind::InvalidData, "External chunk"));
-            }
+                Err(e) => return Err(e),
+            };
+            b &= !region::EXTERNAL_FLAG;
+            cursor_pos = 0;
+        } else {
             if m < 0 {
                 return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Declared size {} of chunk is negative", m)));
             }
@@ -200,16 +234,29 @@ impl Dimension {
             if n > size - 5 {
                 return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Declared size {} of chunk is larger than actual size {}", n, size)));
             }
-            let cursor_pos = cursor.position() as usize;
-            let make_deserializer = || {
-                let cursor = Cursor::new(&buffer[cursor_pos..]);
-                let read: Box<dyn io::Read> = match b {
-                    1 => Box::new(read::GzDecoder::new(cursor)),
-                    2 => Box::new(read::ZlibDecoder::new(cursor)),
-                    3 => Box::new(cursor),
-                    _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown compression type")),
-                };
-                Ok(nbt::de::Decoder::new(read))
+        }
+        buffer.drain(..cursor_pos);
+        Ok(Some((b, buffer)))
+    }
+
+    #[allow(clippy::let_and_return)] // for some reason it doesn't compile without this
+    fn read_chunk(&self, world: &World, pos: ChunkPos) -> io::Result<Option<Chunk>> {
+        let (version, mut serialized_chunk): (u32, SerializedChunk) = {
+            let (b, buffer) = match self.read_chunk_data(world, RegionKind::Terrain, pos)? {
+                Some(data) => data,
+                None => return Ok(None),
+            };
+            let make_deserializer = || -> io::Result<_> {
+                Ok(nbt::de::Decoder::new(compression::decompress(b, &buffer)?))
+            };
+            if let Some(compression) = ChunkCompression::from_id(b) {
+                world.detect_chunk_compression(compression);
+            }
+            // McRegion chunks are rearranged into the oldest Anvil format and read like any other chunk from there
+            let (b, buffer) = if world.region_format == RegionFormat::McRegion {
+                (ChunkCompression::None.id(), versioned_io::mcregion_to_anvil(compression::decompress(b, &buffer)?)?)
+            } else {
+                (b, buffer)
             };
             #[cfg(feature = "debug-chunk-deserialization")]
             {
@@ -217,7 +264,7 @@ impl Dimension {
                     let coords: Vec<i32> = chunk_pos.split(',').map(|s| s.parse().unwrap()).collect();
                     if pos.x == coords[0] && pos.y == coords[1] {
                         use std::io::Write;
-                        std::fs::File::create("debug-chunk-deserialization.nbt").unwrap().write_all(&buffer[cursor_pos..]).unwrap();
+                        std::fs::File::create("debug-chunk-deserialization.nbt").unwrap().write_all(&buffer).unwrap();
                     }
                 }
             }
@@ -225,47 +272,601 @@ impl Dimension {
             versioned_io::CURRENT_DIMENSION.with(|cur_dim| {
                 cur_dim.replace(self.id.clone());
             });
-            let result = VersionedSerde::deserialize(version, world.level_dat.data.version.id, &mut make_deserializer()?)?;
-            result
+            let result = VersionedSerde::deserialize(version, world.data_version(), &mut make_deserializer()?)?;
+            (version, result)
         };
+        // chunks that haven't been saved since the world was upgraded still use the names of their own version
+        rename_chunk(&mut serialized_chunk, version, world.data_version());
 
         let mut chunk = Chunk::empty();
+        chunk.status = serialized_chunk.status;
+        chunk.inhabited_time = serialized_chunk.inhabited_time;
+        chunk.last_update = serialized_chunk.last_update;
+        chunk.extra = serialized_chunk._extra;
+        *chunk.block_entities.get_mut().unwrap() = serialized_chunk.block_entities.into_iter()
+            .map(|block_entity| {
+                let block_entity = block_entity.into_block_entity();
+                (block_entity.pos, block_entity)
+            })
+            .collect();
+        *chunk.entities.get_mut().unwrap() = serialized_chunk.entities.into_iter().map(SerializedEntity::into_entity).collect();
+        *chunk.block_ticks.get_mut().unwrap() = serialized_chunk.block_ticks.into_iter().map(SerializedTick::into_scheduled_tick).collect();
+        *chunk.fluid_ticks.get_mut().unwrap() = serialized_chunk.fluid_ticks.into_iter().map(SerializedTick::into_scheduled_tick).collect();
+        *chunk.heightmaps.get_mut().unwrap() = serialized_chunk.heightmaps;
         for serialized_section in serialized_chunk.sections {
-            let block_palette: Vec<_> = serialized_section.block_states.palette.iter().map(|serialized_state| {
-                let mut state = BlockState::new(&serialized_state.name);
-                for (k, v) in &serialized_state.properties {
-                    state.properties.insert(k.clone(), FName::new(v.to_string().parse().unwrap()));
-                }
-                IBlockState::new(state)
-            }).collect();
+            let block_palette: Vec<_> = serialized_section.block_states.palette.iter().map(SerializedBlockState::to_block_state).collect();
+            let biome_palette = serialized_section.biomes.palette;
             let block_data = BlockData::direct_init(block_palette, serialized_section.block_states.data.iter().map(|i| *i as u64).collect());
-            let biome_data = BiomeData::direct_init(serialized_section.biomes.palette, serialized_section.biomes.data.iter().map(|i| *i as u64).collect());
-            chunk.subchunks.push(Some(Subchunk {
+            let biome_data = BiomeData::direct_init(biome_palette, serialized_section.biomes.data.iter().map(|i| *i as u64).collect());
+            let mut extra = serialized_section._extra;
+            extra.remove("Y");
+            chunk.subchunks.push(OnceLock::from(Subchunk {
                 block_data: RwLock::new(block_data),
                 biome_data: RwLock::new(biome_data),
                 needs_redraw: AtomicBool::new(true),
+                dirty: AtomicBool::new(false),
+                extra,
+                removed: AtomicBool::new(false),
             }));
         }
         let num_subchunks = ((self.max_y - self.min_y + 1) >> 4) as usize;
         if chunk.subchunks.len() < num_subchunks {
             chunk.subchunks.reserve(num_subchunks);
             while chunk.subchunks.len() < num_subchunks {
-                chunk.subchunks.push(None);
+                chunk.subchunks.push(OnceLock::new());
             }
         } else {
             chunk.subchunks.truncate(num_subchunks);
         }
         chunk.subchunks.shrink_to_fit();
 
+        // chunks that haven't finished generating still keep their entities
+        if world.data_version() >= data_versions::V1_17 && chunk.entities.get_mut().unwrap().is_empty() {
+            chunk.separate_entities = true;
+            *chunk.entities.get_mut().unwrap() = self.read_entities(world, pos)?;
+        }
+
         Ok(Some(chunk))
     }
+
+    /// Reads the entities of a chunk from the entities region file, since 1.17.
+    fn read_entities(&self, world: &World, pos: ChunkPos) -> io::Result<Vec<Entity>> {
+        let (b, buffer) = match self.read_chunk_data(world, RegionKind::Entities, pos)? {
+            Some(data) => data,
+            None => return Ok(Vec::new()),
+        };
+        let make_deserializer = || -> io::Result<_> {
+            Ok(nbt::de::Decoder::new(compression::decompress(b, &buffer)?))
+        };
+        let version = convert::get_version(&mut make_deserializer()?)?;
+        let mut entity_chunk: SerializedEntityChunk = VersionedSerde::deserialize(version, world.data_version(), &mut make_deserializer()?)?;
+        for entity in &mut entity_chunk.entities {
+            item_components::rename_nested_item_stacks(&mut entity._extra, version, world.data_version());
+        }
+        Ok(entity_chunk.entities.into_iter().map(SerializedEntity::into_entity).collect())
+    }
+
+        pub fn save_chunk(&self, world: &World, pos: ChunkPos) -> io::Result<bool> {
+        let chunk = match self.get_chunk(pos) {
+            Some(chunk) => chunk,
+            None => return Ok(false),
+        };
+        self.save_region(world, pos >> 5i8, &[(pos, chunk)])?;
+        Ok(true)
+    }
+
+    pub(super) fn save_dirty_chunks(&self, world: &World) -> io::Result<usize> {
+        let mut chunks_by_region: AHashMap<IVec2, Vec<(ChunkPos, Arc<Chunk>)>> = AHashMap::new();
+        for entry in self.chunks.iter() {
+            if entry.value().is_dirty() {
+                chunks_by_region.entry(*entry.key() >> 5i8).or_default().push((*entry.key(), entry.value().clone()));
+            }
+        }
+        let mut saved_chunks = 0;
+        for (region_pos, chunks) in chunks_by_region {
+            self.save_region(world, region_pos, &chunks)?;
+            saved_chunks += chunks.len();
+        }
+        Ok(saved_chunks)
+    }
+
+    fn save_region(&self, world: &World, region_pos: IVec2, chunks: &[(ChunkPos, Arc<Chunk>)]) -> io::Result<()> {
+        let result: io::Result<()> = try {
+            let mut region_file = RegionFile::open_or_create(self.get_region_path(world, RegionKind::Terrain, region_pos))?;
+            let mut entities_region_file = None;
+            let mut poi_region_file = None;
+            let compression = world.chunk_compression();
+            let rebuild_poi = crate::get_config().rebuild_poi_on_save && world.data_version() >= data_versions::V1_14;
+            for (pos, chunk) in chunks {
+                let dirty_subchunks: Vec<usize> = chunk.subchunks.iter().enumerate()
+                    .filter(|(_, subchunk)| subchunk.get().is_some_and(Subchunk::is_dirty))
+                    .map(|(index, _)| index)
+                    .collect();
+                // clear the dirty flag before taking the snapshot, so that concurrent edits get saved next time
+                chunk.clear_dirty();
+                let payload = self.write_chunk_payload(world, *pos, chunk, compression)?;
+                region_file.write_chunk(*pos, compression.id(), &payload)?;
+                if chunk.separate_entities {
+                    if entities_region_file.is_none() {
+                        entities_region_file = Some(RegionFile::open_or_create(self.get_region_path(world, RegionKind::Entities, region_pos))?);
+                    }
+                    let entities_region_file = entities_region_file.as_mut().unwrap();
+                    let entities = chunk.entities.read().unwrap();
+                    // the game removes entity chunks without entities too
+                    if entities.is_empty() {
+                        entities_region_file.remove_chunk(*pos);
+                    } else {
+                        let payload = self.write_entities_payload(world, *pos, &entities, compression)?;
+                        entities_region_file.write_chunk(*pos, compression.id(), &payload)?;
+                    }
+                }
+                if rebuild_poi && !dirty_subchunks.is_empty() {
+                    // read before the POI region file is replaced below. The game rebuilds missing sections itself, so
+                    // unreadable POI data doesn't need to stop the save
+                    let mut sections = self.read_poi(world, *pos).unwrap_or_else(|err| {
+                        warn!("Failed to read POI data of chunk {}: {}", pos, err);
+                        BTreeMap::new()
+                    });
+                    self.rebuild_poi_sections(*pos, chunk, &dirty_subchunks, &mut sections);
+                    if poi_region_file.is_none() {
+                        poi_region_file = Some(RegionFile::open_or_create(self.get_region_path(world, RegionKind::Poi, region_pos))?);
+                    }
+                    self.write_poi_to_region(world, poi_region_file.as_mut().unwrap(), *pos, &sections, compression)?;
+                }
+            }
+            // the cached handles would keep pointing to the old files after they're replaced
+            self.replace_region_file(RegionKind::Terrain, region_pos, || region_file.save())?;
+            if let Some(entities_region_file) = entities_region_file {
+                self.replace_region_file(RegionKind::Entities, region_pos, || entities_region_file.save())?;
+            }
+            if let Some(poi_region_file) = poi_region_file {
+                self.replace_region_file(RegionKind::Poi, region_pos, || poi_region_file.save())?;
+            }
+        };
+        match result {
+            Ok(()) => {
+                for (pos, _) in chunks {
+                    self.chunk_existence_cache.insert(*pos, true);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                for (_, chunk) in chunks {
+                    chunk.mark_dirty();
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn write_chunk_payload(&self, world: &World, pos: ChunkPos, chunk: &Chunk, compression: ChunkCompression) -> io::Result<Vec<u8>> {
+        let version = world.data_version();
+        let serialized_chunk = self.to_serialized_chunk(pos, chunk, version);
+        versioned_io::CURRENT_DIMENSION.with(|cur_dim| {
+            cur_dim.replace(self.id.clone());
+        });
+        let mut nbt_data = Vec::new();
+        VersionedSerde::serialize(serialized_chunk, version, version, &mut nbt::ser::Encoder::new(&mut nbt_data, None))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        compression::compress(compression, &nbt_data)
+    }
+
+    fn write_entities_payload(&self, world: &World, pos: ChunkPos, entities: &[Entity], compression: ChunkCompression) -> io::Result<Vec<u8>> {
+        let version = world.data_version();
+        let mut extra = BTreeMap::new();
+        extra.insert("DataVersion".to_owned(), nbt::Value::Int(version as i32));
+        let entity_chunk = SerializedEntityChunk {
+            position: vec![pos.x, pos.y],
+            entities: entities.iter().map(SerializedEntity::from_entity).collect(),
+            _extra: extra,
+        };
+        let mut nbt_data = Vec::new();
+        VersionedSerde::serialize(entity_chunk, version, version, &mut nbt::ser::Encoder::new(&mut nbt_data, None))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        compression::compress(compression, &nbt_data)
+    }
+
+    /// Converts the uncompressed NBT of a chunk from a region file of the given kind to another data version. Returns
+    /// the converted NBT, and for terrain chunks converted to 1.17 or later from before it, the NBT of the chunk in the
+    /// entities region file if it has entities. The NBT of the chunk in the entities region file can be given to move
+    /// its entities into a terrain chunk converted to before 1.17.
+    pub(super) fn convert_chunk_nbt(&self, kind: RegionKind, pos: ChunkPos, nbt_data: &[u8], target_version: u32, entities_nbt: Option<&[u8]>) -> io::Result<(Vec<u8>, Option<Vec<u8>>)> {
+        versioned_io::CURRENT_DIMENSION.with(|cur_dim| {
+            cur_dim.replace(self.id.clone());
+        });
+        let version = convert::get_version(&mut nbt::de::Decoder::new(nbt_data))?;
+        let data_version = nbt::Value::Int(target_version as i32);
+        let to_io_error = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+        let mut result = Vec::new();
+        let mut encoder = nbt::ser::Encoder::new(&mut result, None);
+        let mut entities_result = None;
+        match kind {
+            RegionKind::Terrain => {
+                let mut chunk: SerializedChunk = VersionedSerde::deserialize(version, target_version, &mut nbt::de::Decoder::new(nbt_data))?;
+                // the chunk is read with the names of its own version
+                rename_chunk(&mut chunk, version, target_version);
+                if let Some(entities_nbt) = entities_nbt {
+                    let entities_version = convert::get_version(&mut nbt::de::Decoder::new(entities_nbt))?;
+                    let mut entity_chunk: SerializedEntityChunk = VersionedSerde::deserialize(entities_version, target_version, &mut nbt::de::Decoder::new(entities_nbt))?;
+                    for entity in &mut entity_chunk.entities {
+                        item_components::rename_nested_item_stacks(&mut entity._extra, entities_version, target_version);
+                    }
+                    chunk.entities.extend(entity_chunk.entities);
+                }
+                // only chunks that haven't finished generating keep their entities since 1.17
+                let is_full = chunk.status.as_deref().map_or(false, |status| status.strip_prefix("minecraft:").unwrap_or(status) == "full");
+                if version < data_versions::V1_17 && target_version >= data_versions::V1_17 && is_full && !chunk.entities.is_empty() {
+                    let mut extra = BTreeMap::new();
+                    extra.insert("DataVersion".to_owned(), data_version.clone());
+                    let entity_chunk = SerializedEntityChunk {
+                        position: vec![pos.x, pos.y],
+                        entities: std::mem::take(&mut chunk.entities),
+                        _extra: extra,
+                    };
+                    let mut entities_nbt = Vec::new();
+                    VersionedSerde::serialize(entity_chunk, target_version, target_version, &mut nbt::ser::Encoder::new(&mut entities_nbt, None))
+                        .map_err(to_io_error)?;
+                    entities_result = Some(entities_nbt);
+                }
+                chunk._extra.insert("DataVersion".to_owned(), data_version);
+                // light is stored differently across versions, this makes the game recalculate it
+                chunk._extra.insert("isLightOn".to_owned(), nbt::Value::Byte(0));
+                VersionedSerde::serialize(chunk, target_version, target_version, &mut encoder)
+            }
+            RegionKind::Entities => {
+                let mut entity_chunk: SerializedEntityChunk = VersionedSerde::deserialize(version, target_version, &mut nbt::de::Decoder::new(nbt_data))?;
+                for entity in &mut entity_chunk.entities {
+                    item_components::rename_nested_item_stacks(&mut entity._extra, version, target_version);
+                }
+                entity_chunk._extra.insert("DataVersion".to_owned(), data_version);
+                VersionedSerde::serialize(entity_chunk, target_version, target_version, &mut encoder)
+            }
+            RegionKind::Poi => {
+                let mut poi_chunk: SerializedPoiChunk = VersionedSerde::deserialize(version, target_version, &mut nbt::de::Decoder::new(nbt_data))?;
+                poi_chunk._extra.insert("DataVersion".to_owned(), data_version);
+                VersionedSerde::serialize(poi_chunk, target_version, target_version, &mut encoder)
+            }
+        }.map_err(to_io_error)?;
+        Ok((result, entities_result))
+    }
+
+    fn to_serialized_chunk(&self, pos: ChunkPos, chunk: &Chunk, version: u32) -> SerializedChunk {
+        let sections = chunk.subchunks.iter().enumerate().filter_map(|(index, subchunk)| {
+            let subchunk = subchunk.get().filter(|subchunk| !subchunk.removed.load(Ordering::Acquire))?;
+            let (block_palette, block_data) = subchunk.block_data.read().unwrap().to_serialized();
+            let (biome_palette, biome_data) = subchunk.biome_data.read().unwrap().to_serialized();
+            let mut extra = subchunk.extra.clone();
+            extra.insert("Y".to_owned(), nbt::Value::Byte(((self.min_y >> 4) + index as i32) as i8));
+            Some(SerializedChunkSection {
+                block_states: SerializedBlockStates {
+                    palette: block_palette.iter().map(|state| SerializedBlockState::from_block_state(state)).collect(),
+                    data: block_data.into_iter().map(|i| i as i64).collect(),
+                    _extra: Default::default(),
+                },
+                biomes: SerializedBiomes {
+                    palette: biome_palette,
+                    data: biome_data.into_iter().map(|i| i as i64).collect(),
+                    _extra: Default::default(),
+                },
+                _extra: extra,
+            })
+        }).collect();
+
+        let mut extra = chunk.extra.clone();
+        extra.insert("DataVersion".to_owned(), nbt::Value::Int(version as i32));
+        extra.insert("xPos".to_owned(), nbt::Value::Int(pos.x));
+        extra.insert("zPos".to_owned(), nbt::Value::Int(pos.y));
+        // light is stale after editing blocks, this makes the game recalculate it
+        extra.insert("isLightOn".to_owned(), nbt::Value::Byte(0));
+        SerializedChunk {
+            sections,
+            block_entities: chunk.block_entities.read().unwrap().values().map(SerializedBlockEntity::from_block_entity).collect(),
+            entities: if chunk.separate_entities {
+                Vec::new()
+            } else {
+                chunk.entities.read().unwrap().iter().map(SerializedEntity::from_entity).collect()
+            },
+            block_ticks: chunk.block_ticks.read().unwrap().iter().map(SerializedTick::from_scheduled_tick).collect(),
+            fluid_ticks: chunk.fluid_ticks.read().unwrap().iter().map(SerializedTick::from_scheduled_tick).collect(),
+            heightmaps: chunk.heightmaps.read().unwrap().clone(),
+            status: chunk.status.clone(),
+            inhabited_time: chunk.inhabited_time,
+            last_update: chunk.last_update,
+            _extra: extra,
+        }
+    }
+}
+
+/// Renames the block states, biomes and items of a chunk that was read at `version` to `target_version`. Block states
+/// from before the flattening and biomes from before 1.18 are converted from IDs straight to the names of the target
+/// version, so they aren't renamed again.
+fn rename_chunk(chunk: &mut SerializedChunk, version: u32, target_version: u32) {
+    if version == target_version {
+        return;
+    }
+    for section in &mut chunk.sections {
+        if version >= data_versions::V1_13 {
+            for state in &mut section.block_states.palette {
+                let renamed = registries::rename_block_state(&state.to_block_state(), version, target_version);
+                *state = SerializedBlockState::from_block_state(&renamed);
+            }
+        }
+        if version >= data_versions::V1_18 {
+            for biome in &mut section.biomes.palette {
+                *biome = registries::rename_biome(biome, version, target_version);
+            }
+        }
+    }
+    for block_entity in &mut chunk.block_entities {
+        item_components::rename_nested_item_stacks(&mut block_entity._extra, version, target_version);
+    }
+    for entity in &mut chunk.entities {
+        item_components::rename_nested_item_stacks(&mut entity._extra, version, target_version);
+    }
+}
+
+fn serialize_block_properties<S: Serializer>(properties: &AHashMap<FName, nbt::Value>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(properties.iter().map(|(k, v)| (k.to_nice_string(), v)))
+}
+
+pub(super) fn serialize_heightmaps<S: Serializer>(heightmaps: &BTreeMap<String, Vec<i64>>, serializer: S) -> Result<S::Ok, S::Error> {
+    struct LongArray<'a>(&'a [i64]);
+    impl serde::Serialize for LongArray<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            nbt::i64_array(self.0, serializer)
+        }
+    }
+    serializer.collect_map(heightmaps.iter().map(|(k, v)| (k, LongArray(v))))
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+convert::variants! {
+    pub(super) struct SerializedBlockEntity {
+        id: FName,
+        x: i32,
+        y: i32,
+        z: i32,
+        #[serde(rename = "keepPacked")]
+        #[serde(default, skip_serializing_if = "is_false")]
+        keep_packed: bool,
+    }
+    fn up(older: Self::UpInput, _prevailing_version: u32) -> Self::UpResult {
+        let mut extra = older._extra;
+        item_components::upgrade_nested_item_stacks(&mut extra);
+        Ok(Self::UpOutput {
+            id: older.id,
+            x: older.x,
+            y: older.y,
+            z: older.z,
+            keep_packed: older.keep_packed,
+            _extra: extra,
+        })
+    }
+    fn down(newer: Self::DownInput, _prevailing_version: u32) -> Self::DownResult {
+        let mut extra = newer._extra;
+        item_components::downgrade_nested_item_stacks(&mut extra);
+        Ok(Self::DownOutput {
+            id: newer.id,
+            x: newer.x,
+            y: newer.y,
+            z: newer.z,
+            keep_packed: newer.keep_packed,
+            _extra: extra,
+        })
+    }
+    // item stacks use data components since 1.20.5
+    1,20,4 => {
+        id: FName,
+        x: i32,
+        y: i32,
+        z: i32,
+        #[serde(rename = "keepPacked")]
+        #[serde(default, skip_serializing_if = "is_false")]
+        keep_packed: bool,
+    }
+}
+
+impl SerializedBlockEntity {
+    pub(super) fn from_block_entity(block_entity: &BlockEntity) -> Self {
+        SerializedBlockEntity {
+            id: block_entity.id.clone(),
+            x: block_entity.pos.x,
+            y: block_entity.pos.y,
+            z: block_entity.pos.z,
+            keep_packed: block_entity.keep_packed,
+            _extra: block_entity.data.clone(),
+        }
+    }
+
+    pub(super) fn into_block_entity(self) -> BlockEntity {
+        BlockEntity {
+            id: self.id,
+            pos: BlockPos::new(self.x, self.y, self.z),
+            keep_packed: self.keep_packed,
+            data: self._extra,
+        }
+    }
+}
+
+convert::variants! {
+    pub(super) struct SerializedEntity {
+        id: FName,
+        #[serde(rename = "Pos")]
+        pos: Vec<f64>,
+        #[serde(rename = "Motion")]
+        #[serde(default)]
+        motion: Vec<f64>,
+        #[serde(rename = "Rotation")]
+        #[serde(default)]
+        rotation: Vec<f32>,
+        #[serde(rename = "UUID")]
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Vec::is_empty", serialize_with = "nbt::i32_array")]
+        uuid: Vec<i32>,
+    }
+    fn up(older: Self::UpInput, _prevailing_version: u32) -> Self::UpResult {
+        let mut extra = older._extra;
+        item_components::upgrade_nested_item_stacks(&mut extra);
+        Ok(Self::UpOutput {
+            id: older.id,
+            pos: older.pos,
+            motion: older.motion,
+            rotation: older.rotation,
+            uuid: older.uuid,
+            _extra: extra,
+        })
+    }
+    fn down(newer: Self::DownInput, _prevailing_version: u32) -> Self::DownResult {
+        let mut extra = newer._extra;
+        item_components::downgrade_nested_item_stacks(&mut extra);
+        Ok(Self::DownOutput {
+            id: newer.id,
+            pos: newer.pos,
+            motion: newer.motion,
+            rotation: newer.rotation,
+            uuid: newer.uuid,
+            _extra: extra,
+        })
+    }
+    1,20,4 => {
+        id: FName,
+        #[serde(rename = "Pos")]
+        pos: Vec<f64>,
+        #[serde(rename = "Motion")]
+        #[serde(default)]
+        motion: Vec<f64>,
+        #[serde(rename = "Rotation")]
+        #[serde(default)]
+        rotation: Vec<f32>,
+        #[serde(rename = "UUID")]
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Vec::is_empty", serialize_with = "nbt::i32_array")]
+        uuid: Vec<i32>,
+    }
+}
+
+impl SerializedEntity {
+    pub(super) fn from_entity(entity: &Entity) -> Self {
+        SerializedEntity {
+            id: entity.id.clone(),
+            pos: entity.pos.to_array().to_vec(),
+            motion: entity.motion.to_array().to_vec(),
+            rotation: entity.rotation.to_array().to_vec(),
+            uuid: entity.uuid.map(|uuid| (0..4).rev().map(|i| (uuid >> (i * 32)) as i32).collect()).unwrap_or_default(),
+            _extra: entity.data.clone(),
+        }
+    }
+
+    pub(super) fn into_entity(self) -> Entity {
+        Entity {
+            id: self.id,
+            pos: <[f64; 3]>::try_from(self.pos).map(DVec3::from).unwrap_or(DVec3::ZERO),
+            motion: <[f64; 3]>::try_from(self.motion).map(DVec3::from).unwrap_or(DVec3::ZERO),
+            rotation: <[f32; 2]>::try_from(self.rotation).map(Vec2::from).unwrap_or(Vec2::ZERO),
+            uuid: (self.uuid.len() == 4).then(|| self.uuid.iter().fold(0, |uuid, part| (uuid << 32) | *part as u32 as u128)),
+            data: self._extra,
+        }
+    }
+}
+
+/// Reads the NBT of a block entity that was saved at `data_version` outside of a chunk, e.g. in a schematic.
+pub(super) fn block_entity_from_nbt(nbt: &BTreeMap<String, nbt::Value>, data_version: u32, prevailing_version: u32) -> io::Result<BlockEntity> {
+    let mut block_entity: SerializedBlockEntity = versioned_from_nbt(nbt, data_version, prevailing_version)?;
+    item_components::rename_nested_item_stacks(&mut block_entity._extra, data_version, prevailing_version);
+    Ok(block_entity.into_block_entity())
+}
+
+pub(super) fn block_entity_to_nbt(block_entity: &BlockEntity, version: u32) -> io::Result<BTreeMap<String, nbt::Value>> {
+    versioned_to_nbt(SerializedBlockEntity::from_block_entity(block_entity), version)
+}
+
+/// Reads the NBT of an entity that was saved at `data_version` outside of a chunk, e.g. in a schematic.
+pub(super) fn entity_from_nbt(nbt: &BTreeMap<String, nbt::Value>, data_version: u32, prevailing_version: u32) -> io::Result<Entity> {
+    let mut entity: SerializedEntity = versioned_from_nbt(nbt, data_version, prevailing_version)?;
+    item_components::rename_nested_item_stacks(&mut entity._extra, data_version, prevailing_version);
+    Ok(entity.into_entity())
 }
 
-pub fn get_level_dat_version<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+pub(super) fn entity_to_nbt(entity: &Entity, version: u32) -> io::Result<BTreeMap<String, nbt::Value>> {
+    versioned_to_nbt(SerializedEntity::from_entity(entity), version)
+}
+
+// round trips through encoded NBT, since the conversions only exist between serde types
+fn versioned_from_nbt<T: for<'de> VersionedSerde<'de>>(nbt: &BTreeMap<String, nbt::Value>, version: u32, prevailing_version: u32) -> io::Result<T> {
+    let mut nbt_data = Vec::new();
+    serde::Serialize::serialize(nbt, &mut nbt::ser::Encoder::new(&mut nbt_data, None))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(T::deserialize(version, prevailing_version, &mut nbt::de::Decoder::new(&nbt_data[..]))?)
+}
+
+fn versioned_to_nbt<T: for<'de> VersionedSerde<'de>>(value: T, version: u32) -> io::Result<BTreeMap<String, nbt::Value>> {
+    let mut nbt_data = Vec::new();
+    VersionedSerde::serialize(value, version, version, &mut nbt::ser::Encoder::new(&mut nbt_data, None))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(Deserialize::deserialize(&mut nbt::de::Decoder::new(&nbt_data[..]))?)
+}
+
+convert::variants! {
+    /// A chunk of an entities region file.
+    struct SerializedEntityChunk {
+        #[serde(rename = "Position")]
+        #[serde(serialize_with = "nbt::i32_array")]
+        position: Vec<i32>,
+        #[serde(rename = "Entities")]
+        #[serde(default)]
+        #[variants]
+        entities: Vec<SerializedEntity>,
+    }
+}
+
+convert::variants! {
+    pub(super) struct SerializedTick {
+        #[serde(rename = "i")]
+        target: FName,
+        #[serde(rename = "p")]
+        #[serde(default)]
+        priority: i32,
+        #[serde(rename = "t")]
+        delay: i32,
+        x: i32,
+        y: i32,
+        z: i32,
+    }
+}
+
+impl SerializedTick {
+    pub(super) fn from_scheduled_tick(tick: &ScheduledTick) -> Self {
+        SerializedTick {
+            target: tick.target.clone(),
+            priority: tick.priority,
+            delay: tick.delay,
+            x: tick.pos.x,
+            y: tick.pos.y,
+            z: tick.pos.z,
+            _extra: Default::default(),
+        }
+    }
+
+    pub(super) fn into_scheduled_tick(self) -> ScheduledTick {
+        ScheduledTick {
+            target: self.target,
+            pos: BlockPos::new(self.x, self.y, self.z),
+            delay: self.delay,
+            priority: self.priority,
+        }
+    }
+}
+
+/// Returns `None` for worlds from before 1.9, which don't have a data version.
+pub fn get_level_dat_version<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
     #[derive(Deserialize)]
     struct LevelDatVersionExtractorData {
         #[serde(rename = "DataVersion")]
-        data_version: u32,
+        #[serde(default)]
+        data_version: Option<u32>,
     }
     #[derive(Deserialize)]
     struct LevelDatVersionExtractor {
@@ -276,7 +877,81 @@ pub fn get_level_dat_version<'de, D: Deserializer<'de>>(deserializer: D) -> Resu
     Ok(extractor.data.data_version)
 }
 
+pub(super) fn read_level_dat(path: &Path) -> io::Result<LevelDat> {
+    let version = match get_level_dat_version(&mut nbt::de::Decoder::new(read::GzDecoder::new(File::open(path)?)))? {
+        Some(version) => version,
+        None => return read_legacy_level_dat(path),
+    };
+    Ok(VersionedSerde::deserialize(version, version, &mut nbt::de::Decoder::new(read::GzDecoder::new(File::open(path)?)))?)
+}
+
+/// Reads a level.dat from before 1.9 as the oldest supported version, adding the version info it doesn't have.
+fn read_legacy_level_dat(path: &Path) -> io::Result<LevelDat> {
+    let mut root: BTreeMap<String, nbt::Value> = Deserialize::deserialize(&mut nbt::de::Decoder::new(read::GzDecoder::new(File::open(path)?)))?;
+    if let Some(nbt::Value::Compound(data)) = root.get_mut("Data") {
+        let version = data_versions::V1_8;
+        data.insert("DataVersion".to_owned(), nbt::Value::Int(version as i32));
+        data.insert("Version".to_owned(), nbt::Value::Compound([
+            ("Id".to_owned(), nbt::Value::Int(version as i32)),
+            ("Name".to_owned(), nbt::Value::String(data_versions::get_version_name(version).unwrap_or_default().to_owned())),
+        ].into_iter().collect()));
+        // item IDs were numbers before 1.8, which the player can't be read with
+        if data.get("Player").is_some_and(has_numeric_item_ids) {
+            data.remove("Player");
+        }
+    }
+    let mut nbt_data = Vec::new();
+    serde::Serialize::serialize(&root, &mut nbt::ser::Encoder::new(&mut nbt_data, None))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(VersionedSerde::deserialize(data_versions::V1_8, data_versions::V1_8, &mut nbt::de::Decoder::new(&nbt_data[..]))?)
+}
+
+fn has_numeric_item_ids(player: &nbt::Value) -> bool {
+    let inventory = match player {
+        nbt::Value::Compound(player) => player.get("Inventory"),
+        _ => None,
+    };
+    match inventory {
+        Some(nbt::Value::List(items)) => items.iter().any(|item| match item {
+            nbt::Value::Compound(item) => !matches!(item.get("id"), Some(nbt::Value::String(_))),
+            _ => false,
+        }),
+        _ => false,
+    }
+}
+
+impl World {
+    pub(super) fn save_level_dat(&self) -> io::Result<()> {
+        let level_dat = self.level_dat.read().unwrap().clone();
+        self.write_level_dat(level_dat)
+    }
+
+    /// Writes level.dat at the version of a whole world conversion, leaving the loaded level.dat alone.
+    pub(super) fn write_converted_level_dat(&self, target_version: u32, target_name: &str) -> io::Result<()> {
+        let mut level_dat = self.level_dat.read().unwrap().clone();
+        level_dat.data.version.id = target_version;
+        level_dat.data.version.name = target_name.to_owned();
+        level_dat.data._extra.insert("DataVersion".to_owned(), nbt::Value::Int(target_version as i32));
+        self.write_level_dat(level_dat)
+    }
+
+    /// Writes level.dat, keeping the previous one as level.dat_old like the game does.
+    fn write_level_dat(&self, level_dat: LevelDat) -> io::Result<()> {
+        let version = level_dat.data.version.id;
+        let mut nbt_data = Vec::new();
+        VersionedSerde::serialize(level_dat, version, version, &mut nbt::ser::Encoder::new(&mut nbt_data, None))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let path = self.path.join("level.dat");
+        match fs::copy(&path, self.path.join("level.dat_old")) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        region::write_atomically(&path, &compression::compress(ChunkCompression::Gzip, &nbt_data)?)
+    }
+}
+
 convert::variants! {
+    #[derive(Clone)]
     pub(super) struct LevelDat {
         #[serde(rename = "Data")]
         #[variants]
@@ -285,26 +960,146 @@ convert::variants! {
 }
 
 convert::variants! {
-    pub(super) struct LevelDatData {
+    /// The world properties in level.dat.
+    #[derive(Clone)]
+    pub struct LevelDatData {
         #[serde(rename = "Version")]
         #[variants]
-        pub(super) version: LevelDatVersionInfo,
+        pub version: LevelDatVersionInfo,
+        #[serde(rename = "LevelName")]
+        #[serde(default)]
+        pub level_name: String,
+        #[serde(rename = "SpawnX")]
+        #[serde(default)]
+        pub spawn_x: i32,
+        #[serde(rename = "SpawnY")]
+        #[serde(default)]
+        pub spawn_y: i32,
+        #[serde(rename = "SpawnZ")]
+        #[serde(default)]
+        pub spawn_z: i32,
+        // since 1.16
+        #[serde(rename = "SpawnAngle")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub spawn_angle: Option<f32>,
+        /// In ticks, 24000 per day.
+        #[serde(rename = "DayTime")]
+        #[serde(default)]
+        pub day_time: i64,
+        #[serde(rename = "raining")]
+        #[serde(default)]
+        pub raining: bool,
+        #[serde(rename = "rainTime")]
+        #[serde(default)]
+        pub rain_time: i32,
+        #[serde(rename = "thundering")]
+        #[serde(default)]
+        pub thundering: bool,
+        #[serde(rename = "thunderTime")]
+        #[serde(default)]
+        pub thunder_time: i32,
+        #[serde(rename = "clearWeatherTime")]
+        #[serde(default)]
+        pub clear_weather_time: i32,
+        /// 0 to 3 for peaceful, easy, normal and hard.
+        #[serde(rename = "Difficulty")]
+        #[serde(default)]
+        pub difficulty: i8,
+        #[serde(rename = "DifficultyLocked")]
+        #[serde(default)]
+        pub difficulty_locked: bool,
+        /// 0 to 3 for survival, creative, adventure and spectator.
+        #[serde(rename = "GameType")]
+        #[serde(default)]
+        pub game_type: i32,
+        #[serde(rename = "hardcore")]
+        #[serde(default)]
+        pub hardcore: bool,
+        /// The values are strings even for boolean and integer rules.
+        #[serde(rename = "GameRules")]
+        #[serde(default)]
+        pub game_rules: BTreeMap<String, String>,
+        // the world border is only written once it has been used, the game uses its defaults otherwise
+        #[serde(rename = "BorderCenterX")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub border_center_x: Option<f64>,
+        #[serde(rename = "BorderCenterZ")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub border_center_z: Option<f64>,
+        #[serde(rename = "BorderSize")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub border_size: Option<f64>,
+        #[serde(rename = "BorderSafeZone")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub border_safe_zone: Option<f64>,
+        #[serde(rename = "BorderDamagePerBlock")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub border_damage_per_block: Option<f64>,
+        #[serde(rename = "BorderWarningBlocks")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub border_warning_blocks: Option<f64>,
+        #[serde(rename = "BorderWarningTime")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub border_warning_time: Option<f64>,
+        // only in singleplayer worlds
+        #[serde(rename = "Player")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[variants]
+        pub(super) player: Option<SerializedPlayer>,
     }
 }
 
+impl LevelDatData {
+    pub const DEFAULT_BORDER_SIZE: f64 = 59999968.0;
+}
+
 convert::variants! {
-    pub(super) struct LevelDatVersionInfo {
+    #[derive(Clone)]
+    pub struct LevelDatVersionInfo {
         #[serde(rename = "Id")]
-        id: u32,
+        pub id: u32,
         #[serde(rename = "Name")]
-        pub(super) name: String,
+        pub name: String,
     }
 }
 
 convert::variants! {
-    struct SerializedChunk {
+    pub(super) struct SerializedChunk {
         #[variants]
         sections: Vec<SerializedChunkSection>,
+
+        #[serde(default)]
+        #[variants]
+        block_entities: Vec<SerializedBlockEntity>,
+
+        // only in chunks that haven't finished generating since 1.17
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        #[variants]
+        entities: Vec<SerializedEntity>,
+
+        #[serde(default)]
+        #[variants]
+        block_ticks: Vec<SerializedTick>,
+
+        #[serde(default)]
+        #[variants]
+        fluid_ticks: Vec<SerializedTick>,
+
+        #[serde(rename = "Heightmaps")]
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty", serialize_with = "serialize_heightmaps")]
+        heightmaps: BTreeMap<String, Vec<i64>>,
+
+        #[serde(rename = "Status")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        status: Option<String>,
+
+        #[serde(rename = "InhabitedTime")]
+        #[serde(default)]
+        inhabited_time: i64,
+
+        #[serde(rename = "LastUpdate")]
+        #[serde(default)]
+        last_update: i64,
     }
     #[variants(SerializedChunkSection, SerializedBlockStates, SerializedBiomes)]
     fn up(older: Self::UpInput, prevailing_version: u32) -> Self::UpResult {
@@ -313,16 +1108,19 @@ convert::variants! {
         let sections = fix_17_sections(older.level.sections, prevailing_version)?;
         let sections = sections.into_iter().zip(biomes).map(|(sec, biomes)| {
             let result: convert::Result<SerializedChunkSection> = try {
+                let palette: Vec<Variant_SerializedBlockState_1_18> = sec.palette.map(|p| p.convert_into(prevailing_version)).transpose()?.unwrap_or_else(|| {
+                    vec![Variant_SerializedBlockState_1_18 {
+                        name: CommonFNames.AIR.clone(),
+                        properties: Default::default(),
+                        _extra: Default::default(),
+                    }]
+                });
+                // sections with a single block state have no data since 1.18
+                let data = if palette.len() <= 1 { Vec::new() } else { sec.block_states };
                 SerializedChunkSection {
                     block_states: SerializedBlockStates {
-                        palette: sec.palette.map(|p| p.convert_into(prevailing_version)).transpose()?.unwrap_or_else(|| {
-                            vec![Variant_SerializedBlockState_1_18 {
-                                name: CommonFNames.AIR.clone(),
-                                properties: Default::default(),
-                                _extra: Default::default(),
-                            }]
-                        }),
-                        data: sec.block_states,
+                        palette,
+                        data,
                         _extra: Default::default(),
                     },
                     biomes,
@@ -331,42 +1129,100 @@ convert::variants! {
             };
             result
         }).collect::<Result<_, _>>()?;
+        let mut extra = older._extra;
+        extra.extend(older.level._extra);
+        // the heightmaps are relative to the bottom of the world, which moves down in 1.18, let the game recompute them
+        let shifted = versioned_io::CURRENT_DIMENSION.with(|c| *c.borrow() == CommonFNames.OVERWORLD) && prevailing_version > data_versions::V1_17_1;
+        let heightmaps = if shifted { BTreeMap::new() } else { older.level.heightmaps };
         Ok(
             Self::UpOutput {
                 sections,
-                _extra: older._extra,
+                block_entities: older.level.tile_entities.convert_into(prevailing_version)?,
+                entities: older.level.entities.convert_into(prevailing_version)?,
+                block_ticks: older.level.tile_ticks.convert_into(prevailing_version)?,
+                fluid_ticks: older.level.liquid_ticks.convert_into(prevailing_version)?,
+                heightmaps,
+                status: older.level.status,
+                inhabited_time: older.level.inhabited_time,
+                last_update: older.level.last_update,
+                _extra: extra,
             }
         )
     }
     #[variants(SerializedChunkLevel, SerializedChunkSection17)]
     fn down(newer: Self::DownInput, prevailing_version: u32) -> Self::DownResult {
-        let biomes = biomes_17_down(&newer.sections, prevailing_version)?;
-        let sections = newer.sections.into_iter().map(|sec| {
+        // the overworld is 0 to 255 high before 1.18, so the sections outside of that are lost
+        let is_overworld = versioned_io::CURRENT_DIMENSION.with(|c| *c.borrow() == CommonFNames.OVERWORLD);
+        let section_count = newer.sections.len();
+        let mut shifted = false;
+        let (section_ys, sections): (Vec<_>, Vec<_>) = newer.sections.into_iter().enumerate().filter_map(|(index, sec)| {
+            let y = match sec._extra.get("Y") {
+                Some(nbt::Value::Byte(y)) => *y as i32,
+                _ if section_count == 24 => index as i32 - 4,
+                _ => index as i32,
+            };
+            if is_overworld && !(0..16).contains(&y) {
+                shifted = true;
+                return None;
+            }
+            Some((y, sec))
+        }).unzip();
+        let mut biomes = biomes_17_down(&sections, prevailing_version)?;
+        if shifted {
+            // the game expects the biomes of exactly 16 sections
+            if let Some(&last) = biomes.last() {
+                biomes.resize(1024, last);
+            }
+        }
+        let sections = section_ys.into_iter().zip(sections).map(|(y, sec)| {
+            let mut extra = sec._extra;
+            extra.remove("Y");
+            // sections with a single block state have no data since 1.18, but older versions only load sections that
+            // have both a palette and data, which is never less than 4 bits per block
+            let block_states = if sec.block_states.data.is_empty() {
+                vec![0; 4096 * 4 / 64]
+            } else {
+                sec.block_states.data
+            };
             let result: convert::Result<SerializedChunkSection17> = try {
                 SerializedChunkSection17 {
                     palette: Some(sec.block_states.palette.convert_into(prevailing_version)?),
-                    block_states: sec.block_states.data,
-                    y: 0,
-                    _extra: sec._extra,
+                    block_states,
+                    y,
+                    _extra: extra,
                 }
             };
             result
         }).collect::<Result<_, _>>()?;
+        // everything except the data version lives inside the level tag before 1.18
+        let mut level_extra = newer._extra;
+        let mut extra = BTreeMap::new();
+        if let Some(data_version) = level_extra.remove("DataVersion") {
+            extra.insert("DataVersion".to_owned(), data_version);
+        }
         Ok(
             Self::DownOutput {
                 level: SerializedChunkLevel {
                     sections,
                     biomes,
-                    _extra: Default::default(),
+                    tile_entities: newer.block_entities.convert_into(prevailing_version)?,
+                    entities: newer.entities.convert_into(prevailing_version)?,
+                    tile_ticks: newer.block_ticks.convert_into(prevailing_version)?,
+                    liquid_ticks: newer.fluid_ticks.convert_into(prevailing_version)?,
+                    heightmaps: if shifted { BTreeMap::new() } else { newer.heightmaps },
+                    status: newer.status,
+                    inhabited_time: newer.inhabited_time,
+                    last_update: newer.last_update,
+                    _extra: level_extra,
                 },
-                _extra: newer._extra,
+                _extra: extra,
             }
         )
     }
     1,17,1 => {
         #[serde(rename = "Level")]
         #[variants]
-        level: SerializedChunkLevel,
+        pub(super) level: SerializedChunkLevel,
     }
 }
 
@@ -384,6 +1240,7 @@ convert::variants! {
         #[variants]
         palette: Vec<SerializedBlockState>,
         #[serde(default)]
+        #[serde(skip_serializing_if = "Vec::is_empty", serialize_with = "nbt::i64_array")]
         data: Vec<i64>,
     }
 }
@@ -395,16 +1252,254 @@ convert::variants! {
         name: FName,
         #[serde(default)]
         #[serde(rename = "Properties")]
+        #[serde(serialize_with = "serialize_block_properties")]
         properties: AHashMap<FName, nbt::Value>,
     }
 }
 
+impl SerializedBlockState {
+    pub(super) fn from_block_state(state: &BlockState) -> Self {
+        SerializedBlockState {
+            name: state.block.clone(),
+            properties: block_properties_to_nbt(state),
+            _extra: Default::default(),
+        }
+    }
+
+    pub(super) fn to_block_state(&self) -> IBlockState {
+        block_state_from_nbt(&self.name, &self.properties)
+    }
+}
+
+pub(super) fn block_properties_to_nbt(state: &BlockState) -> AHashMap<FName, nbt::Value> {
+    state.properties.iter().map(|(k, v)| (k.clone(), nbt::Value::String(v.to_nice_string()))).collect()
+}
+
+pub(super) fn block_state_from_nbt(name: &FName, properties: &AHashMap<FName, nbt::Value>) -> IBlockState {
+    let mut state = BlockState::new(name);
+    for (k, v) in properties {
+        state.properties.insert(k.clone(), FName::new(v.to_string().parse().unwrap()));
+    }
+    IBlockState::new(state)
+}
+
 convert::variants! {
     #[derive(Clone)]
     pub(super) struct SerializedBiomes {
         #[registry(biome)]
         pub(super) palette: Vec<FName>,
         #[serde(default)]
+        #[serde(skip_serializing_if = "Vec::is_empty", serialize_with = "nbt::i64_array")]
         pub(super) data: Vec<i64>,
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fname;
+
+    fn block(name: &str) -> IBlockState {
+        IBlockState::new(BlockState::new(&fname::from_str(name)))
+    }
+
+    fn make_section(y: i8, state_at: impl Fn(usize) -> IBlockState) -> SerializedChunkSection {
+        let mut block_data = BlockData::direct_init(vec![block("air")], Vec::new());
+        for index in 0..4096 {
+            block_data.set(index & 15, index >> 8, (index >> 4) & 15, &state_at(index));
+        }
+        let (palette, data) = block_data.to_serialized();
+        let mut extra = BTreeMap::new();
+        extra.insert("Y".to_owned(), nbt::Value::Byte(y));
+        SerializedChunkSection {
+            block_states: SerializedBlockStates {
+                palette: palette.iter().map(|state| SerializedBlockState::from_block_state(state)).collect(),
+                data: data.into_iter().map(|i| i as i64).collect(),
+                _extra: Default::default(),
+            },
+            biomes: SerializedBiomes {
+                palette: vec![CommonFNames.PLAINS.clone()],
+                data: Vec::new(),
+                _extra: Default::default(),
+            },
+            _extra: extra,
+        }
+    }
+
+    fn make_chunk(sections: Vec<SerializedChunkSection>, version: u32) -> SerializedChunk {
+        let mut extra = BTreeMap::new();
+        extra.insert("DataVersion".to_owned(), nbt::Value::Int(version as i32));
+        SerializedChunk {
+            sections,
+            block_entities: Vec::new(),
+            entities: Vec::new(),
+            block_ticks: Vec::new(),
+            fluid_ticks: Vec::new(),
+            heightmaps: BTreeMap::new(),
+            status: Some("minecraft:full".to_owned()),
+            inhabited_time: 0,
+            last_update: 0,
+            _extra: extra,
+        }
+    }
+
+    /// The states of a section in the order of their indices, y then z then x.
+    fn section_states(section: &SerializedChunkSection) -> Vec<IBlockState> {
+        let palette = section.block_states.palette.iter().map(SerializedBlockState::to_block_state).collect();
+        let block_data = BlockData::direct_init(palette, section.block_states.data.iter().map(|i| *i as u64).collect());
+        (0..4096).map(|index| block_data.get(index & 15, index >> 8, (index >> 4) & 15).clone()).collect()
+    }
+
+    #[test]
+    fn uniform_section_round_trips_through_1_17() {
+        let chunk = make_chunk(vec![make_section(0, |_| block("stone"))], data_versions::V1_17_1);
+        assert!(chunk.sections[0].block_states.data.is_empty());
+
+        let mut nbt_data = Vec::new();
+        VersionedSerde::serialize(chunk, data_versions::V1_17_1, data_versions::V1_17_1, &mut nbt::ser::Encoder::new(&mut nbt_data, None)).unwrap();
+        let old_chunk = Variant_SerializedChunk_1_17_1::deserialize(&mut nbt::de::Decoder::new(&nbt_data[..])).unwrap();
+        let old_section = old_chunk.level.sections.iter().find(|section| section.y == 0).unwrap();
+        assert_eq!(old_section.palette.as_ref().map(Vec::len), Some(1));
+        assert_eq!(old_section.block_states, vec![0; 256]);
+
+        let chunk: SerializedChunk = VersionedSerde::deserialize(data_versions::V1_17_1, data_versions::V1_17_1, &mut nbt::de::Decoder::new(&nbt_data[..])).unwrap();
+        let section = &chunk.sections[0];
+        assert!(section.block_states.data.is_empty());
+        assert!(section_states(section).iter().all(|state| *state == block("stone")));
+    }
+
+    #[test]
+    fn mixed_section_round_trips_through_1_17() {
+        let state_at = |index: usize| block(&format!("test_block_{}", index % 20));
+        let chunk = make_chunk(vec![make_section(1, state_at)], data_versions::V1_17_1);
+
+        let mut nbt_data = Vec::new();
+        VersionedSerde::serialize(chunk, data_versions::V1_17_1, data_versions::V1_17_1, &mut nbt::ser::Encoder::new(&mut nbt_data, None)).unwrap();
+        let chunk: SerializedChunk = VersionedSerde::deserialize(data_versions::V1_17_1, data_versions::V1_17_1, &mut nbt::de::Decoder::new(&nbt_data[..])).unwrap();
+        let states = section_states(&chunk.sections[1]);
+        assert!(states.iter().enumerate().all(|(index, state)| *state == state_at(index)));
+    }
+
+    #[test]
+    fn mixed_section_round_trips_through_1_15_with_spanning_block_states() {
+        let state_at = |index: usize| block(&format!("test_block_{}", index % 20));
+        let chunk = make_chunk(vec![make_section(2, state_at)], data_versions::V1_15_2);
+
+        let mut nbt_data = Vec::new();
+        VersionedSerde::serialize(chunk, data_versions::V1_15_2, data_versions::V1_15_2, &mut nbt::ser::Encoder::new(&mut nbt_data, None)).unwrap();
+        let old_chunk = Variant_SerializedChunk_1_15_2::deserialize(&mut nbt::de::Decoder::new(&nbt_data[..])).unwrap();
+        let old_section = old_chunk.level.sections.iter().find(|section| section.y == 2).unwrap();
+        // 5 bits for each of 4096 blocks, without unused bits at the end of each long
+        assert_eq!(old_section.block_states.len(), 320);
+
+        let chunk: SerializedChunk = VersionedSerde::deserialize(data_versions::V1_15_2, data_versions::V1_15_2, &mut nbt::de::Decoder::new(&nbt_data[..])).unwrap();
+        let states = section_states(&chunk.sections[2]);
+        assert!(states.iter().enumerate().all(|(index, state)| *state == state_at(index)));
+    }
+
+    fn convert_chunk(nbt_data: &[u8], target_version: u32) -> Vec<u8> {
+        let dimension = Dimension::new(CommonFNames.OVERWORLD.clone());
+        let (nbt_data, entities_nbt) = dimension.convert_chunk_nbt(RegionKind::Terrain, ChunkPos::new(0, 0), nbt_data, target_version, None).unwrap();
+        assert!(entities_nbt.is_none());
+        nbt_data
+    }
+
+    #[test]
+    fn converted_chunk_keeps_uniform_and_mixed_sections() {
+        let state_at = |index: usize| block(&format!("test_block_{}", index % 20));
+        let chunk = make_chunk(vec![make_section(0, |_| block("stone")), make_section(1, state_at)], data_versions::V1_18);
+        let mut nbt_data = Vec::new();
+        VersionedSerde::serialize(chunk, data_versions::V1_18, data_versions::V1_18, &mut nbt::ser::Encoder::new(&mut nbt_data, None)).unwrap();
+
+        let nbt_data = convert_chunk(&nbt_data, data_versions::V1_17_1);
+        let old_chunk = Variant_SerializedChunk_1_17_1::deserialize(&mut nbt::de::Decoder::new(&nbt_data[..])).unwrap();
+        let uniform_section = old_chunk.level.sections.iter().find(|section| section.y == 0).unwrap();
+        assert_eq!(uniform_section.palette.as_ref().map(Vec::len), Some(1));
+        assert_eq!(uniform_section.block_states, vec![0; 256]);
+        let mixed_section = old_chunk.level.sections.iter().find(|section| section.y == 1).unwrap();
+        assert_eq!(mixed_section.palette.as_ref().map(Vec::len), Some(20));
+        assert_eq!(mixed_section.block_states.len(), 342);
+
+        let nbt_data = convert_chunk(&nbt_data, data_versions::V1_15_2);
+        let old_chunk = Variant_SerializedChunk_1_15_2::deserialize(&mut nbt::de::Decoder::new(&nbt_data[..])).unwrap();
+        let uniform_section = old_chunk.level.sections.iter().find(|section| section.y == 0).unwrap();
+        assert_eq!(uniform_section.block_states, vec![0; 256]);
+        let mixed_section = old_chunk.level.sections.iter().find(|section| section.y == 1).unwrap();
+        assert_eq!(mixed_section.block_states.len(), 320);
+
+        let nbt_data = convert_chunk(&nbt_data, data_versions::V1_18);
+        let chunk: SerializedChunk = VersionedSerde::deserialize(data_versions::V1_18, data_versions::V1_18, &mut nbt::de::Decoder::new(&nbt_data[..])).unwrap();
+        // the four sections below y=0 are added in 1.18
+        assert!(chunk.sections[4].block_states.data.is_empty());
+        assert!(section_states(&chunk.sections[4]).iter().all(|state| *state == block("stone")));
+        let states = section_states(&chunk.sections[5]);
+        assert!(states.iter().enumerate().all(|(index, state)| *state == state_at(index)));
+    }
+
+    fn compound(entries: Vec<(&str, nbt::Value)>) -> nbt::Value {
+        nbt::Value::Compound(entries.into_iter().map(|(k, v)| (k.to_owned(), v)).collect())
+    }
+
+    fn item_id(item: &nbt::Value) -> &str {
+        match item {
+            nbt::Value::Compound(item) => match item.get("id") {
+                Some(nbt::Value::String(id)) => id,
+                _ => panic!("item without an id"),
+            },
+            _ => panic!("item isn't a compound"),
+        }
+    }
+
+    #[test]
+    fn rename_chunk_renames_nested_items() {
+        let grass = compound(vec![("id", nbt::Value::String("minecraft:grass".to_owned())), ("Count", nbt::Value::Byte(1))]);
+        let shulker_box = compound(vec![
+            ("id", nbt::Value::String("minecraft:shulker_box".to_owned())),
+            ("Count", nbt::Value::Byte(1)),
+            ("tag", compound(vec![("BlockEntityTag", compound(vec![("Items", nbt::Value::List(vec![grass]))]))])),
+        ]);
+        let mut extra = BTreeMap::new();
+        extra.insert("Items".to_owned(), nbt::Value::List(vec![shulker_box]));
+        let mut chunk = make_chunk(vec![make_section(0, |_| block("grass"))], data_versions::V1_20_2);
+        chunk.block_entities.push(SerializedBlockEntity {
+            id: fname::from_str("chest"),
+            x: 0,
+            y: 0,
+            z: 0,
+            keep_packed: false,
+            _extra: extra,
+        });
+
+        rename_chunk(&mut chunk, data_versions::V1_20_2, data_versions::V1_20_3);
+        let palette = &chunk.sections[0].block_states.palette;
+        assert!(palette.iter().any(|state| state.to_block_state() == block("short_grass")));
+        let shulker_box = match &chunk.block_entities[0]._extra["Items"] {
+            nbt::Value::List(items) => &items[0],
+            _ => panic!("Items isn't a list"),
+        };
+        assert_eq!(item_id(shulker_box), "minecraft:shulker_box");
+        let grass = match shulker_box {
+            nbt::Value::Compound(item) => match &item["tag"] {
+                nbt::Value::Compound(tag) => match &tag["BlockEntityTag"] {
+                    nbt::Value::Compound(block_entity_tag) => match &block_entity_tag["Items"] {
+                        nbt::Value::List(items) => &items[0],
+                        _ => panic!("Items isn't a list"),
+                    },
+                    _ => panic!("BlockEntityTag isn't a compound"),
+                },
+                _ => panic!("tag isn't a compound"),
+            },
+            _ => unreachable!(),
+        };
+        assert_eq!(item_id(grass), "minecraft:short_grass");
+    }
+
+    #[test]
+    fn rename_chunk_keeps_flattened_states() {
+        // block states from before the flattening are read with the names of the target version already
+        let mut chunk = make_chunk(vec![make_section(0, |_| block("grass"))], data_versions::V1_12_2);
+        rename_chunk(&mut chunk, data_versions::V1_12_2, data_versions::V1_20_3);
+        let palette = &chunk.sections[0].block_states.palette;
+        assert!(palette.iter().any(|state| state.to_block_state() == block("grass")));
+        assert!(!palette.iter().any(|state| state.to_block_state() == block("short_grass")));
+    }
+}
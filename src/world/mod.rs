@@ -1,10 +1,26 @@
 use std::sync::RwLock;
 use lazy_static::lazy_static;
+pub use compression::ChunkCompression;
+pub use history::{EditHistory, EditTransaction};
+pub use io::{LevelDatData, LevelDatVersionInfo};
+pub use player::{ItemStack, PlayerAbilities, PlayerData, PlayerId};
+pub use poi::{PoiRecord, PoiSection};
+pub use selection::Selection;
 pub use structs::*;
 use workers::WorldRef;
 
+pub mod clipboard;
+mod compression;
+mod history;
 mod io;
+pub mod operations;
 mod palette;
+mod player;
+mod poi;
+mod region;
+pub mod region_tools;
+pub mod schematic;
+mod selection;
 mod structs;
 mod versioned_io;
 #[allow(clippy::mutex_atomic)]
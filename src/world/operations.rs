@@ -0,0 +1,164 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use glam::{IVec3, Vec3Swizzles};
+use log::warn;
+use rayon::prelude::*;
+use crate::{CommonFNames, fname};
+use crate::fname::FName;
+use crate::geom::{BlockPos, ChunkPos, IVec2RangeExtensions, IVec3RangeExtensions};
+use crate::world::{BlockState, BlockStateParseError, EditTransaction, IBlockState, parse_block_name, parse_block_state_parts, Selection, World};
+use crate::world::workers::WorldRef;
+
+/// Matches block states by name and a subset of their properties, e.g. `oak_log[axis=y]` or `*[waterlogged=true]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockPredicate {
+    block: Option<FName>,
+    properties: Vec<(FName, FName)>,
+}
+
+impl BlockPredicate {
+    pub fn matches(&self, state: &BlockState) -> bool {
+        self.block.as_ref().map(|block| *block == state.block).unwrap_or(true)
+            && self.properties.iter().all(|(key, value)| state.properties.get(key) == Some(value))
+    }
+}
+
+impl FromStr for BlockPredicate {
+    type Err = BlockStateParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (block, properties) = parse_block_state_parts(s)?;
+        let mut result = BlockPredicate {
+            block: if block == "*" { None } else { Some(parse_block_name(block)?) },
+            properties: Vec::with_capacity(properties.len()),
+        };
+        for (key, value) in properties {
+            let name = fname::from_str(key);
+            if result.properties.iter().any(|(existing_name, _)| *existing_name == name) {
+                return Err(BlockStateParseError::DuplicateProperty(key.to_string()));
+            }
+            result.properties.push((name, fname::from_str(value)));
+        }
+        Ok(result)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Operation {
+    Fill(IBlockState),
+    /// Replaces the blocks with the given name and properties, whatever their other properties are.
+    Replace { from: BlockPredicate, to: IBlockState },
+    /// The four vertical sides of the selection.
+    Walls(IBlockState),
+    /// All six sides of the selection.
+    Outline(IBlockState),
+    /// Replaces everything inside the sides of the selection with air.
+    Hollow,
+}
+
+impl Operation {
+    fn get_new_state(&self, min: BlockPos, max: BlockPos, pos: BlockPos, state: &IBlockState) -> Option<IBlockState> {
+        let on_side = |axis: usize| pos[axis] == min[axis] || pos[axis] == max[axis];
+        match self {
+            Operation::Fill(new_state) => Some(new_state.clone()),
+            Operation::Replace { from, to } => from.matches(state).then(|| to.clone()),
+            Operation::Walls(new_state) => (on_side(0) || on_side(2)).then(|| new_state.clone()),
+            Operation::Outline(new_state) => (on_side(0) || on_side(1) || on_side(2)).then(|| new_state.clone()),
+            Operation::Hollow => (!on_side(0) && !on_side(1) && !on_side(2)).then(|| IBlockState::new(BlockState::new(&CommonFNames.AIR))),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct OperationProgress {
+    total_chunks: AtomicUsize,
+    done_chunks: AtomicUsize,
+    changed_blocks: AtomicU64,
+    cancelled: AtomicBool,
+    finished: AtomicBool,
+}
+
+impl OperationProgress {
+    pub fn fraction(&self) -> f32 {
+        let total_chunks = self.total_chunks.load(Ordering::Relaxed);
+        if total_chunks == 0 {
+            0.0
+        } else {
+            self.done_chunks.load(Ordering::Relaxed) as f32 / total_chunks as f32
+        }
+    }
+
+    pub fn changed_blocks(&self) -> u64 {
+        self.changed_blocks.load(Ordering::Relaxed)
+    }
+
+    /// Stops processing further chunks. Chunks that were already changed stay changed, and can be undone as usual.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Acquire)
+    }
+}
+
+/// Runs the operation over the selection on the world's worker pool, one chunk per task. The changes are recorded as a
+/// single entry in the world's undo history.
+pub fn run_operation(world: &WorldRef, selection: Selection, operation: Operation) -> Arc<OperationProgress> {
+    let progress = Arc::new(OperationProgress::default());
+    let worker_progress = progress.clone();
+    world.spawn_worker(move |world, stop| {
+        let progress = worker_progress;
+        if let Some(dimension) = world.get_dimension(&selection.dimension) {
+            let min = selection.min().max(IVec3::new(i32::MIN, dimension.min_y, i32::MIN));
+            let max = selection.max().min(IVec3::new(i32::MAX, dimension.max_y, i32::MAX));
+            if min.y <= max.y && !stop() {
+                let chunks: Vec<ChunkPos> = ((min.xz() >> 4i8)..=(max.xz() >> 4i8)).iter().collect();
+                progress.total_chunks.store(chunks.len(), Ordering::Relaxed);
+                let transaction = EditTransaction::new(dimension);
+                chunks.into_par_iter().for_each(|chunk_pos| {
+                    if !progress.cancelled.load(Ordering::Relaxed) {
+                        let changed_blocks = apply_to_chunk(&world, &transaction, &operation, &selection, min, max, chunk_pos);
+                        progress.changed_blocks.fetch_add(changed_blocks, Ordering::Relaxed);
+                    }
+                    progress.done_chunks.fetch_add(1, Ordering::Relaxed);
+                });
+                world.history.lock().unwrap().push(transaction);
+            }
+        }
+        progress.finished.store(true, Ordering::Release);
+    });
+    progress
+}
+
+// min and max are the selection clamped to the height of the dimension
+pub(super) fn apply_to_chunk(world: &World, transaction: &EditTransaction, operation: &Operation, selection: &Selection, min: BlockPos, max: BlockPos, chunk_pos: ChunkPos) -> u64 {
+    let dimension = transaction.dimension();
+    let chunk = match dimension.load_chunk(world, chunk_pos) {
+        Some(chunk) => chunk,
+        // there is nothing to edit in chunks that haven't been generated
+        None => return 0,
+    };
+    let chunk_origin = BlockPos::new(chunk_pos.x << 4, 0, chunk_pos.y << 4);
+    let chunk_min = min.max(BlockPos::new(chunk_origin.x, min.y, chunk_origin.z));
+    let chunk_max = max.min(BlockPos::new(chunk_origin.x + 15, max.y, chunk_origin.z + 15));
+    let air = IBlockState::new(BlockState::new(&CommonFNames.AIR));
+    let mut changed_blocks = 0;
+    for pos in (chunk_min..=chunk_max).iter() {
+        let state = chunk.get_block_state(dimension, pos - chunk_origin).unwrap_or_else(|| air.clone());
+        let new_state = match operation.get_new_state(selection.min(), selection.max(), pos, &state) {
+            Some(new_state) if new_state != state => new_state,
+            _ => continue,
+        };
+        if transaction.set_block_state(pos, &new_state).is_none() {
+            // the chunk was unloaded before our first change marked it as dirty
+            if dimension.load_chunk(world, chunk_pos).is_none() || transaction.set_block_state(pos, &new_state).is_none() {
+                warn!("Chunk {} was unloaded while it was being edited", chunk_pos);
+                break;
+            }
+        }
+        changed_blocks += 1;
+    }
+    changed_blocks
+}
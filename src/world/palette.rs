@@ -73,45 +73,164 @@ macro_rules! define_paletted_data {
                         self.palette.len() - 1
                     }
                 };
+                if self.data.is_empty() {
+                    // the value is already the single value in the palette
+                    return;
+                }
                 let index = y << ($h_bits + $h_bits) | z << $h_bits | x;
                 let (bit, inbit) = index.div_mod_floor(&(self.entries_per_long as usize));
                 self.data[bit] &= !(((1 << self.bits_per_block) - 1) << (inbit * self.bits_per_block as usize));
                 self.data[bit] |= (val as u64) << (inbit * self.bits_per_block as usize);
             }
 
+            /// Returns a compacted palette and packed data suitable for writing to disk, dropping any
+            /// palette entries that are no longer referenced.
+            pub(super) fn to_serialized(&self) -> (Vec<$type>, Vec<u64>) {
+                const SIZE: usize = (1_usize << $h_bits) * (1_usize << $h_bits) * (1_usize << $v_bits);
+                let mut palette = Vec::new();
+                let mut inv_palette = AHashMap::new();
+                let mut indices = Vec::with_capacity(SIZE);
+                for y in 0..1_usize << $v_bits {
+                    for z in 0..1_usize << $h_bits {
+                        for x in 0..1_usize << $h_bits {
+                            let value = self.get(x, y, z);
+                            let index = *inv_palette.entry(value.clone()).or_insert_with(|| {
+                                palette.push(value.clone());
+                                palette.len() - 1
+                            });
+                            indices.push(index as u64);
+                        }
+                    }
+                }
+                if palette.len() <= 1 {
+                    return (palette, Vec::new());
+                }
+                let bits_per_block = (((palette.len() - 1).ilog2() + 1) as usize).max($default_palette_size.ilog2() as usize);
+                let entries_per_long = 64 / bits_per_block;
+                let mut data = vec![0_u64; SIZE.div_ceil(entries_per_long)];
+                for (i, index) in indices.into_iter().enumerate() {
+                    let (word, inword) = i.div_mod_floor(&entries_per_long);
+                    data[word] |= index << (inword * bits_per_block);
+                }
+                (palette, data)
+            }
+
                         fn resize(&mut self) {
+                const SIZE: usize = (1_usize << $h_bits) * (1_usize << $h_bits) * (1_usize << $v_bits);
                 if self.data.is_empty() {
                     self.bits_per_block = $default_palette_size.ilog2() as u8;
                     self.entries_per_long = 64_u8 / self.bits_per_block;
-                    self.data = vec![0; ((1_usize << $h_bits) * (1_usize << $h_bits) * (1_usize << $v_bits)).div_ceil(self.entries_per_long as usize)];
+                    self.data = vec![0; SIZE.div_ceil(self.entries_per_long as usize)];
                     return;
                 }
-                let old_data_size = ((1 << $h_bits) * (1 << $h_bits) * (1 << $v_bits)).div_ceil(&(self.entries_per_long as usize));
-                let old_bits_per_block = self.bits_per_block;
-                let old_entries_per_long = self.entries_per_long;
+                let old_bits_per_block = self.bits_per_block as usize;
+                let old_entries_per_long = self.entries_per_long as usize;
                 self.palette.reserve(self.palette.len());
                 self.bits_per_block += 1;
                 self.entries_per_long = 64_u8.div_floor(self.bits_per_block);
-                let old_data = mem::replace(&mut self.data, Vec::with_capacity(((1 << $h_bits) * (1 << $h_bits) * (1 << $v_bits)).div_ceil(&(self.entries_per_long as usize))));
-                let mut block = 0;
-                for index in 0..old_data_size - 1 {
-                    let word = old_data[index];
-                    for i in 0..old_entries_per_long {
-                        let entry = (word >> (i * old_bits_per_block)) & ((1 << old_bits_per_block) - 1);
-                        block = (block << self.bits_per_block) | entry;
-                        if (index * old_entries_per_long as usize + i as usize + 1) % self.entries_per_long as usize == 0 {
-                            self.data.push(block);
-                            block = 0;
-                        }
-                    }
-                }
-                if ((1_u64 << $h_bits) * (1_u64 << $h_bits) * (1_u64 << $v_bits)) % self.entries_per_long as u64 != 0 {
-                    self.data.push(block);
+                let bits_per_block = self.bits_per_block as usize;
+                let entries_per_long = self.entries_per_long as usize;
+                let old_data = mem::replace(&mut self.data, vec![0; SIZE.div_ceil(entries_per_long)]);
+                for index in 0..SIZE {
+                    let (word, inword) = index.div_mod_floor(&old_entries_per_long);
+                    let entry = (old_data[word] >> (inword * old_bits_per_block)) & ((1 << old_bits_per_block) - 1);
+                    let (word, inword) = index.div_mod_floor(&entries_per_long);
+                    self.data[word] |= entry << (inword * bits_per_block);
                 }
             }
+
+            /// Whether any palette entry matches, including entries that may no longer be used.
+            pub(super) fn palette_contains(&self, f: impl FnMut(&$type) -> bool) -> bool {
+                self.palette.iter().any(f)
+            }
+
+            /// A rough estimate of the heap memory used by this data, for memory limits.
+            pub(super) fn estimated_size(&self) -> usize {
+                // the inverse palette roughly doubles the palette size
+                self.palette.capacity() * mem::size_of::<$type>() * 2 + self.data.capacity() * mem::size_of::<u64>()
+            }
         }
     };
 }
 
 define_paletted_data!(BlockData, IBlockState, 4_usize, 4_usize, 16_usize);
-define_paletted_data!(BiomeData, FName, 2_usize, 2_usize, 2_usize);
\ No newline at end of file
+define_paletted_data!(BiomeData, FName, 2_usize, 2_usize, 2_usize);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fname;
+    use crate::world::BlockState;
+
+    fn block(name: &str) -> IBlockState {
+        IBlockState::new(BlockState::new(&fname::from_str(name)))
+    }
+
+    #[test]
+    fn uniform_data_serializes_without_data() {
+        let mut block_data = BlockData::direct_init(vec![block("air")], Vec::new());
+        for index in 0..4096 {
+            block_data.set(index & 15, index >> 8, (index >> 4) & 15, &block("stone"));
+        }
+        let (palette, data) = block_data.to_serialized();
+        assert_eq!(palette, vec![block("stone")]);
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn to_serialized_drops_unused_entries() {
+        let mut block_data = BlockData::direct_init(vec![block("air")], Vec::new());
+        for i in 0..20 {
+            block_data.set(i % 16, i / 16, 0, &block(&format!("test_block_{}", i)));
+        }
+        // overwrite all but two of the test blocks
+        for i in 2..20 {
+            block_data.set(i % 16, i / 16, 0, &block("air"));
+        }
+        let (palette, data) = block_data.to_serialized();
+        assert_eq!(palette, vec![block("test_block_0"), block("test_block_1"), block("air")]);
+        // at least 4 bits per block, like the game
+        assert_eq!(data.len(), 4096 / 16);
+
+        let block_data = BlockData::direct_init(palette, data);
+        assert_eq!(*block_data.get(0, 0, 0), block("test_block_0"));
+        assert_eq!(*block_data.get(1, 0, 0), block("test_block_1"));
+        assert_eq!(*block_data.get(2, 0, 0), block("air"));
+        assert_eq!(*block_data.get(15, 15, 15), block("air"));
+    }
+
+    #[test]
+    fn to_serialized_round_trips_large_palettes() {
+        let state_at = |index: usize| block(&format!("test_block_{}", index % 40));
+        let mut block_data = BlockData::direct_init(vec![block("air")], Vec::new());
+        for index in 0..4096 {
+            block_data.set(index & 15, index >> 8, (index >> 4) & 15, &state_at(index));
+        }
+        let (palette, data) = block_data.to_serialized();
+        assert_eq!(palette.len(), 40);
+        // 6 bits per block, 10 entries per long
+        assert_eq!(data.len(), 4096_usize.div_ceil(10));
+
+        let block_data = BlockData::direct_init(palette, data);
+        for index in 0..4096 {
+            assert_eq!(*block_data.get(index & 15, index >> 8, (index >> 4) & 15), state_at(index));
+        }
+    }
+
+    #[test]
+    fn biome_data_round_trips() {
+        let biome_at = |index: usize| fname::from_str(if index % 3 == 0 { "plains" } else { "forest" });
+        let mut biome_data = BiomeData::direct_init(vec![fname::from_str("plains")], Vec::new());
+        for index in 0..64 {
+            biome_data.set(index & 3, index >> 4, (index >> 2) & 3, &biome_at(index));
+        }
+        let (palette, data) = biome_data.to_serialized();
+        assert_eq!(palette.len(), 2);
+        // 1 bit per biome
+        assert_eq!(data.len(), 1);
+
+        let biome_data = BiomeData::direct_init(palette, data);
+        for index in 0..64 {
+            assert_eq!(*biome_data.get(index & 3, index >> 4, (index >> 2) & 3), biome_at(index));
+        }
+    }
+}
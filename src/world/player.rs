@@ -0,0 +1,463 @@
+use std::collections::BTreeMap;
+use std::{fmt, fs, io};
+use std::path::PathBuf;
+use flate2::read;
+use glam::{DVec3, Vec2};
+use crate::{CommonFNames, convert, World};
+use crate::convert::{ConvertInto, item_components, registries, VersionedSerde};
+use crate::fname::FName;
+use crate::world::Camera;
+use crate::world::compression;
+use crate::world::compression::ChunkCompression;
+use crate::world::region;
+
+/// The height of the eyes of a standing player above their feet.
+const PLAYER_EYE_HEIGHT: f64 = 1.62;
+
+/// Identifies the data of a player.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum PlayerId {
+    /// The player of a singleplayer world, whose data is in level.dat rather than in `playerdata`.
+    LevelDat,
+    Uuid(u128),
+}
+
+impl fmt::Display for PlayerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlayerId::LevelDat => write!(f, "Singleplayer"),
+            PlayerId::Uuid(uuid) => write!(f, "{}", format_uuid(*uuid)),
+        }
+    }
+}
+
+fn format_uuid(uuid: u128) -> String {
+    let hex = format!("{:032x}", uuid);
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+fn parse_uuid(s: &str) -> Option<u128> {
+    let hex = s.replace('-', "");
+    if hex.len() != 32 {
+        return None;
+    }
+    u128::from_str_radix(&hex, 16).ok()
+}
+
+#[derive(Debug, Clone)]
+pub struct ItemStack {
+    pub id: FName,
+    /// At most 127 before 1.20.5.
+    pub count: i32,
+    /// The rest of the NBT, e.g. the damage and enchantments.
+    pub data: BTreeMap<String, nbt::Value>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PlayerAbilities {
+    pub may_fly: bool,
+    pub flying: bool,
+    pub invulnerable: bool,
+    /// Whether blocks break instantly, as in creative mode.
+    pub instabuild: bool,
+    pub may_build: bool,
+    pub fly_speed: f32,
+    pub walk_speed: f32,
+    extra: BTreeMap<String, nbt::Value>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PlayerData {
+    pub pos: DVec3,
+    /// The yaw and pitch in degrees.
+    pub rotation: Vec2,
+    pub dimension: FName,
+    /// Keyed by slot, 0 to 8 being the hotbar, 9 to 35 the main inventory, 100 to 103 the armor and -106 the offhand.
+    pub inventory: BTreeMap<i8, ItemStack>,
+    pub ender_items: BTreeMap<i8, ItemStack>,
+    pub xp_level: i32,
+    /// The progress towards the next level, from 0 to 1.
+    pub xp_progress: f32,
+    pub xp_total: i32,
+    pub abilities: PlayerAbilities,
+    extra: BTreeMap<String, nbt::Value>,
+}
+
+impl PlayerData {
+    /// Moves the player so that their eyes are at the camera, looking the same way.
+    pub fn move_to_camera(&mut self, camera: &Camera) {
+        self.pos = camera.pos - DVec3::new(0.0, PLAYER_EYE_HEIGHT, 0.0);
+        self.dimension = camera.dimension.clone();
+        // the camera faces north at yaw 0 and up at positive pitch, the game faces south and down
+        self.rotation = Vec2::new((180.0 - camera.yaw).rem_euclid(360.0), -camera.pitch);
+    }
+}
+
+impl Camera {
+    /// Moves the camera to the eyes of the player, looking the same way.
+    pub fn teleport_to_player(&mut self, player: &PlayerData) {
+        self.pos = player.pos + DVec3::new(0.0, PLAYER_EYE_HEIGHT, 0.0);
+        self.dimension = player.dimension.clone();
+        self.yaw = (180.0 - player.rotation.x).rem_euclid(360.0);
+        self.pitch = (-player.rotation.y).clamp(-90.0, 90.0);
+    }
+}
+
+impl World {
+    fn get_player_path(&self, uuid: u128) -> PathBuf {
+        self.path.join("playerdata").join(format!("{}.dat", format_uuid(uuid)))
+    }
+
+    /// The players with data in this world, the singleplayer player first.
+    pub fn list_players(&self) -> io::Result<Vec<PlayerId>> {
+        let mut players = Vec::new();
+        if self.level_dat.read().unwrap().data.player.is_some() {
+            players.push(PlayerId::LevelDat);
+        }
+        let entries = match fs::read_dir(self.path.join("playerdata")) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(players),
+            Err(e) => return Err(e),
+        };
+        let mut uuids = Vec::new();
+        for entry in entries {
+            let name = entry?.file_name();
+            if let Some(uuid) = name.to_string_lossy().strip_suffix(".dat").and_then(parse_uuid) {
+                uuids.push(uuid);
+            }
+        }
+        uuids.sort_unstable();
+        players.extend(uuids.into_iter().map(PlayerId::Uuid));
+        Ok(players)
+    }
+
+    /// The data of the player, including unsaved edits.
+    pub fn get_player(&self, id: PlayerId) -> io::Result<PlayerData> {
+        let uuid = match id {
+            PlayerId::LevelDat => {
+                let level_dat = self.level_dat.read().unwrap();
+                return match &level_dat.data.player {
+                    Some(player) => Ok(player.clone().into_player_data()),
+                    None => Err(io::Error::new(io::ErrorKind::NotFound, "level.dat has no player")),
+                };
+            }
+            PlayerId::Uuid(uuid) => uuid,
+        };
+        if let Some(player) = self.edited_players.get(&uuid) {
+            return Ok(player.clone());
+        }
+        let data = fs::read(self.get_player_path(uuid))?;
+        let make_deserializer = || nbt::de::Decoder::new(read::GzDecoder::new(&data[..]));
+        let version = convert::get_version(&mut make_deserializer())?;
+        let mut player: SerializedPlayer = VersionedSerde::deserialize(version, self.data_version(), &mut make_deserializer())?;
+        player.rename_items(version, self.data_version());
+        Ok(player.into_player_data())
+    }
+
+    /// Edits the data of the player, which gets written when the world is saved.
+    pub fn edit_player<R>(&self, id: PlayerId, f: impl FnOnce(&mut PlayerData) -> R) -> io::Result<R> {
+        let mut player = self.get_player(id)?;
+        let result = f(&mut player);
+        match id {
+            PlayerId::LevelDat => self.edit_level_dat(|level_dat| level_dat.player = Some(SerializedPlayer::from_player_data(player))),
+            PlayerId::Uuid(uuid) => {
+                self.edited_players.insert(uuid, player);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Writes the edited players to their files in `playerdata`, keeping the previous files as `.dat_old` like the
+    /// game does.
+    pub(super) fn save_players(&self) -> io::Result<()> {
+        let version = self.data_version();
+        let uuids: Vec<u128> = self.edited_players.iter().map(|entry| *entry.key()).collect();
+        for uuid in uuids {
+            let player = match self.edited_players.get(&uuid) {
+                Some(player) => player.clone(),
+                None => continue,
+            };
+            let mut player = SerializedPlayer::from_player_data(player);
+            player._extra.insert("DataVersion".to_owned(), nbt::Value::Int(version as i32));
+            let mut nbt_data = Vec::new();
+            VersionedSerde::serialize(player, version, version, &mut nbt::ser::Encoder::new(&mut nbt_data, None))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let path = self.get_player_path(uuid);
+            match fs::copy(&path, path.with_extension("dat_old")) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            region::write_atomically(&path, &compression::compress(ChunkCompression::Gzip, &nbt_data)?)?;
+            self.edited_players.remove(&uuid);
+        }
+        Ok(())
+    }
+
+    /// Rewrites the files in `playerdata` at another data version, for a whole world conversion. Ignores edited
+    /// players, the world shouldn't have unsaved changes.
+    pub(super) fn convert_players(&self, target_version: u32) -> io::Result<()> {
+        for id in self.list_players()? {
+            let path = match id {
+                PlayerId::LevelDat => continue,
+                PlayerId::Uuid(uuid) => self.get_player_path(uuid),
+            };
+            let data = fs::read(&path)?;
+            let make_deserializer = || nbt::de::Decoder::new(read::GzDecoder::new(&data[..]));
+            let version = convert::get_version(&mut make_deserializer())?;
+            let mut player: SerializedPlayer = VersionedSerde::deserialize(version, target_version, &mut make_deserializer())?;
+            player.rename_items(version, target_version);
+            player._extra.insert("DataVersion".to_owned(), nbt::Value::Int(target_version as i32));
+            let mut nbt_data = Vec::new();
+            VersionedSerde::serialize(player, target_version, target_version, &mut nbt::ser::Encoder::new(&mut nbt_data, None))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+            fs::copy(&path, path.with_extension("dat_old"))?;
+            region::write_atomically(&path, &compression::compress(ChunkCompression::Gzip, &nbt_data)?)?;
+        }
+        Ok(())
+    }
+}
+
+convert::variants! {
+    #[derive(Clone)]
+    pub(super) struct SerializedPlayer {
+        #[serde(rename = "Pos")]
+        pos: Vec<f64>,
+        #[serde(rename = "Rotation")]
+        #[serde(default)]
+        rotation: Vec<f32>,
+        #[serde(rename = "Dimension")]
+        dimension: FName,
+        #[serde(rename = "Inventory")]
+        #[serde(default)]
+        #[variants]
+        inventory: Vec<SerializedItemStack>,
+        #[serde(rename = "EnderItems")]
+        #[serde(default)]
+        #[variants]
+        ender_items: Vec<SerializedItemStack>,
+        #[serde(rename = "XpLevel")]
+        #[serde(default)]
+        xp_level: i32,
+        #[serde(rename = "XpP")]
+        #[serde(default)]
+        xp_progress: f32,
+        #[serde(rename = "XpTotal")]
+        #[serde(default)]
+        xp_total: i32,
+        #[variants]
+        abilities: SerializedPlayerAbilities,
+    }
+    fn up(older: Self::UpInput, prevailing_version: u32) -> Self::UpResult {
+        Ok(Self::UpOutput {
+            pos: older.pos,
+            rotation: older.rotation,
+            dimension: match older.dimension {
+                -1 => CommonFNames.THE_NETHER.clone(),
+                1 => CommonFNames.THE_END.clone(),
+                _ => CommonFNames.OVERWORLD.clone(),
+            },
+            inventory: older.inventory.convert_into(prevailing_version)?,
+            ender_items: older.ender_items.convert_into(prevailing_version)?,
+            xp_level: older.xp_level,
+            xp_progress: older.xp_progress,
+            xp_total: older.xp_total,
+            abilities: older.abilities.convert_into(prevailing_version)?,
+            _extra: older._extra,
+        })
+    }
+    fn down(newer: Self::DownInput, prevailing_version: u32) -> Self::DownResult {
+        let dimension = if newer.dimension == CommonFNames.OVERWORLD {
+            0
+        } else if newer.dimension == CommonFNames.THE_NETHER {
+            -1
+        } else if newer.dimension == CommonFNames.THE_END {
+            1
+        } else {
+            return Err(convert::Error::new(format!("Dimension {} doesn't exist before 1.16", newer.dimension.to_nice_string())));
+        };
+        Ok(Self::DownOutput {
+            pos: newer.pos,
+            rotation: newer.rotation,
+            dimension,
+            inventory: newer.inventory.convert_into(prevailing_version)?,
+            ender_items: newer.ender_items.convert_into(prevailing_version)?,
+            xp_level: newer.xp_level,
+            xp_progress: newer.xp_progress,
+            xp_total: newer.xp_total,
+            abilities: newer.abilities.convert_into(prevailing_version)?,
+            _extra: newer._extra,
+        })
+    }
+    1,15,2 => {
+        #[serde(rename = "Pos")]
+        pos: Vec<f64>,
+        #[serde(rename = "Rotation")]
+        #[serde(default)]
+        rotation: Vec<f32>,
+        #[serde(rename = "Dimension")]
+        #[serde(default)]
+        dimension: i32,
+        #[serde(rename = "Inventory")]
+        #[serde(default)]
+        #[variants]
+        inventory: Vec<SerializedItemStack>,
+        #[serde(rename = "EnderItems")]
+        #[serde(default)]
+        #[variants]
+        ender_items: Vec<SerializedItemStack>,
+        #[serde(rename = "XpLevel")]
+        #[serde(default)]
+        xp_level: i32,
+        #[serde(rename = "XpP")]
+        #[serde(default)]
+        xp_progress: f32,
+        #[serde(rename = "XpTotal")]
+        #[serde(default)]
+        xp_total: i32,
+        #[variants]
+        abilities: SerializedPlayerAbilities,
+    }
+}
+
+impl SerializedPlayer {
+    /// Renames the items in the inventories of a player that was read at `version`.
+    fn rename_items(&mut self, version: u32, target_version: u32) {
+        for item in self.inventory.iter_mut().chain(&mut self.ender_items) {
+            item.id = registries::rename_item(&item.id, version, target_version);
+            item_components::rename_item_stack(&mut item._extra, version, target_version);
+        }
+    }
+
+    fn from_player_data(player: PlayerData) -> Self {
+        SerializedPlayer {
+            pos: player.pos.to_array().to_vec(),
+            rotation: player.rotation.to_array().to_vec(),
+            dimension: player.dimension,
+            inventory: player.inventory.into_iter().map(|(slot, stack)| SerializedItemStack::from_item_stack(slot, stack)).collect(),
+            ender_items: player.ender_items.into_iter().map(|(slot, stack)| SerializedItemStack::from_item_stack(slot, stack)).collect(),
+            xp_level: player.xp_level,
+            xp_progress: player.xp_progress,
+            xp_total: player.xp_total,
+            abilities: SerializedPlayerAbilities {
+                may_fly: player.abilities.may_fly,
+                flying: player.abilities.flying,
+                invulnerable: player.abilities.invulnerable,
+                instabuild: player.abilities.instabuild,
+                may_build: player.abilities.may_build,
+                fly_speed: player.abilities.fly_speed,
+                walk_speed: player.abilities.walk_speed,
+                _extra: player.abilities.extra,
+            },
+            _extra: player.extra,
+        }
+    }
+
+    fn into_player_data(self) -> PlayerData {
+        PlayerData {
+            pos: <[f64; 3]>::try_from(self.pos).map(DVec3::from).unwrap_or(DVec3::ZERO),
+            rotation: <[f32; 2]>::try_from(self.rotation).map(Vec2::from).unwrap_or(Vec2::ZERO),
+            dimension: self.dimension,
+            inventory: self.inventory.into_iter().map(SerializedItemStack::into_item_stack).collect(),
+            ender_items: self.ender_items.into_iter().map(SerializedItemStack::into_item_stack).collect(),
+            xp_level: self.xp_level,
+            xp_progress: self.xp_progress,
+            xp_total: self.xp_total,
+            abilities: PlayerAbilities {
+                may_fly: self.abilities.may_fly,
+                flying: self.abilities.flying,
+                invulnerable: self.abilities.invulnerable,
+                instabuild: self.abilities.instabuild,
+                may_build: self.abilities.may_build,
+                fly_speed: self.abilities.fly_speed,
+                walk_speed: self.abilities.walk_speed,
+                extra: self.abilities._extra,
+            },
+            extra: self._extra,
+        }
+    }
+}
+
+convert::variants! {
+    #[derive(Clone)]
+    pub(super) struct SerializedItemStack {
+        #[serde(rename = "Slot")]
+        #[serde(default)]
+        slot: i8,
+        #[registry(item)]
+        id: FName,
+        #[serde(default = "default_item_count")]
+        count: i32,
+    }
+    fn up(older: Self::UpInput, _prevailing_version: u32) -> Self::UpResult {
+        let mut extra = older._extra;
+        item_components::upgrade_item_stack(&mut extra);
+        Ok(Self::UpOutput {
+            slot: older.slot,
+            id: older.id,
+            count: older.count as i32,
+            _extra: extra,
+        })
+    }
+    fn down(newer: Self::DownInput, _prevailing_version: u32) -> Self::DownResult {
+        let mut extra = newer._extra;
+        item_components::downgrade_item_stack(&mut extra);
+        Ok(Self::DownOutput {
+            slot: newer.slot,
+            id: newer.id,
+            count: newer.count.clamp(i8::MIN as i32, i8::MAX as i32) as i8,
+            _extra: extra,
+        })
+    }
+    1,20,4 => {
+        #[serde(rename = "Slot")]
+        #[serde(default)]
+        slot: i8,
+        #[registry(item)]
+        id: FName,
+        #[serde(rename = "Count")]
+        count: i8,
+    }
+}
+
+fn default_item_count() -> i32 {
+    1
+}
+
+impl SerializedItemStack {
+    fn from_item_stack(slot: i8, stack: ItemStack) -> Self {
+        SerializedItemStack {
+            slot,
+            id: stack.id,
+            count: stack.count,
+            _extra: stack.data,
+        }
+    }
+
+    fn into_item_stack(self) -> (i8, ItemStack) {
+        (self.slot, ItemStack { id: self.id, count: self.count, data: self._extra })
+    }
+}
+
+convert::variants! {
+    #[derive(Clone)]
+    pub(super) struct SerializedPlayerAbilities {
+        #[serde(rename = "mayfly")]
+        #[serde(default)]
+        may_fly: bool,
+        #[serde(default)]
+        flying: bool,
+        #[serde(default)]
+        invulnerable: bool,
+        #[serde(default)]
+        instabuild: bool,
+        #[serde(rename = "mayBuild")]
+        #[serde(default)]
+        may_build: bool,
+        #[serde(rename = "flySpeed")]
+        #[serde(default)]
+        fly_speed: f32,
+        #[serde(rename = "walkSpeed")]
+        #[serde(default)]
+        walk_speed: f32,
+    }
+}
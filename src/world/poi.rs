@@ -0,0 +1,217 @@
+use std::collections::BTreeMap;
+use std::io;
+use ahash::AHashMap;
+use lazy_static::lazy_static;
+use crate::{convert, fname, make_a_hash_map, World};
+use crate::convert::VersionedSerde;
+use crate::fname::FName;
+use crate::geom::{BlockPos, ChunkPos};
+use crate::world::{BlockState, Chunk, Dimension};
+use crate::world::compression;
+use crate::world::compression::ChunkCompression;
+use crate::world::region::{RegionFile, RegionKind};
+
+/// A block that villagers, bees or portals look for, e.g. a bed or a workstation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoiRecord {
+    pub pos: BlockPos,
+    pub poi_type: FName,
+    /// How many more mobs can claim it.
+    pub free_tickets: i32,
+    /// The rest of the NBT.
+    pub data: BTreeMap<String, nbt::Value>,
+}
+
+/// The points of interest in a section of a chunk.
+#[derive(Debug, Clone, Default)]
+pub struct PoiSection {
+    /// The game rebuilds invalid sections from their blocks when it loads them.
+    pub valid: bool,
+    pub records: Vec<PoiRecord>,
+    /// The rest of the NBT.
+    pub data: BTreeMap<String, nbt::Value>,
+}
+
+lazy_static! {
+    // the POI type of each block and the tickets it starts with, beds are handled separately
+    static ref POI_TYPES: AHashMap<FName, (FName, i32)> = make_a_hash_map!(
+        fname::from_str("blast_furnace") => (fname::from_str("armorer"), 1),
+        fname::from_str("smoker") => (fname::from_str("butcher"), 1),
+        fname::from_str("cartography_table") => (fname::from_str("cartographer"), 1),
+        fname::from_str("brewing_stand") => (fname::from_str("cleric"), 1),
+        fname::from_str("composter") => (fname::from_str("farmer"), 1),
+        fname::from_str("barrel") => (fname::from_str("fisherman"), 1),
+        fname::from_str("fletching_table") => (fname::from_str("fletcher"), 1),
+        fname::from_str("cauldron") => (fname::from_str("leatherworker"), 1),
+        fname::from_str("water_cauldron") => (fname::from_str("leatherworker"), 1),
+        fname::from_str("lava_cauldron") => (fname::from_str("leatherworker"), 1),
+        fname::from_str("powder_snow_cauldron") => (fname::from_str("leatherworker"), 1),
+        fname::from_str("lectern") => (fname::from_str("librarian"), 1),
+        fname::from_str("stonecutter") => (fname::from_str("mason"), 1),
+        fname::from_str("loom") => (fname::from_str("shepherd"), 1),
+        fname::from_str("smithing_table") => (fname::from_str("toolsmith"), 1),
+        fname::from_str("grindstone") => (fname::from_str("weaponsmith"), 1),
+        fname::from_str("bell") => (fname::from_str("meeting"), 32),
+        fname::from_str("beehive") => (fname::from_str("beehive"), 0),
+        fname::from_str("bee_nest") => (fname::from_str("bee_nest"), 0),
+        fname::from_str("nether_portal") => (fname::from_str("nether_portal"), 0),
+        fname::from_str("lodestone") => (fname::from_str("lodestone"), 0),
+        fname::from_str("lightning_rod") => (fname::from_str("lightning_rod"), 0),
+    );
+
+    static ref HOME: FName = fname::from_str("home");
+    static ref PART: FName = fname::from_str("part");
+    static ref HEAD: FName = fname::from_str("head");
+}
+
+/// The POI type of the block state and the tickets a new record of it has, if it's a point of interest.
+fn get_poi_type(state: &BlockState) -> Option<(FName, i32)> {
+    // only the head of a bed is a home
+    if state.block.name.ends_with("_bed") {
+        return (state.properties.get(&*PART) == Some(&*HEAD)).then(|| (HOME.clone(), 1));
+    }
+    POI_TYPES.get(&state.block).cloned()
+}
+
+impl Dimension {
+    /// Reads the POI sections of a chunk, keyed by section y, since 1.14.
+    pub fn read_poi(&self, world: &World, pos: ChunkPos) -> io::Result<BTreeMap<i32, PoiSection>> {
+        let (b, buffer) = match self.read_chunk_data(world, RegionKind::Poi, pos)? {
+            Some(data) => data,
+            None => return Ok(BTreeMap::new()),
+        };
+        let make_deserializer = || -> io::Result<_> {
+            Ok(nbt::de::Decoder::new(compression::decompress(b, &buffer)?))
+        };
+        let version = convert::get_version(&mut make_deserializer()?)?;
+        let poi_chunk: SerializedPoiChunk = VersionedSerde::deserialize(version, world.data_version(), &mut make_deserializer()?)?;
+        Ok(poi_chunk.sections.into_iter()
+            .filter_map(|(y, section)| Some((y.parse().ok()?, section.into_poi_section())))
+            .collect())
+    }
+
+    /// Writes the POI sections of a chunk, removing the chunk from the POI region file if there are none.
+    pub fn write_poi(&self, world: &World, pos: ChunkPos, sections: &BTreeMap<i32, PoiSection>) -> io::Result<()> {
+        let region_pos = pos >> 5i8;
+        let mut region_file = RegionFile::open_or_create(self.get_region_path(world, RegionKind::Poi, region_pos))?;
+        self.write_poi_to_region(world, &mut region_file, pos, sections, world.chunk_compression())?;
+        self.replace_region_file(RegionKind::Poi, region_pos, || region_file.save())
+    }
+
+    pub(super) fn write_poi_to_region(&self, world: &World, region_file: &mut RegionFile, pos: ChunkPos, sections: &BTreeMap<i32, PoiSection>, compression: ChunkCompression) -> io::Result<()> {
+        if sections.is_empty() {
+            region_file.remove_chunk(pos);
+            return Ok(());
+        }
+        let version = world.data_version();
+        let mut extra = BTreeMap::new();
+        extra.insert("DataVersion".to_owned(), nbt::Value::Int(version as i32));
+        let poi_chunk = SerializedPoiChunk {
+            sections: sections.iter().map(|(y, section)| (y.to_string(), SerializedPoiSection::from_poi_section(section))).collect(),
+            _extra: extra,
+        };
+        let mut nbt_data = Vec::new();
+        VersionedSerde::serialize(poi_chunk, version, version, &mut nbt::ser::Encoder::new(&mut nbt_data, None))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        region_file.write_chunk(pos, compression.id(), &compression::compress(compression, &nbt_data)?)
+    }
+
+    /// Recomputes the POI sections of the given subchunks from their blocks. Records whose block keeps its POI type
+    /// are kept as they are, so that villagers don't lose their claims.
+    pub(super) fn rebuild_poi_sections(&self, pos: ChunkPos, chunk: &Chunk, subchunk_indices: &[usize], sections: &mut BTreeMap<i32, PoiSection>) {
+        for &index in subchunk_indices {
+            let section_y = (self.min_y >> 4) + index as i32;
+            let old_section = sections.remove(&section_y).unwrap_or_default();
+            let mut old_records: AHashMap<_, _> = old_section.records.into_iter().map(|record| ((record.pos, record.poi_type.clone()), record)).collect();
+            let subchunk = match chunk.get_subchunk(index) {
+                Some(subchunk) => subchunk,
+                None => continue,
+            };
+            let block_data = subchunk.block_data.read().unwrap();
+            if !block_data.palette_contains(|state| get_poi_type(state).is_some()) {
+                continue;
+            }
+            let mut records = Vec::new();
+            let origin = BlockPos::new(pos.x << 4, section_y << 4, pos.y << 4);
+            for y in 0..16 {
+                for z in 0..16 {
+                    for x in 0..16 {
+                        if let Some((poi_type, tickets)) = get_poi_type(block_data.get(x, y, z)) {
+                            let pos = origin + BlockPos::new(x as i32, y as i32, z as i32);
+                            let record = old_records.remove(&(pos, poi_type.clone()))
+                                .unwrap_or_else(|| PoiRecord { pos, poi_type, free_tickets: tickets, data: BTreeMap::new() });
+                            records.push(record);
+                        }
+                    }
+                }
+            }
+            if !records.is_empty() {
+                sections.insert(section_y, PoiSection { valid: true, records, data: old_section.data });
+            }
+        }
+    }
+}
+
+convert::variants! {
+    /// A chunk of a POI region file.
+    pub(super) struct SerializedPoiChunk {
+        #[serde(rename = "Sections")]
+        #[serde(default)]
+        #[variants]
+        sections: BTreeMap<String, SerializedPoiSection>,
+    }
+}
+
+convert::variants! {
+    struct SerializedPoiSection {
+        #[serde(rename = "Valid")]
+        #[serde(default)]
+        valid: bool,
+        #[serde(rename = "Records")]
+        #[serde(default)]
+        #[variants]
+        records: Vec<SerializedPoiRecord>,
+    }
+}
+
+impl SerializedPoiSection {
+    fn from_poi_section(section: &PoiSection) -> Self {
+        SerializedPoiSection {
+            valid: section.valid,
+            records: section.records.iter().map(|record| SerializedPoiRecord {
+                pos: record.pos.to_array().to_vec(),
+                poi_type: record.poi_type.clone(),
+                free_tickets: record.free_tickets,
+                _extra: record.data.clone(),
+            }).collect(),
+            _extra: section.data.clone(),
+        }
+    }
+
+    fn into_poi_section(self) -> PoiSection {
+        PoiSection {
+            valid: self.valid,
+            records: self.records.into_iter().filter_map(|record| {
+                Some(PoiRecord {
+                    pos: <[i32; 3]>::try_from(record.pos).ok().map(BlockPos::from)?,
+                    poi_type: record.poi_type,
+                    free_tickets: record.free_tickets,
+                    data: record._extra,
+                })
+            }).collect(),
+            data: self._extra,
+        }
+    }
+}
+
+convert::variants! {
+    struct SerializedPoiRecord {
+        #[serde(serialize_with = "nbt::i32_array")]
+        pos: Vec<i32>,
+        #[registry(point_of_interest_type)]
+        #[serde(rename = "type")]
+        poi_type: FName,
+        #[serde(default)]
+        free_tickets: i32,
+    }
+}
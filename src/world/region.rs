@@ -0,0 +1,445 @@
+use std::{fs, io, time};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use byteorder::{BigEndian, ByteOrder};
+use glam::IVec2;
+use crate::geom::ChunkPos;
+
+pub(super) const SECTOR_SIZE: usize = 4096;
+pub(super) const HEADER_SECTORS: usize = 2;
+const MAX_SECTORS_PER_CHUNK: usize = 255;
+/// Set in the compression type of chunks stored in an external chunk file.
+pub(super) const EXTERNAL_FLAG: u8 = 128;
+
+/// The kinds of region files a dimension has, each in a folder of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(super) enum RegionKind {
+    Terrain,
+    /// Since 1.17, entities are stored separately from the terrain.
+    Entities,
+    /// The points of interest of villagers, bees and portals, since 1.14.
+    Poi,
+}
+
+impl RegionKind {
+    pub(super) const ALL: [RegionKind; 3] = [RegionKind::Terrain, RegionKind::Entities, RegionKind::Poi];
+
+    pub(super) fn dir_name(self) -> &'static str {
+        match self {
+            RegionKind::Terrain => "region",
+            RegionKind::Entities => "entities",
+            RegionKind::Poi => "poi",
+        }
+    }
+}
+
+/// The formats of region files, which only differ in the chunks they contain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum RegionFormat {
+    Anvil,
+    /// Used before 1.2, with chunks 128 blocks high whose blocks are in one array ordered by x, z, y.
+    McRegion,
+}
+
+impl RegionFormat {
+    /// The `version` in level.dat of McRegion worlds, Anvil worlds have 19133.
+    const MCREGION_VERSION: i32 = 19132;
+
+    pub(super) fn from_level_dat_version(version: i32) -> RegionFormat {
+        if version == Self::MCREGION_VERSION {
+            RegionFormat::McRegion
+        } else {
+            RegionFormat::Anvil
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            RegionFormat::Anvil => "mca",
+            RegionFormat::McRegion => "mcr",
+        }
+    }
+}
+
+pub(super) fn get_region_file_name(region_pos: IVec2, format: RegionFormat) -> String {
+    format!("r.{}.{}.{}", region_pos.x, region_pos.y, format.extension())
+}
+
+/// Parses the region position from a region file name, e.g. `r.1.-2.mca`.
+pub(super) fn parse_region_file_name(name: &str, format: RegionFormat) -> Option<IVec2> {
+    let mut parts = name.strip_prefix("r.")?.strip_suffix(format.extension())?.strip_suffix('.')?.split('.');
+    let x = parts.next()?.parse().ok()?;
+    let z = parts.next()?.parse().ok()?;
+    match parts.next() {
+        Some(_) => None,
+        None => Some(IVec2::new(x, z)),
+    }
+}
+
+/// Chunks too large to fit in a region file are stored in a file of their own next to it.
+pub(super) fn get_external_chunk_file_name(pos: ChunkPos) -> String {
+    format!("c.{}.{}.mcc", pos.x, pos.y)
+}
+
+pub(super) fn get_chunk_index(pos: ChunkPos) -> usize {
+    ((pos.x & 31) | ((pos.y & 31) << 5)) as usize
+}
+
+/// An in-memory copy of a region file, which is modified and then written back atomically.
+pub(super) struct RegionFile {
+    path: PathBuf,
+    data: Vec<u8>,
+    // external chunk files to write, or to delete if `None`, when the region file is saved
+    external_chunks: Vec<(PathBuf, Option<Vec<u8>>)>,
+}
+
+impl RegionFile {
+    pub(super) fn open_or_create(path: PathBuf) -> io::Result<Self> {
+        let mut data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        if data.len() < HEADER_SECTORS * SECTOR_SIZE {
+            data.resize(HEADER_SECTORS * SECTOR_SIZE, 0);
+        }
+        Ok(RegionFile { path, data, external_chunks: Vec::new() })
+    }
+
+    pub(super) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(super) fn sector_count(&self) -> usize {
+        self.data.len().div_ceil(SECTOR_SIZE)
+    }
+
+    /// The raw data of a run of sectors, or `None` if it goes past the end of the file.
+    pub(super) fn sectors(&self, offset: usize, count: usize) -> Option<&[u8]> {
+        if offset + count > self.sector_count() {
+            return None;
+        }
+        // the last sector may not be padded to its full size
+        Some(&self.data[offset * SECTOR_SIZE..((offset + count) * SECTOR_SIZE).min(self.data.len())])
+    }
+
+    /// The first sector and the sector count of the chunk, both 0 if there is no chunk.
+    pub(super) fn location(&self, index: usize) -> (usize, usize) {
+        let offset = BigEndian::read_u24(&self.data[index << 2..]) as usize;
+        let count = self.data[(index << 2) + 3] as usize;
+        (offset, count)
+    }
+
+    fn set_location(&mut self, index: usize, offset: usize, count: usize) {
+        BigEndian::write_u24(&mut self.data[index << 2..], offset as u32);
+        self.data[(index << 2) + 3] = count as u8;
+    }
+
+    pub(super) fn timestamp(&self, index: usize) -> u32 {
+        BigEndian::read_u32(&self.data[SECTOR_SIZE + (index << 2)..])
+    }
+
+    pub(super) fn set_timestamp(&mut self, index: usize, timestamp: u32) {
+        BigEndian::write_u32(&mut self.data[SECTOR_SIZE + (index << 2)..], timestamp);
+    }
+
+    fn current_timestamp() -> u32 {
+        time::SystemTime::now().duration_since(time::UNIX_EPOCH).map(|d| d.as_secs() as u32).unwrap_or(0)
+    }
+
+    fn find_free_sectors(&self, excluding_index: usize, count: usize) -> usize {
+        let total_sectors = self.sector_count();
+        let mut used = vec![false; total_sectors];
+        used[..HEADER_SECTORS].fill(true);
+        for index in 0..1024 {
+            if index == excluding_index {
+                continue;
+            }
+            let (offset, sector_count) = self.location(index);
+            for sector in offset..(offset + sector_count).min(total_sectors) {
+                used[sector] = true;
+            }
+        }
+        let mut run_start = HEADER_SECTORS;
+        for sector in HEADER_SECTORS..total_sectors {
+            if used[sector] {
+                run_start = sector + 1;
+            } else if sector + 1 - run_start >= count {
+                return run_start;
+            }
+        }
+        run_start
+    }
+
+    pub(super) fn external_chunk_path(&self, pos: ChunkPos) -> PathBuf {
+        self.path.with_file_name(get_external_chunk_file_name(pos))
+    }
+
+    /// Writes the already compressed chunk payload into the first free run of sectors that can hold it. Payloads too
+    /// large for the region file are written to an external chunk file instead, with only a marker in the region file.
+    pub(super) fn write_chunk(&mut self, pos: ChunkPos, compression: u8, payload: &[u8]) -> io::Result<()> {
+        let index = get_chunk_index(pos);
+        let external_path = self.external_chunk_path(pos);
+        self.external_chunks.retain(|(path, _)| *path != external_path);
+        if (payload.len() + 5).div_ceil(SECTOR_SIZE) > MAX_SECTORS_PER_CHUNK {
+            self.external_chunks.push((external_path, Some(payload.to_vec())));
+            self.write_chunk_sectors(index, compression | EXTERNAL_FLAG, &[]);
+        } else {
+            // the chunk may have been external before
+            self.external_chunks.push((external_path, None));
+            self.write_chunk_sectors(index, compression, payload);
+        }
+        Ok(())
+    }
+
+    fn write_chunk_sectors(&mut self, index: usize, compression: u8, payload: &[u8]) {
+        let sector_count = (payload.len() + 5).div_ceil(SECTOR_SIZE);
+        let offset = self.find_free_sectors(index, sector_count);
+        let start = offset * SECTOR_SIZE;
+        let end = (offset + sector_count) * SECTOR_SIZE;
+        if self.data.len() < end {
+            self.data.resize(end, 0);
+        }
+        BigEndian::write_i32(&mut self.data[start..], (payload.len() + 1) as i32);
+        self.data[start + 4] = compression;
+        self.data[start + 5..start + 5 + payload.len()].copy_from_slice(payload);
+        self.data[start + 5 + payload.len()..end].fill(0);
+        self.set_location(index, offset, sector_count);
+        self.set_timestamp(index, Self::current_timestamp());
+    }
+
+    /// The compression type and the payload of the chunk in the region file, which is empty for external chunks.
+    /// Payloads longer than their sectors are cut short.
+    pub(super) fn chunk_payload(&self, index: usize) -> Option<(u8, &[u8])> {
+        let (offset, count) = self.location(index);
+        let sectors = match self.sectors(offset, count) {
+            Some(sectors) if offset >= HEADER_SECTORS && sectors.len() >= 5 => sectors,
+            _ => return None,
+        };
+        let length = (BigEndian::read_i32(sectors).max(1) as usize - 1).min(sectors.len() - 5);
+        Some((sectors[4], &sectors[5..5 + length]))
+    }
+
+    /// Copies the chunk into free sectors, e.g. when it shares its sectors with another chunk.
+    pub(super) fn relocate_chunk(&mut self, index: usize) {
+        let (compression, payload) = match self.chunk_payload(index) {
+            Some((compression, payload)) => (compression, payload.to_vec()),
+            None => return,
+        };
+        let timestamp = self.timestamp(index);
+        // so that the old sectors are free, unless the other chunk uses them
+        self.set_location(index, 0, 0);
+        self.write_chunk_sectors(index, compression, &payload);
+        self.set_timestamp(index, timestamp);
+    }
+
+    /// Points the location of one chunk at the sectors of another, e.g. if the chunk data was stored in the wrong
+    /// place. External chunk files are named after their chunk, so their payload is written again for the new position.
+    pub(super) fn move_chunk(&mut self, from: ChunkPos, to: ChunkPos) -> io::Result<()> {
+        let (from_index, to_index) = (get_chunk_index(from), get_chunk_index(to));
+        let timestamp = self.timestamp(from_index);
+        match self.chunk_payload(from_index) {
+            Some((compression, _)) if compression & EXTERNAL_FLAG != 0 => {
+                let payload = fs::read(self.external_chunk_path(from))?;
+                // moves the payload back into the region file if it fits
+                self.write_chunk(to, compression & !EXTERNAL_FLAG, &payload)?;
+                self.remove_chunk(from);
+            }
+            _ => {
+                let (offset, count) = self.location(from_index);
+                self.set_location(to_index, offset, count);
+                self.set_location(from_index, 0, 0);
+                self.set_timestamp(from_index, 0);
+            }
+        }
+        self.set_timestamp(to_index, timestamp);
+        Ok(())
+    }
+
+    /// Rewrites the chunks one after another with no free sectors between them, keeping their timestamps. Chunks whose
+    /// sectors can't be read are removed. Returns the number of chunks removed.
+    pub(super) fn compact(&mut self) -> usize {
+        let mut chunks: Vec<usize> = (0..1024).filter(|&index| self.location(index) != (0, 0)).collect();
+        // keep the order of the chunks in the file
+        chunks.sort_by_key(|&index| self.location(index).0);
+        let mut data = self.data[..HEADER_SECTORS * SECTOR_SIZE].to_vec();
+        let mut removed_chunks = 0;
+        for index in chunks {
+            let offset = data.len() / SECTOR_SIZE;
+            match self.chunk_payload(index) {
+                Some((compression, payload)) => {
+                    let sector_count = (payload.len() + 5).div_ceil(SECTOR_SIZE);
+                    let mut header = [0; 5];
+                    BigEndian::write_i32(&mut header, (payload.len() + 1) as i32);
+                    header[4] = compression;
+                    data.extend_from_slice(&header);
+                    data.extend_from_slice(payload);
+                    data.resize((offset + sector_count) * SECTOR_SIZE, 0);
+                    BigEndian::write_u24(&mut data[index << 2..], offset as u32);
+                    data[(index << 2) + 3] = sector_count as u8;
+                }
+                None => {
+                    data[index << 2..(index << 2) + 4].fill(0);
+                    BigEndian::write_u32(&mut data[SECTOR_SIZE + (index << 2)..], 0);
+                    removed_chunks += 1;
+                }
+            }
+        }
+        self.data = data;
+        removed_chunks
+    }
+
+    pub(super) fn remove_chunk(&mut self, pos: ChunkPos) {
+        let index = get_chunk_index(pos);
+        self.set_location(index, 0, 0);
+        self.set_timestamp(index, 0);
+        let external_path = self.external_chunk_path(pos);
+        self.external_chunks.retain(|(path, _)| *path != external_path);
+        self.external_chunks.push((external_path, None));
+    }
+
+    /// Writes the region file to a temporary file next to it, then renames it over the original. External chunk files
+    /// are written first, so the region file never points to a missing one.
+    pub(super) fn save(&self) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        for (path, payload) in &self.external_chunks {
+            if let Some(payload) = payload {
+                write_atomically(path, payload)?;
+            }
+        }
+        write_atomically(&self.path, &self.data)?;
+        for (path, payload) in &self.external_chunks {
+            if payload.is_none() {
+                match fs::remove_file(path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes to a temporary file next to the path, then renames it over the path.
+pub(super) fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    {
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory of its own in the system temp directory, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("quickedit-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn payload(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+    }
+
+    #[test]
+    fn written_chunks_round_trip_through_save() {
+        let dir = TempDir::new("region-round-trip");
+        let path = dir.0.join(get_region_file_name(IVec2::ZERO, RegionFormat::Anvil));
+        let (a, b) = (ChunkPos::new(1, 2), ChunkPos::new(31, 31));
+        let mut region_file = RegionFile::open_or_create(path.clone()).unwrap();
+        region_file.write_chunk(a, 2, &payload(100, 1)).unwrap();
+        region_file.write_chunk(b, 2, &payload(SECTOR_SIZE * 3, 2)).unwrap();
+        // a larger payload no longer fits in the sector of the chunk
+        region_file.write_chunk(a, 2, &payload(SECTOR_SIZE, 3)).unwrap();
+        region_file.set_timestamp(get_chunk_index(a), 1234);
+        region_file.save().unwrap();
+
+        let region_file = RegionFile::open_or_create(path).unwrap();
+        assert_eq!(region_file.chunk_payload(get_chunk_index(a)), Some((2, &payload(SECTOR_SIZE, 3)[..])));
+        assert_eq!(region_file.chunk_payload(get_chunk_index(b)), Some((2, &payload(SECTOR_SIZE * 3, 2)[..])));
+        assert_eq!(region_file.timestamp(get_chunk_index(a)), 1234);
+        assert_eq!(region_file.location(get_chunk_index(a)).1, 2);
+        assert_eq!(region_file.chunk_payload(0), None);
+    }
+
+    #[test]
+    fn relocate_chunk_separates_shared_sectors() {
+        let mut region_file = RegionFile::open_or_create(PathBuf::from("r.0.0.mca")).unwrap();
+        let (a, b) = (ChunkPos::new(0, 0), ChunkPos::new(1, 0));
+        region_file.write_chunk(a, 2, &payload(100, 1)).unwrap();
+        let (offset, count) = region_file.location(get_chunk_index(a));
+        region_file.set_location(get_chunk_index(b), offset, count);
+        region_file.set_timestamp(get_chunk_index(b), 1234);
+
+        region_file.relocate_chunk(get_chunk_index(b));
+        assert_eq!(region_file.location(get_chunk_index(a)), (offset, count));
+        assert_ne!(region_file.location(get_chunk_index(b)).0, offset);
+        assert_eq!(region_file.chunk_payload(get_chunk_index(b)), Some((2, &payload(100, 1)[..])));
+        assert_eq!(region_file.timestamp(get_chunk_index(b)), 1234);
+    }
+
+    #[test]
+    fn compact_removes_gaps_and_unreadable_chunks() {
+        let mut region_file = RegionFile::open_or_create(PathBuf::from("r.0.0.mca")).unwrap();
+        let (a, b, c) = (ChunkPos::new(0, 0), ChunkPos::new(1, 0), ChunkPos::new(2, 0));
+        region_file.write_chunk(a, 2, &payload(100, 1)).unwrap();
+        region_file.write_chunk(b, 2, &payload(SECTOR_SIZE * 2, 2)).unwrap();
+        region_file.write_chunk(c, 2, &payload(100, 3)).unwrap();
+        region_file.set_timestamp(get_chunk_index(c), 1234);
+        region_file.remove_chunk(b);
+        // points past the end of the file
+        region_file.set_location(5, 1000, 1);
+        assert_eq!(region_file.sector_count(), HEADER_SECTORS + 5);
+
+        assert_eq!(region_file.compact(), 1);
+        assert_eq!(region_file.sector_count(), HEADER_SECTORS + 2);
+        assert_eq!(region_file.location(get_chunk_index(a)), (HEADER_SECTORS, 1));
+        assert_eq!(region_file.location(get_chunk_index(c)), (HEADER_SECTORS + 1, 1));
+        assert_eq!(region_file.location(5), (0, 0));
+        assert_eq!(region_file.chunk_payload(get_chunk_index(a)), Some((2, &payload(100, 1)[..])));
+        assert_eq!(region_file.chunk_payload(get_chunk_index(c)), Some((2, &payload(100, 3)[..])));
+        assert_eq!(region_file.timestamp(get_chunk_index(c)), 1234);
+    }
+
+    #[test]
+    fn external_chunks_move_back_into_the_region_file() {
+        let dir = TempDir::new("region-external");
+        let path = dir.0.join(get_region_file_name(IVec2::ZERO, RegionFormat::Anvil));
+        let (from, to) = (ChunkPos::new(3, 4), ChunkPos::new(5, 6));
+        let large_payload = payload(SECTOR_SIZE * MAX_SECTORS_PER_CHUNK, 1);
+        let mut region_file = RegionFile::open_or_create(path.clone()).unwrap();
+        region_file.write_chunk(from, 2, &large_payload).unwrap();
+        assert_eq!(region_file.chunk_payload(get_chunk_index(from)), Some((2 | EXTERNAL_FLAG, &[][..])));
+        region_file.save().unwrap();
+        assert_eq!(fs::read(region_file.external_chunk_path(from)).unwrap(), large_payload);
+
+        let mut region_file = RegionFile::open_or_create(path).unwrap();
+        region_file.move_chunk(from, to).unwrap();
+        region_file.save().unwrap();
+        // still too large, so it's external under its new name
+        assert!(!region_file.external_chunk_path(from).exists());
+        assert_eq!(fs::read(region_file.external_chunk_path(to)).unwrap(), large_payload);
+        assert_eq!(region_file.location(get_chunk_index(from)), (0, 0));
+        assert_eq!(region_file.chunk_payload(get_chunk_index(to)).map(|(compression, _)| compression), Some(2 | EXTERNAL_FLAG));
+    }
+}
@@ -0,0 +1,867 @@
+use std::{fmt, fs, io};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use byteorder::{BigEndian, ByteOrder};
+use glam::IVec2;
+use log::info;
+use serde::Deserialize;
+use crate::convert::data_versions;
+use crate::fname::FName;
+use crate::geom::{ChunkPos, IVec2RangeExtensions};
+use crate::world::{compression, Dimension, World};
+use crate::world::compression::ChunkCompression;
+use crate::world::region;
+use crate::world::region::{RegionFile, RegionFormat, RegionKind};
+use crate::world::workers::WorldRef;
+
+/// The progress of a tool that runs over all region files of a world, and its report once it's finished.
+#[derive(Default)]
+pub struct RegionToolProgress {
+    total_regions: AtomicUsize,
+    done_regions: AtomicUsize,
+    cancelled: AtomicBool,
+    finished: AtomicBool,
+    report: Mutex<Option<String>>,
+}
+
+impl RegionToolProgress {
+    pub fn fraction(&self) -> f32 {
+        let total_regions = self.total_regions.load(Ordering::Relaxed);
+        if total_regions == 0 {
+            0.0
+        } else {
+            self.done_regions.load(Ordering::Relaxed) as f32 / total_regions as f32
+        }
+    }
+
+    /// Stops before the next region file. Region files that were already processed stay processed.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Acquire)
+    }
+
+    pub fn take_report(&self) -> Option<String> {
+        self.report.lock().unwrap().take()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChunkProblem {
+    /// The sectors of the chunk are inside the header or past the end of the file.
+    OutOfBounds { offset: usize, count: usize },
+    /// The chunk shares sectors with another chunk.
+    Overlapping(ChunkPos),
+    /// The declared length of the chunk doesn't fit in its sectors.
+    Truncated,
+    UnknownCompression(u8),
+    MissingExternalFile,
+    UndecodableNbt(String),
+    /// The chunk data is for a different position than where it's stored.
+    WrongPosition(ChunkPos),
+}
+
+impl fmt::Display for ChunkProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkProblem::OutOfBounds { offset, count } => write!(f, "sectors {} to {} are out of bounds", offset, offset + count),
+            ChunkProblem::Overlapping(other) => write!(f, "overlaps chunk {}", other),
+            ChunkProblem::Truncated => write!(f, "data is truncated"),
+            ChunkProblem::UnknownCompression(id) => write!(f, "unknown compression type {}", id),
+            ChunkProblem::MissingExternalFile => write!(f, "external chunk file is missing"),
+            ChunkProblem::UndecodableNbt(err) => write!(f, "can't be decoded: {}", err),
+            ChunkProblem::WrongPosition(pos) => write!(f, "contains chunk {}", pos),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkFix {
+    /// Copied to free sectors.
+    Relocated,
+    /// Moved to the location of the position in its data.
+    Moved(ChunkPos),
+    Dropped,
+}
+
+#[derive(Debug, Clone)]
+pub struct RegionProblem {
+    pub dimension: FName,
+    pub chunk: ChunkPos,
+    pub problem: ChunkProblem,
+    pub fix: Option<ChunkFix>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RegionReport {
+    pub regions: usize,
+    pub chunks: usize,
+    pub problems: Vec<RegionProblem>,
+    /// Region files which couldn't be read at all, with the error.
+    pub unreadable_regions: Vec<(PathBuf, String)>,
+}
+
+impl fmt::Display for RegionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Checked {} chunks in {} region files, found {} problems", self.chunks, self.regions, self.problems.len())?;
+        for (path, err) in &self.unreadable_regions {
+            write!(f, "\n{}: {}", path.display(), err)?;
+        }
+        for problem in &self.problems {
+            write!(f, "\n{} chunk {}: {}", problem.dimension, problem.chunk, problem.problem)?;
+            match problem.fix {
+                Some(ChunkFix::Relocated) => write!(f, " (relocated)")?,
+                Some(ChunkFix::Moved(pos)) => write!(f, " (moved to {})", pos)?,
+                Some(ChunkFix::Dropped) => write!(f, " (dropped)")?,
+                None => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks every region file of the world for corrupt chunks on a worker. With `repair`, chunks that share sectors are
+/// relocated, chunks stored in the wrong place are moved to the right place if it's free, and the rest of the broken
+/// chunks are dropped. The world shouldn't have unsaved changes when repairing.
+pub fn check_regions(world: &WorldRef, repair: bool) -> Arc<RegionToolProgress> {
+    run_region_tool(world, &RegionKind::ALL, move |_, region, report: &mut RegionReport| {
+        let (chunk_count, problems) = check_region(region, repair)?;
+        report.regions += 1;
+        report.chunks += chunk_count;
+        report.problems.extend(problems);
+        Ok(())
+    })
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CompactionReport {
+    pub regions: usize,
+    pub chunks: usize,
+    pub recompressed_chunks: usize,
+    /// Chunks which couldn't be decompressed, and were kept with their old compression.
+    pub failed_chunks: usize,
+    /// Chunks whose sectors couldn't be read at all, and were removed.
+    pub removed_chunks: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    pub unreadable_regions: Vec<(PathBuf, String)>,
+}
+
+impl fmt::Display for CompactionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Compacted {} chunks in {} region files from {} to {} bytes",
+            self.chunks,
+            self.regions,
+            self.bytes_before,
+            self.bytes_after,
+        )?;
+        if self.recompressed_chunks != 0 || self.failed_chunks != 0 {
+            write!(f, "\nRecompressed {} chunks, failed to recompress {} chunks", self.recompressed_chunks, self.failed_chunks)?;
+        }
+        if self.removed_chunks != 0 {
+            write!(f, "\nRemoved {} unreadable chunks", self.removed_chunks)?;
+        }
+        for (path, err) in &self.unreadable_regions {
+            write!(f, "\n{}: {}", path.display(), err)?;
+        }
+        Ok(())
+    }
+}
+
+/// Rewrites every region file of the world without free sectors on a worker, keeping the chunk timestamps. With
+/// `recompression`, chunks are also recompressed with that compression type. The world shouldn't have unsaved changes.
+pub fn compact_regions(world: &WorldRef, recompression: Option<ChunkCompression>) -> Arc<RegionToolProgress> {
+    run_region_tool(world, &RegionKind::ALL, move |_, region, report: &mut CompactionReport| {
+        let mut region_file = RegionFile::open_or_create(region.path.clone())?;
+        report.bytes_before += fs::metadata(&region.path)?.len();
+        report.regions += 1;
+        report.chunks += (0..1024).filter(|&index| region_file.location(index) != (0, 0)).count();
+        if let Some(recompression) = recompression {
+            for index in 0..1024 {
+                match recompress_chunk(&mut region_file, chunk_pos_from_index(region.pos, index), recompression) {
+                    Ok(true) => report.recompressed_chunks += 1,
+                    Ok(false) => {}
+                    Err(_) => report.failed_chunks += 1,
+                }
+            }
+        }
+        report.removed_chunks += region_file.compact();
+        region.dimension.replace_region_file(region.kind, region.pos, || region_file.save())?;
+        report.bytes_after += fs::metadata(&region.path)?.len();
+        Ok(())
+    })
+}
+
+/// Returns whether the chunk was recompressed, which it isn't if it's missing or already has the right compression.
+fn recompress_chunk(region_file: &mut RegionFile, pos: ChunkPos, compression: ChunkCompression) -> io::Result<bool> {
+    let index = region::get_chunk_index(pos);
+    let (compression_id, payload) = match read_chunk_payload(region_file, pos)? {
+        Some(chunk) => chunk,
+        None => return Ok(false),
+    };
+    if compression_id == compression.id() {
+        return Ok(false);
+    }
+    let data = decompress_payload(compression_id, &payload)?;
+    let payload = compression::compress(compression, &data)?;
+    let timestamp = region_file.timestamp(index);
+    region_file.write_chunk(pos, compression.id(), &payload)?;
+    region_file.set_timestamp(index, timestamp);
+    Ok(true)
+}
+
+/// The compression type and the still compressed data of the chunk, which may be in an external chunk file.
+fn read_chunk_payload(region_file: &RegionFile, pos: ChunkPos) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let (compression_id, payload) = match region_file.chunk_payload(region::get_chunk_index(pos)) {
+        Some(chunk) => chunk,
+        None => return Ok(None),
+    };
+    let payload = if compression_id & region::EXTERNAL_FLAG != 0 {
+        fs::read(region_file.external_chunk_path(pos))?
+    } else {
+        payload.to_vec()
+    };
+    Ok(Some((compression_id & !region::EXTERNAL_FLAG, payload)))
+}
+
+fn decompress_payload(compression_id: u8, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    compression::decompress(compression_id, payload)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// A chunk that couldn't be converted, and was left at its old version.
+#[derive(Debug, Clone)]
+pub struct FailedChunk {
+    pub dimension: FName,
+    pub region: PathBuf,
+    pub chunk: ChunkPos,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ConversionReport {
+    pub regions: usize,
+    pub chunks: usize,
+    pub converted_chunks: usize,
+    pub failed_chunks: Vec<FailedChunk>,
+    /// Region files which couldn't be read at all, and files outside of region files which couldn't be converted.
+    pub unreadable_regions: Vec<(PathBuf, String)>,
+}
+
+impl fmt::Display for ConversionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Converted {} of {} chunks in {} region files, {} chunks failed to convert",
+            self.converted_chunks,
+            self.chunks,
+            self.regions,
+            self.failed_chunks.len(),
+        )?;
+        for (path, err) in &self.unreadable_regions {
+            write!(f, "\n{}: {}", path.display(), err)?;
+        }
+        for failed in &self.failed_chunks {
+            write!(f, "\n{} chunk {} in {}: {}", failed.dimension, failed.chunk, failed.region.display(), failed.error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Converts every chunk of the world to another data version on a worker, along with level.dat and the player files.
+/// Chunks that fail to convert are left as they were. Entities are moved between the terrain and the entities region
+/// files when converting across 1.17, and points of interest are left alone when converting to before 1.14. The world
+/// shouldn't have unsaved changes, and has to be reopened afterwards.
+pub fn convert_world(world: &WorldRef, target_version: u32) -> Arc<RegionToolProgress> {
+    let merge_entities = world.data_version() >= data_versions::V1_17 && target_version < data_versions::V1_17;
+    let mut kinds = vec![RegionKind::Terrain];
+    if !merge_entities {
+        kinds.push(RegionKind::Entities);
+    }
+    if target_version >= data_versions::V1_14 {
+        kinds.push(RegionKind::Poi);
+    }
+    run_region_tool_then(
+        world,
+        &kinds,
+        move |_, region, report: &mut ConversionReport| convert_region(region, target_version, merge_entities, report),
+        move |world, _report| {
+            let version_name = data_versions::get_version_name(target_version).unwrap_or_default();
+            world.write_converted_level_dat(target_version, version_name)?;
+            world.convert_players(target_version)
+        },
+    )
+}
+
+fn convert_region(region: &RegionLocation, target_version: u32, merge_entities: bool, report: &mut ConversionReport) -> io::Result<()> {
+    let (dimension, region_pos) = (&region.dimension, region.pos);
+    let mut region_file = RegionFile::open_or_create(region.path.clone())?;
+    let entities_path = region.sibling_path(RegionKind::Entities);
+    let merged_region_file = if merge_entities && region.kind == RegionKind::Terrain && entities_path.exists() {
+        Some(RegionFile::open_or_create(entities_path.clone())?)
+    } else {
+        None
+    };
+    let mut split_region_file = None;
+    report.regions += 1;
+    let failed_before = report.failed_chunks.len();
+    for index in 0..1024 {
+        if region_file.location(index) == (0, 0) {
+            continue;
+        }
+        report.chunks += 1;
+        let pos = chunk_pos_from_index(region_pos, index);
+        match convert_chunk(region, &mut region_file, merged_region_file.as_ref(), &mut split_region_file, pos, target_version) {
+            Ok(()) => report.converted_chunks += 1,
+            Err(err) => report.failed_chunks.push(FailedChunk {
+                dimension: dimension.id.clone(),
+                region: region.path.clone(),
+                chunk: pos,
+                error: err.to_string(),
+            }),
+        }
+    }
+    dimension.replace_region_file(region.kind, region_pos, || region_file.save())?;
+    if let Some(split_region_file) = split_region_file {
+        dimension.replace_region_file(RegionKind::Entities, region_pos, || split_region_file.save())?;
+    }
+    // the entities are in the terrain chunks now, unless some of them failed to convert
+    if let Some(mut merged_region_file) = merged_region_file {
+        if report.failed_chunks.len() == failed_before {
+            for index in 0..1024 {
+                merged_region_file.remove_chunk(chunk_pos_from_index(region_pos, index));
+            }
+            dimension.replace_region_file(RegionKind::Entities, region_pos, || {
+                // deletes the external chunk files too
+                merged_region_file.save()?;
+                fs::remove_file(&entities_path)
+            })?;
+        }
+    }
+    Ok(())
+}
+
+fn convert_chunk(
+    region: &RegionLocation,
+    region_file: &mut RegionFile,
+    merged_region_file: Option<&RegionFile>,
+    split_region_file: &mut Option<RegionFile>,
+    pos: ChunkPos,
+    target_version: u32,
+) -> io::Result<()> {
+    let index = region::get_chunk_index(pos);
+    let (compression_id, payload) = match read_chunk_payload(region_file, pos)? {
+        Some(chunk) => chunk,
+        None => return Ok(()),
+    };
+    let compression = ChunkCompression::from_id(compression_id)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Unknown compression type {}", compression_id)))?;
+    let nbt_data = decompress_payload(compression_id, &payload)?;
+    let entities_nbt = match merged_region_file {
+        Some(merged_region_file) => read_chunk_payload(merged_region_file, pos)?
+            .map(|(compression_id, payload)| decompress_payload(compression_id, &payload))
+            .transpose()?,
+        None => None,
+    };
+    let (nbt_data, split_entities_nbt) = region.dimension.convert_chunk_nbt(region.kind, pos, &nbt_data, target_version, entities_nbt.as_deref())?;
+    let timestamp = region_file.timestamp(index);
+    region_file.write_chunk(pos, compression.id(), &compression::compress(compression, &nbt_data)?)?;
+    region_file.set_timestamp(index, timestamp);
+    if let Some(entities_nbt) = split_entities_nbt {
+        if split_region_file.is_none() {
+            *split_region_file = Some(RegionFile::open_or_create(region.sibling_path(RegionKind::Entities))?);
+        }
+        let split_region_file = split_region_file.as_mut().unwrap();
+        split_region_file.write_chunk(pos, compression.id(), &compression::compress(compression, &entities_nbt)?)?;
+        split_region_file.set_timestamp(index, timestamp);
+    }
+    Ok(())
+}
+
+/// A rectangle or circle of blocks in a dimension, or in every dimension if `dimension` is `None`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeepArea {
+    pub dimension: Option<FName>,
+    pub shape: AreaShape,
+}
+
+/// In block coordinates, where `y` is the z coordinate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AreaShape {
+    Rect { min: IVec2, max: IVec2 },
+    Circle { center: IVec2, radius: i32 },
+}
+
+impl KeepArea {
+    /// Whether any part of the chunk is in the area.
+    fn contains_chunk(&self, dimension: &FName, pos: ChunkPos) -> bool {
+        if self.dimension.as_ref().map_or(false, |area_dimension| area_dimension != dimension) {
+            return false;
+        }
+        let (chunk_min, chunk_max) = (pos << 4i8, (pos << 4i8) + 15);
+        match self.shape {
+            AreaShape::Rect { min, max } => chunk_max.cmpge(min).all() && chunk_min.cmple(max).all(),
+            AreaShape::Circle { center, radius } => {
+                let delta = center.clamp(chunk_min, chunk_max) - center;
+                let (dx, dz) = (delta.x as i64, delta.y as i64);
+                dx * dx + dz * dz <= radius as i64 * radius as i64
+            }
+        }
+    }
+}
+
+/// The chunk statuses in the order the game generates them.
+pub const STATUS_ORDER: [&str; 14] = [
+    "empty",
+    "structure_starts",
+    "structure_references",
+    "biomes",
+    "noise",
+    "surface",
+    "carvers",
+    "liquid_carvers",
+    "features",
+    "initialize_light",
+    "light",
+    "spawn",
+    "heightmaps",
+    "full",
+];
+
+fn get_status_order(status: &str) -> Option<usize> {
+    let status = status.strip_prefix("minecraft:").unwrap_or(status);
+    // the 1.13 names
+    let status = match status {
+        "base" => "noise",
+        "carved" => "carvers",
+        "liquid_carved" => "liquid_carvers",
+        "decorated" => "features",
+        "lighted" => "light",
+        "mobs_spawned" => "spawn",
+        "finalized" => "heightmaps",
+        "fullchunk" | "postprocessed" => "full",
+        status => status,
+    };
+    STATUS_ORDER.iter().position(|s| *s == status)
+}
+
+/// Which chunks to remove when pruning. A chunk is removed if it matches any of the filters.
+#[derive(Debug, Clone, Default)]
+pub struct PruneFilter {
+    /// Removes chunks players have spent fewer ticks than this near.
+    pub min_inhabited_time: Option<i64>,
+    /// Removes chunks outside all of these areas, unless it's empty.
+    pub keep_areas: Vec<KeepArea>,
+    /// Removes chunks that weren't generated as far as this status, e.g. `full`.
+    pub min_status: Option<String>,
+}
+
+impl PruneFilter {
+    fn should_remove(&self, dimension: &FName, pos: ChunkPos, info: &ChunkInfo) -> bool {
+        if let Some(min_inhabited_time) = self.min_inhabited_time {
+            if info.inhabited_time.unwrap_or(0) < min_inhabited_time {
+                return true;
+            }
+        }
+        if !self.keep_areas.is_empty() && !self.keep_areas.iter().any(|area| area.contains_chunk(dimension, pos)) {
+            return true;
+        }
+        if let Some(min_status) = self.min_status.as_deref().and_then(get_status_order) {
+            // chunks with unknown statuses are kept
+            if let Some(status) = info.status.as_deref().and_then(get_status_order) {
+                if status < min_status {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PruneReport {
+    pub regions: usize,
+    pub chunks: usize,
+    pub removed_chunks: usize,
+    /// Region files that were deleted because all their chunks were removed.
+    pub removed_regions: usize,
+    /// Chunks which couldn't be read, and were kept.
+    pub skipped_chunks: usize,
+    pub unreadable_regions: Vec<(PathBuf, String)>,
+}
+
+impl fmt::Display for PruneReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Removed {} of {} chunks and {} of {} region files",
+            self.removed_chunks,
+            self.chunks,
+            self.removed_regions,
+            self.regions,
+        )?;
+        if self.skipped_chunks != 0 {
+            write!(f, "\nKept {} unreadable chunks", self.skipped_chunks)?;
+        }
+        for (path, err) in &self.unreadable_regions {
+            write!(f, "\n{}: {}", path.display(), err)?;
+        }
+        Ok(())
+    }
+}
+
+/// Removes the chunks matching the filter from every region file of the world on a worker, deleting region files
+/// that end up empty. The entities and points of interest of removed chunks are removed too. The world shouldn't have
+/// unsaved changes.
+pub fn prune_chunks(world: &WorldRef, filter: PruneFilter) -> Arc<RegionToolProgress> {
+    run_region_tool(world, &[RegionKind::Terrain], move |world, region, report: &mut PruneReport| {
+        let (dimension, region_pos, path) = (&region.dimension, region.pos, &region.path);
+        let mut region_file = RegionFile::open_or_create(path.clone())?;
+        report.regions += 1;
+        let mut removed_chunks = Vec::new();
+        let mut remaining_chunks = 0;
+        for index in 0..1024 {
+            let (offset, count) = region_file.location(index);
+            if offset == 0 && count == 0 {
+                continue;
+            }
+            report.chunks += 1;
+            let pos = chunk_pos_from_index(region_pos, index);
+            match read_chunk_info(&region_file, pos, offset, count) {
+                Ok(info) if filter.should_remove(&dimension.id, pos, &info) => removed_chunks.push(pos),
+                Ok(_) => remaining_chunks += 1,
+                Err(_) => {
+                    report.skipped_chunks += 1;
+                    remaining_chunks += 1;
+                }
+            }
+        }
+        if removed_chunks.is_empty() {
+            return Ok(());
+        }
+        for pos in &removed_chunks {
+            region_file.remove_chunk(*pos);
+        }
+        dimension.replace_region_file(RegionKind::Terrain, region_pos, || {
+            // deletes the external chunk files of the removed chunks too
+            region_file.save()?;
+            if remaining_chunks == 0 {
+                fs::remove_file(path)?;
+            }
+            Ok(())
+        })?;
+        if remaining_chunks == 0 {
+            report.removed_regions += 1;
+        }
+        for kind in [RegionKind::Entities, RegionKind::Poi] {
+            let other_path = region.sibling_path(kind);
+            if other_path.exists() {
+                let mut other_region_file = RegionFile::open_or_create(other_path.clone())?;
+                for pos in &removed_chunks {
+                    other_region_file.remove_chunk(*pos);
+                }
+                dimension.replace_region_file(kind, region_pos, || {
+                    other_region_file.save()?;
+                    // entities and points of interest without terrain would never be loaded
+                    if remaining_chunks == 0 {
+                        fs::remove_file(&other_path)?;
+                    }
+                    Ok(())
+                })?;
+            }
+        }
+        for pos in &removed_chunks {
+            dimension.chunks.remove(pos);
+            dimension.chunk_existence_cache.insert(*pos, false);
+            // removed chunks aren't loaded again, which is what usually rebuilds their geometry and that of their
+            // neighbors
+            for delta in (-IVec2::ONE..=IVec2::ONE).iter() {
+                world.renderer.mark_chunk_for_redraw(&dimension.id, *pos + delta);
+            }
+        }
+        report.removed_chunks += removed_chunks.len();
+        Ok(())
+    })
+}
+
+/// A region file found in the folder of a dimension.
+struct RegionLocation {
+    dimension: Arc<Dimension>,
+    kind: RegionKind,
+    format: RegionFormat,
+    pos: IVec2,
+    path: PathBuf,
+}
+
+impl RegionLocation {
+    /// The region file of another kind at the same position.
+    fn sibling_path(&self, kind: RegionKind) -> PathBuf {
+        let dimension_dir = self.path.parent().and_then(Path::parent).unwrap_or_else(|| Path::new(""));
+        dimension_dir.join(kind.dir_name()).join(region::get_region_file_name(self.pos, self.format))
+    }
+}
+
+fn run_region_tool<R, F>(world: &WorldRef, kinds: &[RegionKind], process_region: F) -> Arc<RegionToolProgress>
+    where
+        R: fmt::Display + Default + UnreadableRegions,
+        F: FnMut(&World, &RegionLocation, &mut R) -> io::Result<()> + Send + 'static,
+{
+    run_region_tool_then(world, kinds, process_region, |_, _| Ok(()))
+}
+
+/// Like `run_region_tool`, and calls `finish` once all region files were processed, unless it was cancelled.
+fn run_region_tool_then<R, F, G>(world: &WorldRef, kinds: &[RegionKind], mut process_region: F, finish: G) -> Arc<RegionToolProgress>
+    where
+        R: fmt::Display + Default + UnreadableRegions,
+        F: FnMut(&World, &RegionLocation, &mut R) -> io::Result<()> + Send + 'static,
+        G: FnOnce(&World, &mut R) -> io::Result<()> + Send + 'static,
+{
+    let kinds = kinds.to_vec();
+    let progress = Arc::new(RegionToolProgress::default());
+    let worker_progress = progress.clone();
+    world.region_tool_running.store(true, Ordering::Release);
+    world.spawn_worker(move |world, stop| {
+        let _running = RegionToolRunning(&world);
+        let _region_files = world.lock_region_files();
+        let progress = worker_progress;
+        let regions = list_regions(&world, &kinds);
+        progress.total_regions.store(regions.len(), Ordering::Relaxed);
+        let mut report = R::default();
+        let mut cancelled = false;
+        for region in regions {
+            if stop() || progress.cancelled.load(Ordering::Relaxed) {
+                cancelled = true;
+                break;
+            }
+            if let Err(err) = process_region(&world, &region, &mut report) {
+                report.add_unreadable_region(region.path, err.to_string());
+            }
+            progress.done_regions.fetch_add(1, Ordering::Relaxed);
+        }
+        if !cancelled {
+            if let Err(err) = finish(&world, &mut report) {
+                report.add_unreadable_region(world.path.clone(), err.to_string());
+            }
+        }
+        let report = report.to_string();
+        info!("{}", report);
+        *progress.report.lock().unwrap() = Some(report);
+        progress.finished.store(true, Ordering::Release);
+    });
+    progress
+}
+
+/// Clears the world's `region_tool_running` flag when dropped, also when the region tool panics.
+struct RegionToolRunning<'a>(&'a World);
+
+impl Drop for RegionToolRunning<'_> {
+    fn drop(&mut self) {
+        self.0.region_tool_running.store(false, Ordering::Release);
+    }
+}
+
+trait UnreadableRegions {
+    fn add_unreadable_region(&mut self, path: PathBuf, err: String);
+}
+
+impl UnreadableRegions for RegionReport {
+    fn add_unreadable_region(&mut self, path: PathBuf, err: String) {
+        self.unreadable_regions.push((path, err));
+    }
+}
+
+impl UnreadableRegions for CompactionReport {
+    fn add_unreadable_region(&mut self, path: PathBuf, err: String) {
+        self.unreadable_regions.push((path, err));
+    }
+}
+
+impl UnreadableRegions for PruneReport {
+    fn add_unreadable_region(&mut self, path: PathBuf, err: String) {
+        self.unreadable_regions.push((path, err));
+    }
+}
+
+impl UnreadableRegions for ConversionReport {
+    fn add_unreadable_region(&mut self, path: PathBuf, err: String) {
+        self.unreadable_regions.push((path, err));
+    }
+}
+
+fn list_regions(world: &World, kinds: &[RegionKind]) -> Vec<RegionLocation> {
+    let mut regions = Vec::new();
+    for dimension in world.dimensions() {
+        for &kind in kinds {
+            let entries = match fs::read_dir(dimension.get_save_dir(world).join(kind.dir_name())) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                if let Some(pos) = region::parse_region_file_name(&entry.file_name().to_string_lossy(), world.region_format) {
+                    regions.push(RegionLocation { dimension: dimension.clone(), kind, format: world.region_format, pos, path: entry.path() });
+                }
+            }
+        }
+    }
+    regions
+}
+
+fn chunk_pos_from_index(region_pos: IVec2, index: usize) -> ChunkPos {
+    region_pos * 32 + ChunkPos::new((index & 31) as i32, (index >> 5) as i32)
+}
+
+/// Returns the number of chunks in the region and their problems.
+fn check_region(region: &RegionLocation, repair: bool) -> io::Result<(usize, Vec<RegionProblem>)> {
+    let (dimension, region_pos) = (&region.dimension, region.pos);
+    let mut region_file = RegionFile::open_or_create(region.path.clone())?;
+    let sector_count = region_file.sector_count();
+    let mut chunk_count = 0;
+    let mut problems: Vec<(usize, ChunkProblem)> = Vec::new();
+    let mut valid = [false; 1024];
+    let mut sector_owners: Vec<Option<usize>> = vec![None; sector_count];
+    let mut overlaps: Vec<(usize, usize)> = Vec::new();
+    for index in 0..1024 {
+        let (offset, count) = region_file.location(index);
+        if offset == 0 && count == 0 {
+            continue;
+        }
+        chunk_count += 1;
+        if offset < region::HEADER_SECTORS || count == 0 || offset + count > sector_count {
+            problems.push((index, ChunkProblem::OutOfBounds { offset, count }));
+            continue;
+        }
+        for owner in &mut sector_owners[offset..offset + count] {
+            match owner {
+                Some(other) => {
+                    if !overlaps.contains(&(*other, index)) {
+                        overlaps.push((*other, index));
+                    }
+                }
+                None => *owner = Some(index),
+            }
+        }
+        match check_chunk(&region_file, chunk_pos_from_index(region_pos, index), offset, count) {
+            Ok(()) => valid[index] = true,
+            Err(problem) => problems.push((index, problem)),
+        }
+    }
+    for (first, second) in &overlaps {
+        problems.push((*second, ChunkProblem::Overlapping(chunk_pos_from_index(region_pos, *first))));
+    }
+
+    let mut fixes: [Option<ChunkFix>; 1024] = [None; 1024];
+    if repair && !problems.is_empty() {
+        // drop chunks first, so that their sectors are free for relocated chunks
+        for (index, problem) in &problems {
+            match problem {
+                ChunkProblem::Overlapping(_) => {}
+                ChunkProblem::WrongPosition(pos) => {
+                    let target_index = region::get_chunk_index(*pos);
+                    if *pos >> 5i8 == region_pos && region_file.location(target_index) == (0, 0) {
+                        fixes[*index] = Some(ChunkFix::Moved(*pos));
+                        region_file.move_chunk(chunk_pos_from_index(region_pos, *index), *pos)?;
+                    } else {
+                        fixes[*index] = Some(ChunkFix::Dropped);
+                        region_file.remove_chunk(chunk_pos_from_index(region_pos, *index));
+                    }
+                }
+                _ => {
+                    fixes[*index] = Some(ChunkFix::Dropped);
+                    region_file.remove_chunk(chunk_pos_from_index(region_pos, *index));
+                }
+            }
+        }
+        for (first, second) in &overlaps {
+            // if either of them is broken it was dropped above, and the other no longer overlaps anything
+            if valid[*first] && valid[*second] {
+                fixes[*second] = Some(ChunkFix::Relocated);
+                region_file.relocate_chunk(*second);
+            }
+        }
+        // the cached handle would keep pointing to the old file after it's replaced
+        dimension.replace_region_file(region.kind, region_pos, || region_file.save())?;
+        if region.kind == RegionKind::Terrain {
+            for index in 0..1024 {
+                dimension.chunk_existence_cache.remove(&chunk_pos_from_index(region_pos, index));
+            }
+        }
+    }
+
+    let problems = problems.into_iter().map(|(index, problem)| RegionProblem {
+        dimension: dimension.id.clone(),
+        chunk: chunk_pos_from_index(region_pos, index),
+        problem,
+        fix: fixes[index],
+    }).collect();
+    Ok((chunk_count, problems))
+}
+
+fn check_chunk(region_file: &RegionFile, pos: ChunkPos, offset: usize, count: usize) -> Result<(), ChunkProblem> {
+    let info = read_chunk_info(region_file, pos, offset, count)?;
+    let (x_pos, z_pos) = match info.position.as_deref() {
+        Some(&[x, z]) => (Some(x), Some(z)),
+        _ => (info.x_pos, info.z_pos),
+    };
+    if let (Some(x), Some(z)) = (x_pos, z_pos) {
+        if ChunkPos::new(x, z) != pos {
+            return Err(ChunkProblem::WrongPosition(ChunkPos::new(x, z)));
+        }
+    }
+    Ok(())
+}
+
+/// The parts of the chunk NBT the region tools look at, without decoding the rest of the chunk.
+#[derive(Deserialize)]
+struct ChunkInfo {
+    #[serde(rename = "xPos")]
+    x_pos: Option<i32>,
+    #[serde(rename = "zPos")]
+    z_pos: Option<i32>,
+    // entity chunks
+    #[serde(rename = "Position")]
+    position: Option<Vec<i32>>,
+    #[serde(rename = "Status")]
+    status: Option<String>,
+    #[serde(rename = "InhabitedTime")]
+    inhabited_time: Option<i64>,
+    // before 1.18
+    #[serde(rename = "Level")]
+    level: Option<Box<ChunkInfo>>,
+}
+
+fn read_chunk_info(region_file: &RegionFile, pos: ChunkPos, offset: usize, count: usize) -> Result<ChunkInfo, ChunkProblem> {
+    let sectors = region_file.sectors(offset, count).ok_or(ChunkProblem::Truncated)?;
+    if sectors.len() < 5 {
+        return Err(ChunkProblem::Truncated);
+    }
+    let length = BigEndian::read_i32(sectors);
+    let compression_id = sectors[4];
+    let external_data;
+    let data = if compression_id & region::EXTERNAL_FLAG != 0 {
+        external_data = fs::read(region_file.external_chunk_path(pos)).map_err(|_| ChunkProblem::MissingExternalFile)?;
+        &external_data[..]
+    } else {
+        if length <= 0 || length as usize - 1 > sectors.len() - 5 {
+            return Err(ChunkProblem::Truncated);
+        }
+        &sectors[5..4 + length as usize]
+    };
+    let compression_id = compression_id & !region::EXTERNAL_FLAG;
+    if ChunkCompression::from_id(compression_id).is_none() {
+        return Err(ChunkProblem::UnknownCompression(compression_id));
+    }
+
+    let read = compression::decompress(compression_id, data).map_err(|err| ChunkProblem::UndecodableNbt(err.to_string()))?;
+    let info = ChunkInfo::deserialize(&mut nbt::de::Decoder::new(read)).map_err(|err| ChunkProblem::UndecodableNbt(err.to_string()))?;
+    Ok(match info.level {
+        Some(level) => *level,
+        None => info,
+    })
+}
@@ -0,0 +1,928 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Cursor, Read, Write};
+use std::path::Path;
+use ahash::AHashMap;
+use flate2::{read, write};
+use glam::{IVec3, Vec3Swizzles};
+use log::warn;
+use rayon::prelude::*;
+use serde::Deserialize;
+use crate::{blocks, CommonFNames, convert};
+use crate::convert::{block_transforms, flattening, registries, VersionedSerde};
+use crate::convert::data_versions::V1_13;
+use crate::fname::FName;
+use crate::geom::{BlockPos, ChunkPos, Direction, IVec2RangeExtensions, IVec3RangeExtensions};
+use crate::world::{BlockEntity, BlockState, EditTransaction, Entity, IBlockState, Selection, World};
+use crate::world::io::*;
+
+/// A box of block states that isn't part of any world, as read from or written to a schematic file. Biomes are only
+/// kept when copying within the editor, schematic files are read and written without them. Entities are copied and
+/// kept in schematic files, but aren't placed when pasting.
+#[derive(Debug, Clone)]
+pub struct Schematic {
+    size: IVec3,
+    palette: Vec<IBlockState>,
+    palette_lookup: AHashMap<IBlockState, u32>,
+    // indexed by x + z * width + y * width * length, the same as the Sponge format
+    blocks: Vec<u32>,
+    // same indexing as blocks, or empty if there are no biomes
+    biomes: Vec<Option<FName>>,
+    // keyed by position relative to the schematic
+    block_entities: AHashMap<BlockPos, BlockEntity>,
+    // with positions relative to the schematic
+    entities: Vec<Entity>,
+    /// Added to each position before it's transformed and placed, e.g. the Sponge schematic offset.
+    pub offset: IVec3,
+}
+
+impl Schematic {
+    pub fn new(size: IVec3) -> io::Result<Self> {
+        // positions are turned into indices as i32
+        let volume = (size.x as usize).checked_mul(size.y as usize).and_then(|volume| volume.checked_mul(size.z as usize));
+        let volume = match volume {
+            Some(volume) if size.cmpge(IVec3::ZERO).all() && volume <= i32::MAX as usize => volume,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid schematic size {}", size))),
+        };
+        let air = IBlockState::new(BlockState::new(&CommonFNames.AIR));
+        let mut palette_lookup = AHashMap::new();
+        palette_lookup.insert(air.clone(), 0);
+        Ok(Schematic {
+            size,
+            palette: vec![air],
+            palette_lookup,
+            blocks: vec![0; volume],
+            biomes: Vec::new(),
+            block_entities: AHashMap::new(),
+            entities: Vec::new(),
+            offset: IVec3::ZERO,
+        })
+    }
+
+    pub fn size(&self) -> IVec3 {
+        self.size
+    }
+
+    fn index(&self, pos: BlockPos) -> usize {
+        (pos.x + pos.z * self.size.x + pos.y * self.size.x * self.size.z) as usize
+    }
+
+    fn pos_from_index(&self, index: usize) -> BlockPos {
+        let index = index as i32;
+        BlockPos::new(index % self.size.x, index / (self.size.x * self.size.z), (index / self.size.x) % self.size.z)
+    }
+
+    pub fn get_block_state(&self, pos: BlockPos) -> &IBlockState {
+        &self.palette[self.blocks[self.index(pos)] as usize]
+    }
+
+    pub fn set_block_state(&mut self, pos: BlockPos, state: &IBlockState) {
+        let id = match self.palette_lookup.get(state) {
+            Some(id) => *id,
+            None => {
+                let id = self.palette.len() as u32;
+                self.palette.push(state.clone());
+                self.palette_lookup.insert(state.clone(), id);
+                id
+            }
+        };
+        let index = self.index(pos);
+        self.blocks[index] = id;
+    }
+
+    pub fn get_biome(&self, pos: BlockPos) -> Option<&FName> {
+        self.biomes.get(self.index(pos))?.as_ref()
+    }
+
+    pub fn set_biome(&mut self, pos: BlockPos, biome: Option<FName>) {
+        if self.biomes.is_empty() {
+            if biome.is_none() {
+                return;
+            }
+            self.biomes = vec![None; self.blocks.len()];
+        }
+        let index = self.index(pos);
+        self.biomes[index] = biome;
+    }
+
+    pub fn get_block_entity(&self, pos: BlockPos) -> Option<&BlockEntity> {
+        self.block_entities.get(&pos)
+    }
+
+    pub fn set_block_entity(&mut self, pos: BlockPos, block_entity: Option<BlockEntity>) {
+        match block_entity {
+            Some(mut block_entity) => {
+                block_entity.pos = pos;
+                self.block_entities.insert(pos, block_entity)
+            }
+            None => self.block_entities.remove(&pos),
+        };
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Iterates over the non-air blocks, excluding structure voids, with their positions relative to the schematic.
+    pub fn iter_solid_blocks(&self) -> impl Iterator<Item = (BlockPos, &IBlockState)> + '_ {
+        self.blocks.iter().enumerate().filter_map(move |(index, id)| {
+            let state = &self.palette[*id as usize];
+            if state.block == CommonFNames.STRUCTURE_VOID || blocks::is_air(state) {
+                None
+            } else {
+                Some((self.pos_from_index(index), state))
+            }
+        })
+    }
+
+    /// Renames the block states and biomes from one data version to another, for pasting into a world of a different
+    /// version.
+    pub fn rename(&mut self, from_version: u32, to_version: u32) {
+        if from_version == to_version {
+            return;
+        }
+        let old_palette = std::mem::take(&mut self.palette);
+        self.palette_lookup.clear();
+        let ids: Vec<u32> = old_palette.iter().map(|state| {
+            let state = registries::rename_block_state(state, from_version, to_version);
+            match self.palette_lookup.get(&state) {
+                Some(id) => *id,
+                None => {
+                    let id = self.palette.len() as u32;
+                    self.palette.push(state.clone());
+                    self.palette_lookup.insert(state, id);
+                    id
+                }
+            }
+        }).collect();
+        for id in &mut self.blocks {
+            *id = ids[*id as usize];
+        }
+        for biome in self.biomes.iter_mut().flatten() {
+            *biome = registries::rename_biome(biome, from_version, to_version);
+        }
+    }
+
+    /// Copies the blocks, biomes, block entities and entities in the selection, with the minimum corner at the origin of
+    /// the schematic. Blocks in chunks that haven't been generated are copied as air.
+    pub fn from_selection(world: &World, selection: &Selection) -> io::Result<Self> {
+        let dimension = world.get_dimension(&selection.dimension)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Dimension {} doesn't exist", selection.dimension.to_nice_string())))?;
+        let (min, max) = (selection.min(), selection.max());
+        let mut schematic = Schematic::new(selection.size())?;
+        for chunk_pos in ((min.xz() >> 4i8)..=(max.xz() >> 4i8)).iter() {
+            let chunk = match dimension.load_chunk(world, chunk_pos) {
+                Some(chunk) => chunk,
+                None => continue,
+            };
+            let chunk_origin = BlockPos::new(chunk_pos.x << 4, 0, chunk_pos.y << 4);
+            let chunk_min = min.max(BlockPos::new(chunk_origin.x, min.y.max(dimension.min_y), chunk_origin.z));
+            let chunk_max = max.min(BlockPos::new(chunk_origin.x + 15, max.y.min(dimension.max_y), chunk_origin.z + 15));
+            for pos in (chunk_min..=chunk_max).iter() {
+                if let Some(state) = chunk.get_block_state(&dimension, pos - chunk_origin) {
+                    schematic.set_block_state(pos - min, &state);
+                }
+                schematic.set_biome(pos - min, chunk.get_biome(&dimension, pos - chunk_origin));
+            }
+            for (pos, block_entity) in chunk.block_entities.read().unwrap().iter() {
+                if pos.cmpge(chunk_min).all() && pos.cmple(chunk_max).all() {
+                    let mut block_entity = block_entity.clone();
+                    block_entity.pos = *pos - min;
+                    schematic.block_entities.insert(block_entity.pos, block_entity);
+                }
+            }
+            let (entity_min, entity_max) = (chunk_min.as_dvec3(), (chunk_max + IVec3::ONE).as_dvec3());
+            for mut entity in chunk.entities() {
+                if entity.pos.cmpge(entity_min).all() && entity.pos.cmplt(entity_max).all() {
+                    entity.pos -= min.as_dvec3();
+                    schematic.entities.push(entity);
+                }
+            }
+        }
+        Ok(schematic)
+    }
+
+    /// Places the schematic into the transaction's dimension, transforming it around `origin`. Structure voids are
+    /// always skipped, and air is skipped unless `paste_air` is set. Biomes and block entities are placed along with the
+    /// blocks they belong to. Returns the number of blocks changed.
+    pub fn place(&self, world: &World, transaction: &EditTransaction, origin: BlockPos, transform: Transform, paste_air: bool) -> u64 {
+        let dimension = transaction.dimension();
+        let palette: Vec<Option<IBlockState>> = self.palette.iter().map(|state| {
+            if state.block == CommonFNames.STRUCTURE_VOID || (!paste_air && blocks::is_air(state)) {
+                None
+            } else {
+                Some(transform.transform_block_state(state))
+            }
+        }).collect();
+        let mut blocks_by_chunk: AHashMap<ChunkPos, Vec<PlacedBlock>> = AHashMap::new();
+        for (index, id) in self.blocks.iter().enumerate() {
+            if let Some(state) = &palette[*id as usize] {
+                let rel_pos = self.pos_from_index(index);
+                let pos = origin + transform.transform_pos(self.offset + rel_pos);
+                if pos.y >= dimension.min_y && pos.y <= dimension.max_y {
+                    blocks_by_chunk.entry(pos.xz() >> 4i8).or_default().push(PlacedBlock {
+                        pos,
+                        state: state.clone(),
+                        biome: self.biomes.get(index).cloned().flatten(),
+                        block_entity: self.block_entities.get(&rel_pos).cloned(),
+                    });
+                }
+            }
+        }
+        blocks_by_chunk.into_iter().collect::<Vec<_>>().into_par_iter().map(|(chunk_pos, blocks)| {
+            place_in_chunk(world, transaction, chunk_pos, blocks)
+        }).sum()
+    }
+}
+
+struct PlacedBlock {
+    pos: BlockPos,
+    state: IBlockState,
+    biome: Option<FName>,
+    block_entity: Option<BlockEntity>,
+}
+
+fn place_in_chunk(world: &World, transaction: &EditTransaction, chunk_pos: ChunkPos, blocks: Vec<PlacedBlock>) -> u64 {
+    let dimension = transaction.dimension();
+    if dimension.load_chunk(world, chunk_pos).is_none() {
+        warn!("Not placing {} blocks in chunk {} because it hasn't been generated", blocks.len(), chunk_pos);
+        return 0;
+    }
+    let mut changed_blocks = 0;
+    for block in blocks {
+        let old_state = match transaction.set_block_state(block.pos, &block.state) {
+            Some(old_state) => old_state,
+            // the chunk was unloaded before our first change marked it as dirty
+            None => match dimension.load_chunk(world, chunk_pos).and_then(|_| transaction.set_block_state(block.pos, &block.state)) {
+                Some(old_state) => old_state,
+                None => {
+                    warn!("Chunk {} was unloaded while it was being edited", chunk_pos);
+                    break;
+                }
+            },
+        };
+        if old_state != block.state {
+            changed_blocks += 1;
+        }
+        if let Some(biome) = &block.biome {
+            transaction.set_biome(block.pos, biome);
+        }
+        if block.block_entity.is_some() {
+            transaction.set_block_entity(block.pos, block.block_entity);
+        }
+    }
+    changed_blocks
+}
+
+/// A rotation around the Y axis, optionally preceded by a mirror, applied to positions and to the properties of block
+/// states that depend on direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Transform {
+    /// The number of quarter turns clockwise when viewed from above.
+    pub rotation: u8,
+    /// Whether to negate X before rotating.
+    pub mirror: bool,
+}
+
+impl Transform {
+    pub fn is_identity(&self) -> bool {
+        self.rotation % 4 == 0 && !self.mirror
+    }
+
+    pub fn transform_pos(&self, mut pos: BlockPos) -> BlockPos {
+        if self.mirror {
+            pos.x = -pos.x;
+        }
+        for _ in 0..self.rotation % 4 {
+            pos = BlockPos::new(-pos.z, pos.y, pos.x);
+        }
+        pos
+    }
+
+    pub fn transform_direction(&self, dir: Direction) -> Direction {
+        block_transforms::transform_direction(dir, self.rotation, self.mirror)
+    }
+
+    pub fn transform_block_state(&self, state: &IBlockState) -> IBlockState {
+        block_transforms::transform_block_state(state, self.rotation, self.mirror)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchematicFormat {
+    SpongeV2,
+    SpongeV3,
+    Structure,
+}
+
+impl SchematicFormat {
+    pub const ALL: [SchematicFormat; 3] = [
+        SchematicFormat::SpongeV2,
+        SchematicFormat::SpongeV3,
+        SchematicFormat::Structure,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SchematicFormat::SpongeV2 => "Sponge schematic v2",
+            SchematicFormat::SpongeV3 => "Sponge schematic v3",
+            SchematicFormat::Structure => "Structure block",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            SchematicFormat::SpongeV2 | SchematicFormat::SpongeV3 => "schem",
+            SchematicFormat::Structure => "nbt",
+        }
+    }
+}
+
+/// The extensions of all formats that can be read.
+pub const SCHEMATIC_EXTENSIONS: [&str; 3] = ["schem", "nbt", "schematic"];
+
+/// Reads a schematic, upgrading its block states from the data version it was saved with to `world_version`. The
+/// format is chosen by extension, Sponge versions are told apart by their contents. MCEdit `.schematic` files with
+/// numeric block IDs are flattened.
+pub fn read_schematic(path: &Path, world_version: u32) -> io::Result<Schematic> {
+    let mut data = Vec::new();
+    read::GzDecoder::new(File::open(path)?).read_to_end(&mut data)?;
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("").to_ascii_lowercase();
+    match extension.as_str() {
+        "schem" => read_sponge_schematic(&data, world_version),
+        "nbt" => read_structure(&data, world_version),
+        "schematic" => read_legacy_schematic(&data, world_version),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown schematic extension \"{}\"", extension))),
+    }
+}
+
+pub fn write_schematic(path: &Path, schematic: &Schematic, format: SchematicFormat, world_version: u32) -> io::Result<()> {
+    let mut encoder = write::GzEncoder::new(BufWriter::new(File::create(path)?), flate2::Compression::default());
+    match format {
+        SchematicFormat::SpongeV2 => write_sponge_schematic_v2(&mut encoder, schematic, world_version)?,
+        SchematicFormat::SpongeV3 => write_sponge_schematic_v3(&mut encoder, schematic, world_version)?,
+        SchematicFormat::Structure => write_structure(&mut encoder, schematic, world_version)?,
+    }
+    encoder.finish()?.flush()
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn parse_palette(palette: &BTreeMap<String, i32>) -> io::Result<Vec<IBlockState>> {
+    let mut result = vec![IBlockState::new(BlockState::new(&CommonFNames.AIR)); palette.len()];
+    for (state, id) in palette {
+        let state: BlockState = state.parse().map_err(|err| invalid_data(format!("Invalid block state \"{}\": {}", state, err)))?;
+        match result.get_mut(*id as usize) {
+            Some(entry) => *entry = IBlockState::new(state),
+            None => return Err(invalid_data(format!("Palette index {} out of bounds", id))),
+        }
+    }
+    Ok(result)
+}
+
+fn get_pos(value: Option<nbt::Value>) -> Option<BlockPos> {
+    match value? {
+        nbt::Value::IntArray(pos) if pos.len() == 3 => Some(BlockPos::new(pos[0], pos[1], pos[2])),
+        nbt::Value::List(pos) => match pos[..] {
+            [nbt::Value::Int(x), nbt::Value::Int(y), nbt::Value::Int(z)] => Some(BlockPos::new(x, y, z)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Reads a Sponge block entity, whose data is next to its position and ID in v2 and in `Data` in v3.
+fn read_sponge_block_entity(mut nbt: BTreeMap<String, nbt::Value>, data_version: u32, world_version: u32) -> io::Result<BlockEntity> {
+    let pos = get_pos(nbt.remove("Pos")).ok_or_else(|| invalid_data("Block entity has an invalid position"))?;
+    if let Some(nbt::Value::Compound(data)) = nbt.remove("Data") {
+        nbt.extend(data);
+    }
+    let id = nbt.remove("Id").ok_or_else(|| invalid_data("Block entity is missing its ID"))?;
+    nbt.insert("id".to_owned(), id);
+    nbt.insert("x".to_owned(), nbt::Value::Int(pos.x));
+    nbt.insert("y".to_owned(), nbt::Value::Int(pos.y));
+    nbt.insert("z".to_owned(), nbt::Value::Int(pos.z));
+    block_entity_from_nbt(&nbt, data_version, world_version)
+}
+
+fn write_sponge_block_entity(block_entity: &BlockEntity, world_version: u32, nest_data: bool) -> io::Result<BTreeMap<String, nbt::Value>> {
+    let mut data = block_entity_to_nbt(block_entity, world_version)?;
+    for key in ["id", "x", "y", "z"] {
+        data.remove(key);
+    }
+    let mut nbt = if nest_data {
+        BTreeMap::from([("Data".to_owned(), nbt::Value::Compound(data.into_iter().collect()))])
+    } else {
+        data
+    };
+    nbt.insert("Id".to_owned(), nbt::Value::String(block_entity.id.to_string()));
+    nbt.insert("Pos".to_owned(), nbt::Value::IntArray(block_entity.pos.to_array().to_vec()));
+    Ok(nbt)
+}
+
+/// Reads a Sponge entity, whose data is next to its position and ID in v2 and in `Data` in v3.
+fn read_sponge_entity(mut nbt: BTreeMap<String, nbt::Value>, data_version: u32, world_version: u32) -> io::Result<Entity> {
+    let pos = nbt.remove("Pos");
+    if let Some(nbt::Value::Compound(data)) = nbt.remove("Data") {
+        nbt.extend(data);
+    }
+    let id = nbt.remove("Id").ok_or_else(|| invalid_data("Entity is missing its ID"))?;
+    nbt.insert("id".to_owned(), id);
+    if let Some(pos) = pos {
+        nbt.insert("Pos".to_owned(), pos);
+    }
+    entity_from_nbt(&nbt, data_version, world_version)
+}
+
+fn write_sponge_entity(entity: &Entity, world_version: u32, nest_data: bool) -> io::Result<BTreeMap<String, nbt::Value>> {
+    let mut data = entity_to_nbt(entity, world_version)?;
+    data.remove("id");
+    let pos = data.remove("Pos").unwrap_or_else(|| nbt::Value::List(entity.pos.to_array().map(nbt::Value::Double).to_vec()));
+    let mut nbt = if nest_data {
+        BTreeMap::from([("Data".to_owned(), nbt::Value::Compound(data.into_iter().collect()))])
+    } else {
+        data
+    };
+    nbt.insert("Id".to_owned(), nbt::Value::String(entity.id.to_string()));
+    nbt.insert("Pos".to_owned(), pos);
+    Ok(nbt)
+}
+
+fn read_sponge_schematic(data: &[u8], world_version: u32) -> io::Result<Schematic> {
+    #[derive(Deserialize)]
+    struct SpongeVersionExtractor {
+        #[serde(rename = "Version")]
+        version: Option<i32>,
+        #[serde(rename = "DataVersion")]
+        data_version: Option<u32>,
+        #[serde(rename = "Schematic")]
+        schematic: Option<Box<SpongeVersionExtractor>>,
+    }
+    let mut extractor = SpongeVersionExtractor::deserialize(&mut nbt::de::Decoder::new(Cursor::new(data)))?;
+    // v3 nests everything inside a compound called Schematic
+    let nested = extractor.schematic.is_some();
+    if let Some(schematic) = extractor.schematic {
+        extractor = *schematic;
+    }
+    let data_version = extractor.data_version.ok_or_else(|| invalid_data("Sponge schematic is missing its DataVersion"))?;
+    let (size, offset, palette, block_data, block_entities, entities) = match (extractor.version, nested) {
+        (Some(2), false) => {
+            let schematic: SpongeSchematicV2 = VersionedSerde::deserialize(data_version, world_version, &mut nbt::de::Decoder::new(Cursor::new(data)))?;
+            let size = IVec3::new(schematic.width as u16 as i32, schematic.height as u16 as i32, schematic.length as u16 as i32);
+            (size, schematic.offset, schematic.palette, schematic.block_data, schematic.block_entities, schematic.entities)
+        }
+        (Some(3), true) => {
+            let schematic: SpongeSchematicV3 = VersionedSerde::deserialize(data_version, world_version, &mut nbt::de::Decoder::new(Cursor::new(data)))?;
+            let schematic = schematic.schematic;
+            let size = IVec3::new(schematic.width as u16 as i32, schematic.height as u16 as i32, schematic.length as u16 as i32);
+            let blocks = schematic.blocks.unwrap_or_default();
+            (size, schematic.offset, blocks.palette, blocks.data, blocks.block_entities, schematic.entities)
+        }
+        (version, _) => return Err(invalid_data(format!("Unsupported Sponge schematic version {}", version.unwrap_or(1)))),
+    };
+
+    let mut schematic = Schematic::new(size)?;
+    if let [x, y, z] = offset[..] {
+        schematic.offset = IVec3::new(x, y, z);
+    }
+    let palette = parse_palette(&palette)?;
+    let ids = read_varints(&block_data, schematic.blocks.len())?;
+    for (index, id) in ids.into_iter().enumerate() {
+        let state = palette.get(id as usize).ok_or_else(|| invalid_data(format!("Block data refers to missing palette index {}", id)))?;
+        let pos = schematic.pos_from_index(index);
+        schematic.set_block_state(pos, state);
+    }
+    for block_entity in block_entities {
+        let block_entity = read_sponge_block_entity(block_entity, data_version, world_version)?;
+        schematic.block_entities.insert(block_entity.pos, block_entity);
+    }
+    for entity in entities {
+        schematic.entities.push(read_sponge_entity(entity, data_version, world_version)?);
+    }
+    schematic.rename(data_version, world_version);
+    Ok(schematic)
+}
+
+fn read_varints(data: &[i8], count: usize) -> io::Result<Vec<u32>> {
+    let mut result = Vec::with_capacity(count);
+    let mut value = 0u32;
+    let mut shift = 0;
+    for byte in data {
+        let byte = *byte as u8;
+        value |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 != 0 {
+            shift += 7;
+            if shift > 28 {
+                return Err(invalid_data("Varint in block data is too long"));
+            }
+        } else {
+            result.push(value);
+            value = 0;
+            shift = 0;
+        }
+    }
+    if result.len() != count {
+        return Err(invalid_data(format!("Expected {} blocks in block data, found {}", count, result.len())));
+    }
+    Ok(result)
+}
+
+fn write_varint(data: &mut Vec<i8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            data.push(byte as i8);
+            return;
+        }
+        data.push((byte | 0x80) as i8);
+    }
+}
+
+fn to_sponge_palette_and_data(schematic: &Schematic) -> (BTreeMap<String, i32>, Vec<i8>) {
+    let palette = schematic.palette.iter().enumerate().map(|(id, state)| (state.to_string(), id as i32)).collect();
+    let mut data = Vec::with_capacity(schematic.blocks.len());
+    for id in &schematic.blocks {
+        write_varint(&mut data, *id);
+    }
+    (palette, data)
+}
+
+fn check_sponge_size(schematic: &Schematic) -> io::Result<()> {
+    if schematic.size.cmpgt(IVec3::splat(u16::MAX as i32)).any() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Sponge schematics can be at most {} blocks along each axis", u16::MAX)));
+    }
+    Ok(())
+}
+
+fn write_sponge_schematic_v2(writer: &mut impl Write, schematic: &Schematic, world_version: u32) -> io::Result<()> {
+    check_sponge_size(schematic)?;
+    let (palette, block_data) = to_sponge_palette_and_data(schematic);
+    let block_entities = schematic.block_entities.values()
+        .map(|block_entity| write_sponge_block_entity(block_entity, world_version, false))
+        .collect::<io::Result<_>>()?;
+    let entities = schematic.entities.iter()
+        .map(|entity| write_sponge_entity(entity, world_version, false))
+        .collect::<io::Result<_>>()?;
+    let mut extra = BTreeMap::new();
+    extra.insert("DataVersion".to_owned(), nbt::Value::Int(world_version as i32));
+    let serialized = SpongeSchematicV2 {
+        version: 2,
+        width: schematic.size.x as u16 as i16,
+        height: schematic.size.y as u16 as i16,
+        length: schematic.size.z as u16 as i16,
+        offset: schematic.offset.to_array().to_vec(),
+        palette_max: palette.len() as i32,
+        palette,
+        block_data,
+        block_entities,
+        entities,
+        _extra: extra,
+    };
+    VersionedSerde::serialize(serialized, world_version, world_version, &mut nbt::ser::Encoder::new(writer, Some("Schematic")))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_sponge_schematic_v3(writer: &mut impl Write, schematic: &Schematic, world_version: u32) -> io::Result<()> {
+    check_sponge_size(schematic)?;
+    let (palette, data) = to_sponge_palette_and_data(schematic);
+    let block_entities = schematic.block_entities.values()
+        .map(|block_entity| write_sponge_block_entity(block_entity, world_version, true))
+        .collect::<io::Result<_>>()?;
+    let entities = schematic.entities.iter()
+        .map(|entity| write_sponge_entity(entity, world_version, true))
+        .collect::<io::Result<_>>()?;
+    let mut extra = BTreeMap::new();
+    extra.insert("DataVersion".to_owned(), nbt::Value::Int(world_version as i32));
+    let serialized = SpongeSchematicV3 {
+        schematic: SpongeSchematicV3Body {
+            version: 3,
+            width: schematic.size.x as u16 as i16,
+            height: schematic.size.y as u16 as i16,
+            length: schematic.size.z as u16 as i16,
+            offset: schematic.offset.to_array().to_vec(),
+            blocks: Some(SpongeBlockContainer {
+                palette,
+                data,
+                block_entities,
+                _extra: Default::default(),
+            }),
+            entities,
+            _extra: extra,
+        },
+        _extra: Default::default(),
+    };
+    VersionedSerde::serialize(serialized, world_version, world_version, &mut nbt::ser::Encoder::new(writer, None))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn read_structure(data: &[u8], world_version: u32) -> io::Result<Schematic> {
+    let data_version = convert::get_version(&mut nbt::de::Decoder::new(Cursor::new(data)))?;
+    let structure: SerializedStructure = VersionedSerde::deserialize(data_version, world_version, &mut nbt::de::Decoder::new(Cursor::new(data)))?;
+    let size = match structure.size[..] {
+        [x, y, z] => IVec3::new(x, y, z),
+        _ => return Err(invalid_data("Structure size must have 3 elements")),
+    };
+    // structures with several palettes, such as shipwrecks, pick one at random when placed, we use the first
+    let palette = if structure.palette.is_empty() {
+        structure.palettes.into_iter().next().unwrap_or_default()
+    } else {
+        structure.palette
+    };
+    let palette: Vec<_> = palette.iter().map(SerializedBlockState::to_block_state).collect();
+
+    let mut schematic = Schematic::new(size)?;
+    // blocks missing from the structure are left alone when placed
+    let structure_void = IBlockState::new(BlockState::new(&CommonFNames.STRUCTURE_VOID));
+    for pos in (IVec3::ZERO..size).iter() {
+        schematic.set_block_state(pos, &structure_void);
+    }
+    for block in structure.blocks {
+        let pos = match block.pos[..] {
+            [x, y, z] if IVec3::new(x, y, z).cmpge(IVec3::ZERO).all() && IVec3::new(x, y, z).cmplt(size).all() => IVec3::new(x, y, z),
+            _ => return Err(invalid_data(format!("Invalid block position {:?}", block.pos))),
+        };
+        let state = palette.get(block.state as usize).ok_or_else(|| invalid_data(format!("Block refers to missing palette index {}", block.state)))?;
+        schematic.set_block_state(pos, state);
+        if let Some(mut nbt) = block.nbt {
+            nbt.insert("x".to_owned(), nbt::Value::Int(pos.x));
+            nbt.insert("y".to_owned(), nbt::Value::Int(pos.y));
+            nbt.insert("z".to_owned(), nbt::Value::Int(pos.z));
+            schematic.block_entities.insert(pos, block_entity_from_nbt(&nbt, data_version, world_version)?);
+        }
+    }
+    for entity in structure.entities {
+        let mut nbt = entity.nbt;
+        // the position in the entity's NBT is where it was saved from, the structure's own position is relative
+        nbt.insert("Pos".to_owned(), nbt::Value::List(entity.pos.into_iter().map(nbt::Value::Double).collect()));
+        schematic.entities.push(entity_from_nbt(&nbt, data_version, world_version)?);
+    }
+    schematic.rename(data_version, world_version);
+    Ok(schematic)
+}
+
+fn read_legacy_schematic(data: &[u8], world_version: u32) -> io::Result<Schematic> {
+    #[derive(Deserialize)]
+    struct SerializedLegacySchematic {
+        #[serde(rename = "Width")]
+        width: i16,
+        #[serde(rename = "Height")]
+        height: i16,
+        #[serde(rename = "Length")]
+        length: i16,
+        #[serde(rename = "Materials")]
+        #[serde(default)]
+        materials: Option<String>,
+        #[serde(rename = "Blocks")]
+        blocks: Vec<i8>,
+        #[serde(rename = "Data")]
+        data: Vec<i8>,
+        // the upper 4 bits of block IDs above 255, two per byte
+        #[serde(rename = "AddBlocks")]
+        #[serde(default)]
+        add_blocks: Vec<i8>,
+        #[serde(rename = "WEOffsetX")]
+        #[serde(default)]
+        offset_x: i32,
+        #[serde(rename = "WEOffsetY")]
+        #[serde(default)]
+        offset_y: i32,
+        #[serde(rename = "WEOffsetZ")]
+        #[serde(default)]
+        offset_z: i32,
+    }
+    let legacy = SerializedLegacySchematic::deserialize(&mut nbt::de::Decoder::new(Cursor::new(data)))?;
+    if let Some(materials) = &legacy.materials {
+        if materials != "Alpha" {
+            return Err(invalid_data(format!("Unsupported schematic materials \"{}\"", materials)));
+        }
+    }
+    let size = IVec3::new(legacy.width as u16 as i32, legacy.height as u16 as i32, legacy.length as u16 as i32);
+    let mut schematic = Schematic::new(size)?;
+    let volume = schematic.blocks.len();
+    if legacy.blocks.len() != volume || legacy.data.len() != volume {
+        return Err(invalid_data(format!("Expected {} blocks, found {} blocks and {} data values", volume, legacy.blocks.len(), legacy.data.len())));
+    }
+
+    let mut unknown_ids = BTreeMap::new();
+    let air = IBlockState::new(BlockState::new(&CommonFNames.AIR));
+    let mut states: Vec<IBlockState> = (0..volume).map(|index| {
+        let mut id = legacy.blocks[index] as u8 as u16;
+        if let Some(add) = legacy.add_blocks.get(index >> 1) {
+            let add = *add as u8 as u16;
+            id |= if index & 1 == 0 { (add & 0x0f) << 8 } else { (add & 0xf0) << 4 };
+        }
+        let data = legacy.data[index] as u8;
+        flattening::flatten_block_state(id, data).unwrap_or_else(|| {
+            *unknown_ids.entry(id).or_insert(0) += 1;
+            air.clone()
+        })
+    }).collect();
+    for (id, count) in unknown_ids {
+        warn!("Replaced {} blocks with unknown ID {} with air", count, id);
+    }
+
+    schematic.offset = IVec3::new(legacy.offset_x, legacy.offset_y, legacy.offset_z);
+    let layer_size = (size.x * size.z) as usize;
+    for index in 0..volume.saturating_sub(layer_size) {
+        if let Some((lower, upper)) = flattening::fix_split_block(&states[index], &states[index + layer_size]) {
+            states[index] = lower;
+            states[index + layer_size] = upper;
+        }
+    }
+    for (index, state) in states.iter().enumerate() {
+        let pos = schematic.pos_from_index(index);
+        schematic.set_block_state(pos, &registries::rename_block_state(state, V1_13, world_version));
+    }
+    Ok(schematic)
+}
+
+fn write_structure(writer: &mut impl Write, schematic: &Schematic, world_version: u32) -> io::Result<()> {
+    const MAX_STRUCTURE_SIZE: i32 = 48;
+    if schematic.size.cmpgt(IVec3::splat(MAX_STRUCTURE_SIZE)).any() {
+        warn!("Structure blocks can only load structures up to {} blocks along each axis", MAX_STRUCTURE_SIZE);
+    }
+    let blocks = schematic.blocks.iter().enumerate()
+        .filter(|(_, id)| schematic.palette[**id as usize].block != CommonFNames.STRUCTURE_VOID)
+        .map(|(index, id)| {
+            let pos = schematic.pos_from_index(index);
+            let nbt = schematic.block_entities.get(&pos).map(|block_entity| {
+                let mut nbt = block_entity_to_nbt(block_entity, world_version)?;
+                for key in ["x", "y", "z"] {
+                    nbt.remove(key);
+                }
+                Ok::<_, io::Error>(nbt)
+            }).transpose()?;
+            Ok(SerializedStructureBlock {
+                pos: pos.to_array().to_vec(),
+                state: *id as i32,
+                nbt,
+                _extra: Default::default(),
+            })
+        })
+        .collect::<io::Result<_>>()?;
+    let entities = schematic.entities.iter().map(|entity| {
+        Ok(SerializedStructureEntity {
+            pos: entity.pos.to_array().to_vec(),
+            block_pos: entity.pos.floor().as_ivec3().to_array().to_vec(),
+            nbt: entity_to_nbt(entity, world_version)?,
+            _extra: Default::default(),
+        })
+    }).collect::<io::Result<_>>()?;
+    let mut extra = BTreeMap::new();
+    extra.insert("DataVersion".to_owned(), nbt::Value::Int(world_version as i32));
+    let serialized = SerializedStructure {
+        size: schematic.size.to_array().to_vec(),
+        palette: schematic.palette.iter().map(|state| SerializedBlockState::from_block_state(state)).collect(),
+        palettes: Vec::new(),
+        blocks,
+        entities,
+        _extra: extra,
+    };
+    VersionedSerde::serialize(serialized, world_version, world_version, &mut nbt::ser::Encoder::new(writer, None))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+convert::variants! {
+    struct SpongeSchematicV2 {
+        #[serde(rename = "Version")]
+        version: i32,
+        #[serde(rename = "Width")]
+        width: i16,
+        #[serde(rename = "Height")]
+        height: i16,
+        #[serde(rename = "Length")]
+        length: i16,
+        #[serde(default)]
+        #[serde(rename = "Offset", serialize_with = "nbt::i32_array")]
+        offset: Vec<i32>,
+        #[serde(rename = "PaletteMax")]
+        palette_max: i32,
+        #[serde(rename = "Palette")]
+        palette: BTreeMap<String, i32>,
+        #[serde(rename = "BlockData", serialize_with = "nbt::i8_array")]
+        block_data: Vec<i8>,
+        #[serde(rename = "BlockEntities")]
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        block_entities: Vec<BTreeMap<String, nbt::Value>>,
+        #[serde(rename = "Entities")]
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        entities: Vec<BTreeMap<String, nbt::Value>>,
+    }
+}
+
+convert::variants! {
+    struct SpongeSchematicV3 {
+        #[serde(rename = "Schematic")]
+        #[variants]
+        schematic: SpongeSchematicV3Body,
+    }
+}
+
+convert::variants! {
+    struct SpongeSchematicV3Body {
+        #[serde(rename = "Version")]
+        version: i32,
+        #[serde(rename = "Width")]
+        width: i16,
+        #[serde(rename = "Height")]
+        height: i16,
+        #[serde(rename = "Length")]
+        length: i16,
+        #[serde(default)]
+        #[serde(rename = "Offset", serialize_with = "nbt::i32_array")]
+        offset: Vec<i32>,
+        #[serde(default)]
+        #[serde(rename = "Blocks", skip_serializing_if = "Option::is_none")]
+        #[variants]
+        blocks: Option<SpongeBlockContainer>,
+        #[serde(rename = "Entities")]
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        entities: Vec<BTreeMap<String, nbt::Value>>,
+    }
+}
+
+convert::variants! {
+    #[derive(Default)]
+    struct SpongeBlockContainer {
+        #[serde(rename = "Palette")]
+        palette: BTreeMap<String, i32>,
+        #[serde(rename = "Data", serialize_with = "nbt::i8_array")]
+        data: Vec<i8>,
+        #[serde(rename = "BlockEntities")]
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        block_entities: Vec<BTreeMap<String, nbt::Value>>,
+    }
+}
+
+convert::variants! {
+    struct SerializedStructure {
+        size: Vec<i32>,
+        #[serde(default)]
+        #[variants]
+        palette: Vec<SerializedBlockState>,
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        #[variants]
+        palettes: Vec<Vec<SerializedBlockState>>,
+        #[variants]
+        blocks: Vec<SerializedStructureBlock>,
+        #[serde(default)]
+        #[variants]
+        entities: Vec<SerializedStructureEntity>,
+    }
+}
+
+convert::variants! {
+    struct SerializedStructureBlock {
+        pos: Vec<i32>,
+        state: i32,
+        // the block entity, without its position
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        nbt: Option<BTreeMap<String, nbt::Value>>,
+    }
+}
+
+convert::variants! {
+    struct SerializedStructureEntity {
+        pos: Vec<f64>,
+        #[serde(rename = "blockPos")]
+        block_pos: Vec<i32>,
+        nbt: BTreeMap<String, nbt::Value>,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::convert::data_versions;
+
+    fn state(s: &str) -> IBlockState {
+        IBlockState::new(s.parse().unwrap())
+    }
+
+    #[test]
+    fn rename_up_applies_state_rules() {
+        let mut schematic = Schematic::new(IVec3::new(2, 1, 1)).unwrap();
+        schematic.set_block_state(IVec3::new(0, 0, 0), &state("cauldron[level=0]"));
+        schematic.set_block_state(IVec3::new(1, 0, 0), &state("cauldron[level=2]"));
+        schematic.rename(data_versions::V1_16_5, data_versions::V1_17);
+        assert_eq!(*schematic.get_block_state(IVec3::new(0, 0, 0)), state("cauldron"));
+        assert_eq!(*schematic.get_block_state(IVec3::new(1, 0, 0)), state("water_cauldron[level=2]"));
+    }
+
+    #[test]
+    fn rename_down_applies_state_rules() {
+        let mut schematic = Schematic::new(IVec3::new(3, 1, 1)).unwrap();
+        schematic.set_block_state(IVec3::new(0, 0, 0), &state("cauldron"));
+        schematic.set_block_state(IVec3::new(1, 0, 0), &state("lava_cauldron"));
+        schematic.set_block_state(IVec3::new(2, 0, 0), &state("powder_snow_cauldron[level=3]"));
+        schematic.rename(data_versions::V1_17, data_versions::V1_16_5);
+        for x in 0..3 {
+            assert_eq!(*schematic.get_block_state(IVec3::new(x, 0, 0)), state("cauldron[level=0]"));
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use glam::IVec3;
+use crate::fname::FName;
+use crate::geom::BlockPos;
+
+/// A cuboid selection between two corners, both inclusive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selection {
+    pub dimension: FName,
+    pub corner1: BlockPos,
+    pub corner2: BlockPos,
+}
+
+impl Selection {
+    pub fn new(dimension: FName, pos: BlockPos) -> Self {
+        Selection {
+            dimension,
+            corner1: pos,
+            corner2: pos,
+        }
+    }
+
+    pub fn min(&self) -> BlockPos {
+        self.corner1.min(self.corner2)
+    }
+
+    pub fn max(&self) -> BlockPos {
+        self.corner1.max(self.corner2)
+    }
+
+    pub fn size(&self) -> IVec3 {
+        self.max() - self.min() + IVec3::ONE
+    }
+
+    pub fn volume(&self) -> u64 {
+        let size = self.size();
+        size.x as u64 * size.y as u64 * size.z as u64
+    }
+
+    pub fn contains(&self, pos: BlockPos) -> bool {
+        pos.cmpge(self.min()).all() && pos.cmple(self.max()).all()
+    }
+}
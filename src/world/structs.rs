@@ -1,26 +1,34 @@
 use std::collections::hash_map::DefaultHasher;
 use std::{fmt, io, time};
 use std::borrow::Borrow;
+use std::collections::BTreeMap;
 use std::fmt::Formatter;
-use std::fs::File;
 use std::hash::{Hash, Hasher};
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, RwLock};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError, RwLock};
 use ahash::AHashMap;
-use flate2::read;
 use glam::{IVec2, Vec3Swizzles};
 use internment::ArcIntern;
+use log::warn;
 use positioned_io_preview::RandomAccessFile;
+use crate::fname;
 use crate::fname::FName;
-use crate::geom::{BlockPos, ChunkPos, IVec2RangeExtensions};
+use crate::geom::{BlockPos, ChunkPos, IVec2RangeExtensions, IVec3RangeExtensions};
 use crate::renderer;
 use crate::renderer::WorldRenderer;
-use crate::{CommonFNames, minecraft, resources};
-use crate::convert::{data_versions, VersionedSerde};
+use crate::{CommonFNames, minecraft, resources, ResourceLocation};
+use crate::convert::data_versions;
 use crate::util::{FastDashMap, make_fast_dash_map};
-use crate::world::io::{get_level_dat_version, LevelDat};
+use crate::world::clipboard::PastePreview;
+use crate::world::compression::ChunkCompression;
+use crate::world::history::EditHistory;
+use crate::world::selection::Selection;
+use crate::world::io::{LevelDat, LevelDatData, read_level_dat};
 use crate::world::palette::{BiomeData, BlockData};
+use crate::world::player::PlayerData;
+use crate::world::region::{RegionFormat, RegionKind};
 use crate::world::workers;
 use crate::world::workers::WorldRef;
 
@@ -40,6 +48,97 @@ impl fmt::Display for BlockState {
     }
 }
 
+impl FromStr for BlockState {
+    type Err = BlockStateParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (block, properties) = parse_block_state_parts(s)?;
+        let mut result = BlockState::new(&parse_block_name(block)?);
+        for (key, value) in properties {
+            if result.properties.insert(fname::from_str(key), fname::from_str(value)).is_some() {
+                return Err(BlockStateParseError::DuplicateProperty(key.to_string()));
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockStateParseError {
+    Empty,
+    InvalidBlockName(String),
+    UnclosedProperties,
+    TrailingCharacters(String),
+    ExpectedKeyValue(String),
+    InvalidPropertyName(String),
+    InvalidPropertyValue(String),
+    DuplicateProperty(String),
+}
+
+impl fmt::Display for BlockStateParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockStateParseError::Empty => write!(f, "Expected a block state, e.g. oak_log[axis=y]"),
+            BlockStateParseError::InvalidBlockName(name) => write!(f, "Invalid block name \"{}\"", name),
+            BlockStateParseError::UnclosedProperties => write!(f, "Missing ']' after the properties"),
+            BlockStateParseError::TrailingCharacters(rest) => write!(f, "Unexpected \"{}\" after the properties", rest),
+            BlockStateParseError::ExpectedKeyValue(property) => write!(f, "Expected key=value, found \"{}\"", property),
+            BlockStateParseError::InvalidPropertyName(name) => write!(f, "Invalid property name \"{}\", expected lowercase letters, digits and _", name),
+            BlockStateParseError::InvalidPropertyValue(value) => write!(f, "Invalid property value \"{}\", expected lowercase letters, digits and _", value),
+            BlockStateParseError::DuplicateProperty(name) => write!(f, "Property \"{}\" is specified more than once", name),
+        }
+    }
+}
+
+impl std::error::Error for BlockStateParseError {}
+
+/// Splits a block state in the `name[key=value,...]` format that `Display` writes into its name and properties, checking
+/// the syntax of the properties but not of the name.
+pub fn parse_block_state_parts(s: &str) -> Result<(&str, Vec<(&str, &str)>), BlockStateParseError> {
+    let s = s.trim();
+    let (block, properties) = match s.find('[') {
+        Some(open_index) => {
+            let close_index = s.rfind(']').filter(|close_index| *close_index > open_index).ok_or(BlockStateParseError::UnclosedProperties)?;
+            if close_index + 1 != s.len() {
+                return Err(BlockStateParseError::TrailingCharacters(s[close_index + 1..].to_string()));
+            }
+            (s[..open_index].trim_end(), s[open_index + 1..close_index].trim())
+        }
+        None => (s, ""),
+    };
+    if block.is_empty() {
+        return Err(BlockStateParseError::Empty);
+    }
+    let mut result = Vec::new();
+    if properties.is_empty() {
+        return Ok((block, result));
+    }
+    let is_valid_part = |part: &str| !part.is_empty() && part.chars().all(|c| matches!(c, 'a'..='z' | '0'..='9' | '_'));
+    for property in properties.split(',') {
+        let property = property.trim();
+        let (key, value) = property.split_once('=').ok_or_else(|| BlockStateParseError::ExpectedKeyValue(property.to_string()))?;
+        let (key, value) = (key.trim(), value.trim());
+        if !is_valid_part(key) {
+            return Err(BlockStateParseError::InvalidPropertyName(key.to_string()));
+        }
+        if !is_valid_part(value) {
+            return Err(BlockStateParseError::InvalidPropertyValue(value.to_string()));
+        }
+        result.push((key, value));
+    }
+    Ok((block, result))
+}
+
+pub fn parse_block_name(name: &str) -> Result<FName, BlockStateParseError> {
+    let (namespace, path) = name.split_once(':').unwrap_or(("minecraft", name));
+    let is_valid_namespace = !namespace.is_empty() && namespace.chars().all(|c| matches!(c, 'a'..='z' | '0'..='9' | '_' | '-' | '.'));
+    let is_valid_path = !path.is_empty() && path.chars().all(|c| matches!(c, 'a'..='z' | '0'..='9' | '_' | '-' | '.' | '/'));
+    if !is_valid_namespace || !is_valid_path {
+        return Err(BlockStateParseError::InvalidBlockName(name.to_string()));
+    }
+    Ok(FName::new(ResourceLocation::new(namespace, path)))
+}
+
 #[allow(clippy::derived_hash_with_manual_eq)]
 impl Hash for BlockState {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
@@ -61,6 +160,7 @@ pub trait IBlockStateExtensions {
     fn with_block(&self, block: FName) -> Self;
     fn with_property(&self, key: FName, value: FName) -> Self;
     fn remove_property<Q: ?Sized + Eq + Hash>(&self, key: &Q) -> Self where FName: Borrow<Q>;
+    fn parse(s: &str) -> Result<Self, BlockStateParseError> where Self: Sized;
 }
 
 impl IBlockStateExtensions for IBlockState {
@@ -81,6 +181,10 @@ impl IBlockStateExtensions for IBlockState {
         state.properties.remove(key);
         IBlockState::new(state)
     }
+
+    fn parse(s: &str) -> Result<Self, BlockStateParseError> {
+        s.parse().map(IBlockState::new)
+    }
 }
 
 impl BlockState {
@@ -96,41 +200,187 @@ pub struct Subchunk {
     pub(super) block_data: RwLock<BlockData>,
     pub(super) biome_data: RwLock<BiomeData>,
     pub needs_redraw: AtomicBool,
+    // whether this subchunk was edited since it was loaded or last saved, separate from needs_redraw
+    pub(super) dirty: AtomicBool,
+    // section NBT that we don't model, e.g. light data, written back unchanged on save
+    pub(super) extra: BTreeMap<String, nbt::Value>,
+    // set when the edit that created this subchunk is undone. It's left out on save until something is placed in it
+    // again, but kept in memory so that it's redrawn as air
+    pub(super) removed: AtomicBool,
 }
 
 impl Subchunk {
+    fn empty(biome: FName) -> Self {
+        Subchunk {
+            block_data: RwLock::new(BlockData::direct_init(vec![IBlockState::new(BlockState::new(&CommonFNames.AIR))], Vec::new())),
+            biome_data: RwLock::new(BiomeData::direct_init(vec![biome], Vec::new())),
+            needs_redraw: AtomicBool::new(true),
+            dirty: AtomicBool::new(false),
+            extra: BTreeMap::new(),
+            removed: AtomicBool::new(false),
+        }
+    }
+
         pub fn get_block_state(&self, pos: BlockPos) -> IBlockState {
         self.block_data.read().unwrap().get(pos.x as usize, pos.y as usize, pos.z as usize).clone()
     }
 
-        fn set_block_state(&self, pos: BlockPos, value: &IBlockState) {
-        self.block_data.write().unwrap().set(pos.x as usize, pos.y as usize, pos.z as usize, value);
-        self.needs_redraw.store(true, Ordering::Release);
+        fn set_block_state(&self, pos: BlockPos, value: &IBlockState) -> IBlockState {
+        let mut block_data = self.block_data.write().unwrap();
+        let old_value = block_data.get(pos.x as usize, pos.y as usize, pos.z as usize).clone();
+        if old_value != *value {
+            block_data.set(pos.x as usize, pos.y as usize, pos.z as usize, value);
+            self.needs_redraw.store(true, Ordering::Release);
+            self.dirty.store(true, Ordering::Release);
+        }
+        old_value
     }
 
         pub fn get_biome(&self, pos: BlockPos) -> FName {
         self.biome_data.read().unwrap().get(pos.x as usize >> 2, pos.y as usize >> 2, pos.z as usize >> 2).clone()
     }
 
-        fn set_biome(&self, pos: BlockPos, value: &FName) {
-        self.biome_data.write().unwrap().set(pos.x as usize >> 2, pos.y as usize >> 2, pos.z as usize >> 2, value);
-        self.needs_redraw.store(true, Ordering::Release);
+        fn set_biome(&self, pos: BlockPos, value: &FName) -> FName {
+        let mut biome_data = self.biome_data.write().unwrap();
+        let old_value = biome_data.get(pos.x as usize >> 2, pos.y as usize >> 2, pos.z as usize >> 2).clone();
+        if old_value != *value {
+            biome_data.set(pos.x as usize >> 2, pos.y as usize >> 2, pos.z as usize >> 2, value);
+            self.needs_redraw.store(true, Ordering::Release);
+            self.dirty.store(true, Ordering::Release);
+        }
+        old_value
     }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::Acquire)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockEntity {
+    pub id: FName,
+    /// The world position, kept in sync with the position of the block entity in its chunk.
+    pub pos: BlockPos,
+    /// Set for block entities the game hasn't created yet, e.g. in chunks that are still generating.
+    pub keep_packed: bool,
+    /// The rest of the NBT, e.g. the items of a chest.
+    pub data: BTreeMap<String, nbt::Value>,
+}
+
+impl BlockEntity {
+    pub fn new(id: FName, pos: BlockPos) -> Self {
+        BlockEntity {
+            id,
+            pos,
+            keep_packed: false,
+            data: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entity {
+    pub id: FName,
+    pub pos: glam::DVec3,
+    pub motion: glam::DVec3,
+    /// The yaw and pitch in degrees.
+    pub rotation: glam::Vec2,
+    /// Missing before 1.16, where the UUID is in `UUIDMost` and `UUIDLeast` in the data.
+    pub uuid: Option<u128>,
+    /// The rest of the NBT, e.g. the health and passengers.
+    pub data: BTreeMap<String, nbt::Value>,
+}
+
+/// A block or fluid update the game will run after a delay.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduledTick {
+    /// The block or fluid to tick, which is skipped if it's no longer at the position.
+    pub target: FName,
+    pub pos: BlockPos,
+    /// In ticks.
+    pub delay: i32,
+    /// Lower values run first among ticks scheduled for the same game tick.
+    pub priority: i32,
 }
 
 pub struct Chunk {
-    pub subchunks: Vec<Option<Subchunk>>,
+    // subchunks that don't exist in the save file are empty, and get created when something is placed in them
+    pub(super) subchunks: Vec<OnceLock<Subchunk>>,
+    pub(super) dirty: AtomicBool,
+    // keyed by world position
+    pub(super) block_entities: RwLock<AHashMap<BlockPos, BlockEntity>>,
+    // only stored in chunks before 1.17, or in chunks that haven't finished generating
+    pub(super) entities: RwLock<Vec<Entity>>,
+    // whether the entities are in the entities region file rather than in the chunk, since 1.17
+    pub(super) separate_entities: bool,
+    pub(super) block_ticks: RwLock<Vec<ScheduledTick>>,
+    pub(super) fluid_ticks: RwLock<Vec<ScheduledTick>>,
+    // the long arrays of each heightmap type, e.g. "MOTION_BLOCKING"
+    pub(super) heightmaps: RwLock<BTreeMap<String, Vec<i64>>>,
+    // how far the game generated the chunk, e.g. "minecraft:full", or None for chunks we created
+    pub(super) status: Option<String>,
+    // the total ticks players have spent near the chunk
+    pub(super) inhabited_time: i64,
+    pub(super) last_update: i64,
+    // chunk NBT that we don't model, written back unchanged on save
+    pub(super) extra: BTreeMap<String, nbt::Value>,
 }
 
 impl Chunk {
     pub fn empty() -> Self {
         Chunk {
             subchunks: Vec::new(),
+            dirty: AtomicBool::new(false),
+            block_entities: RwLock::new(AHashMap::new()),
+            entities: RwLock::new(Vec::new()),
+            separate_entities: false,
+            block_ticks: RwLock::new(Vec::new()),
+            fluid_ticks: RwLock::new(Vec::new()),
+            heightmaps: RwLock::new(BTreeMap::new()),
+            status: None,
+            inhabited_time: 0,
+            last_update: 0,
+            extra: BTreeMap::new(),
         }
     }
 
-        pub fn get_block_state(&self, dimension: &Dimension, pos: BlockPos) -> Option<IBlockState> {
-        let subchunk_index = (pos.y - dimension.min_y) >> 4;
+    pub fn status(&self) -> Option<&str> {
+        self.status.as_deref()
+    }
+
+    pub fn inhabited_time(&self) -> i64 {
+        self.inhabited_time
+    }
+
+    pub fn last_update(&self) -> i64 {
+        self.last_update
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::Acquire) || self.iter_subchunks().any(|subchunk| subchunk.is_dirty())
+    }
+
+    pub fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    pub(super) fn clear_dirty(&self) {
+        self.dirty.store(false, Ordering::Release);
+        for subchunk in self.iter_subchunks() {
+            subchunk.dirty.store(false, Ordering::Release);
+        }
+    }
+
+    pub fn get_subchunk(&self, index: usize) -> Option<&Subchunk> {
+        self.subchunks.get(index)?.get()
+    }
+
+    pub fn iter_subchunks(&self) -> impl Iterator<Item = &Subchunk> {
+        self.subchunks.iter().filter_map(OnceLock::get)
+    }
+
+    fn get_subchunk_index(&self, dimension: &Dimension, y: i32) -> Option<usize> {
+        let subchunk_index = (y - dimension.min_y) >> 4;
         if subchunk_index < 0 {
             return None;
         }
@@ -138,22 +388,144 @@ impl Chunk {
         if subchunk_index >= self.subchunks.len() {
             return None;
         }
-        let subchunk = self.subchunks[subchunk_index].as_ref()?;
-        Some(subchunk.get_block_state(pos & glam::IVec3::new(!0, 15, !0)))
+        Some(subchunk_index)
     }
 
-        pub fn get_biome(&self, dimension: &Dimension, pos: BlockPos) -> Option<FName> {
-        let subchunk_index = (pos.y - dimension.min_y) >> 4;
-        if subchunk_index < 0 {
-            return None;
+    fn get_or_create_subchunk(&self, index: usize) -> &Subchunk {
+        let subchunk = self.subchunks[index].get_or_init(|| {
+            // continue the biomes of the closest subchunk below, or above if there is none
+            let biome_source = (0..index).rev().chain(index + 1..self.subchunks.len())
+                .find_map(|i| self.subchunks[i].get().map(|subchunk| (i, subchunk)));
+            let (source_index, source) = match biome_source {
+                Some(biome_source) => biome_source,
+                None => return Subchunk::empty(CommonFNames.PLAINS.clone()),
+            };
+            let source_y = if source_index < index { 3 } else { 0 };
+            let source_biomes = source.biome_data.read().unwrap();
+            let subchunk = Subchunk::empty(source_biomes.get(0, source_y, 0).clone());
+            {
+                let mut biome_data = subchunk.biome_data.write().unwrap();
+                for (x, z) in (0..4).flat_map(|x| (0..4).map(move |z| (x, z))) {
+                    let biome = source_biomes.get(x, source_y, z);
+                    for y in 0..4 {
+                        biome_data.set(x, y, z, biome);
+                    }
+                }
+            }
+            subchunk
+        });
+        // placing something in a removed subchunk brings it back, with the air and biomes the undo left in it
+        subchunk.removed.store(false, Ordering::Release);
+        subchunk
+    }
+
+    /// Whether the subchunk at the given height exists and will be saved.
+    fn has_subchunk(&self, dimension: &Dimension, y: i32) -> bool {
+        let subchunk = self.get_subchunk_index(dimension, y).and_then(|index| self.get_subchunk(index));
+        subchunk.is_some_and(|subchunk| !subchunk.removed.load(Ordering::Acquire))
+    }
+
+    /// Leaves the subchunk at the given height out when saving, e.g. when undoing the edit that created it.
+    fn remove_subchunk(&self, dimension: &Dimension, y: i32) {
+        if let Some(subchunk) = self.get_subchunk_index(dimension, y).and_then(|index| self.get_subchunk(index)) {
+            subchunk.removed.store(true, Ordering::Release);
+            self.mark_dirty();
         }
-        let subchunk_index = subchunk_index as usize;
-        if subchunk_index >= self.subchunks.len() {
-            return None;
+    }
+
+        pub fn get_block_state(&self, dimension: &Dimension, pos: BlockPos) -> Option<IBlockState> {
+        let subchunk = self.get_subchunk(self.get_subchunk_index(dimension, pos.y)?)?;
+        Some(subchunk.get_block_state(pos & glam::IVec3::new(!0, 15, !0)))
+    }
+
+    /// Sets the block state at the given chunk-relative position, creating the subchunk if needed. Returns the previous
+    /// block state, or `None` if the position is outside the height range of the dimension.
+    pub fn set_block_state(&self, dimension: &Dimension, pos: BlockPos, state: &IBlockState) -> Option<IBlockState> {
+        let subchunk_index = self.get_subchunk_index(dimension, pos.y)?;
+        let is_missing = self.subchunks[subchunk_index].get().map_or(true, |subchunk| subchunk.removed.load(Ordering::Acquire));
+        if is_missing && state.block == CommonFNames.AIR && state.properties.is_empty() {
+            // missing and removed subchunks are already air
+            return Some(state.clone());
         }
-        let subchunk = self.subchunks[subchunk_index].as_ref()?;
+        let subchunk = self.get_or_create_subchunk(subchunk_index);
+        Some(subchunk.set_block_state(pos & glam::IVec3::new(!0, 15, !0), state))
+    }
+
+        pub fn get_biome(&self, dimension: &Dimension, pos: BlockPos) -> Option<FName> {
+        let subchunk = self.get_subchunk(self.get_subchunk_index(dimension, pos.y)?)?;
         Some(subchunk.get_biome(pos & glam::IVec3::new(!0, 15, !0)))
     }
+
+    /// Sets the biome at the given chunk-relative position, creating the subchunk if needed. Returns the previous biome,
+    /// or `None` if the position is outside the height range of the dimension.
+    pub fn set_biome(&self, dimension: &Dimension, pos: BlockPos, biome: &FName) -> Option<FName> {
+        let subchunk = self.get_or_create_subchunk(self.get_subchunk_index(dimension, pos.y)?);
+        Some(subchunk.set_biome(pos & glam::IVec3::new(!0, 15, !0), biome))
+    }
+
+    /// Gets the block entity at the given world position.
+    pub fn get_block_entity(&self, pos: BlockPos) -> Option<BlockEntity> {
+        self.block_entities.read().unwrap().get(&pos).cloned()
+    }
+
+    /// Sets or removes the block entity at the given world position, updating its position. Returns the previous
+    /// block entity.
+    pub fn set_block_entity(&self, pos: BlockPos, block_entity: Option<BlockEntity>) -> Option<BlockEntity> {
+        let mut block_entities = self.block_entities.write().unwrap();
+        let old_block_entity = match block_entity {
+            Some(mut block_entity) => {
+                block_entity.pos = pos;
+                block_entities.insert(pos, block_entity)
+            }
+            None => block_entities.remove(&pos),
+        };
+        self.mark_dirty();
+        old_block_entity
+    }
+
+    pub fn block_entities(&self) -> Vec<BlockEntity> {
+        self.block_entities.read().unwrap().values().cloned().collect()
+    }
+
+    pub fn entities(&self) -> Vec<Entity> {
+        self.entities.read().unwrap().clone()
+    }
+
+    /// Edits the entities stored in the chunk, marking it dirty.
+    pub fn edit_entities<R>(&self, f: impl FnOnce(&mut Vec<Entity>) -> R) -> R {
+        let result = f(&mut self.entities.write().unwrap());
+        self.mark_dirty();
+        result
+    }
+
+    pub fn block_ticks(&self) -> Vec<ScheduledTick> {
+        self.block_ticks.read().unwrap().clone()
+    }
+
+    pub fn fluid_ticks(&self) -> Vec<ScheduledTick> {
+        self.fluid_ticks.read().unwrap().clone()
+    }
+
+    /// Edits the scheduled block ticks and fluid ticks of the chunk, marking it dirty.
+    pub fn edit_ticks<R>(&self, f: impl FnOnce(&mut Vec<ScheduledTick>, &mut Vec<ScheduledTick>) -> R) -> R {
+        let result = f(&mut self.block_ticks.write().unwrap(), &mut self.fluid_ticks.write().unwrap());
+        self.mark_dirty();
+        result
+    }
+
+    pub fn get_heightmap(&self, kind: &str) -> Option<Vec<i64>> {
+        self.heightmaps.read().unwrap().get(kind).cloned()
+    }
+
+    /// Sets or removes a heightmap. The game recalculates missing heightmaps when it loads the chunk.
+    pub fn set_heightmap(&self, kind: &str, heightmap: Option<Vec<i64>>) {
+        let mut heightmaps = self.heightmaps.write().unwrap();
+        match heightmap {
+            Some(heightmap) => heightmaps.insert(kind.to_owned(), heightmap),
+            None => heightmaps.remove(kind),
+        };
+        self.mark_dirty();
+    }
 }
 
 pub struct Dimension {
@@ -162,7 +534,7 @@ pub struct Dimension {
     pub max_y: i32,
     pub(super) chunks: FastDashMap<ChunkPos, Arc<Chunk>>,
 
-    pub(super) region_file_cache: FastDashMap<IVec2, (RandomAccessFile, time::SystemTime)>,
+    pub(super) region_file_cache: FastDashMap<(RegionKind, IVec2), (RandomAccessFile, time::SystemTime)>,
     pub(super) chunk_existence_cache: FastDashMap<IVec2, bool>,
 }
 
@@ -183,15 +555,104 @@ impl Dimension {
         chunk.get_block_state(self, pos & glam::IVec3::new(15, !0, 15))
     }
 
+    /// Sets the block state at the given position and redraws the affected subchunks. Returns the previous block
+    /// state, or `None` if the chunk isn't loaded or the position is outside the height range of the dimension.
+    pub fn set_block_state(&self, pos: BlockPos, state: &IBlockState) -> Option<IBlockState> {
+        let old_state = self.edit_loaded_chunk(pos.xz() >> glam::IVec2::new(4, 4), |chunk| {
+            chunk.set_block_state(self, pos & glam::IVec3::new(15, !0, 15), state)
+        })??;
+        if old_state != *state {
+            self.mark_neighbors_for_redraw(pos);
+        }
+        Some(old_state)
+    }
+
         pub fn get_biome(&self, pos: BlockPos) -> Option<FName> {
         let chunk = self.get_chunk(pos.xz() >> glam::IVec2::new(4, 4))?;
         chunk.get_biome(self, pos & glam::IVec3::new(15, !0, 15))
     }
 
+    pub fn get_block_entity(&self, pos: BlockPos) -> Option<BlockEntity> {
+        self.get_chunk(pos.xz() >> glam::IVec2::new(4, 4))?.get_block_entity(pos)
+    }
+
+    /// Sets or removes the block entity at the given position. Returns the previous block entity, or `None` if the
+    /// chunk isn't loaded.
+    pub fn set_block_entity(&self, pos: BlockPos, block_entity: Option<BlockEntity>) -> Option<Option<BlockEntity>> {
+        self.edit_loaded_chunk(pos.xz() >> glam::IVec2::new(4, 4), |chunk| chunk.set_block_entity(pos, block_entity))
+    }
+
+    /// Whether the subchunk containing the given position exists in a loaded chunk and will be saved. Blocks in missing
+    /// subchunks are air.
+    pub fn has_subchunk(&self, pos: BlockPos) -> bool {
+        self.get_chunk(pos.xz() >> glam::IVec2::new(4, 4)).is_some_and(|chunk| chunk.has_subchunk(self, pos.y))
+    }
+
+    /// Leaves the subchunk containing the given position out when saving, until something is placed in it again.
+    pub(super) fn remove_subchunk(&self, pos: BlockPos) {
+        self.edit_loaded_chunk(pos.xz() >> glam::IVec2::new(4, 4), |chunk| chunk.remove_subchunk(self, pos.y));
+    }
+
+    /// Sets the biome of the 4x4x4 biome cell containing the given position. Returns the previous biome, or `None` if
+    /// the chunk isn't loaded or the position is outside the height range of the dimension.
+    pub fn set_biome(&self, pos: BlockPos, biome: &FName) -> Option<FName> {
+        let old_biome = self.edit_loaded_chunk(pos.xz() >> glam::IVec2::new(4, 4), |chunk| {
+            chunk.set_biome(self, pos & glam::IVec3::new(15, !0, 15), biome)
+        })??;
+        if old_biome != *biome {
+            let cell_start = pos & glam::IVec3::splat(!3);
+            self.mark_neighbors_for_redraw(cell_start);
+            self.mark_neighbors_for_redraw(cell_start + glam::IVec3::splat(3));
+        }
+        Some(old_biome)
+    }
+
+    /// Runs `f` on the chunk if it's loaded, holding its entry in the chunk map so that `unload_chunk` can't evict it
+    /// before the edit has marked it dirty. `f` must not access the chunk map itself.
+    fn edit_loaded_chunk<R>(&self, pos: ChunkPos, f: impl FnOnce(&Chunk) -> R) -> Option<R> {
+        let chunk = self.chunks.get(&pos)?;
+        Some(f(&chunk))
+    }
+
+    /// Marks the subchunks adjacent to the given position for redraw, if the position is on a subchunk border.
+    fn mark_neighbors_for_redraw(&self, pos: BlockPos) {
+        let get_section_pos = |pos: BlockPos| BlockPos::new(pos.x >> 4, (pos.y - self.min_y) >> 4, pos.z >> 4);
+        let section_pos = get_section_pos(pos);
+        for delta in (-glam::IVec3::ONE..=glam::IVec3::ONE).iter() {
+            let neighbor_section_pos = get_section_pos(pos + delta);
+            if neighbor_section_pos == section_pos || neighbor_section_pos.y < 0 {
+                continue;
+            }
+            if let Some(chunk) = self.get_chunk(neighbor_section_pos.xz()) {
+                if let Some(subchunk) = chunk.get_subchunk(neighbor_section_pos.y as usize) {
+                    subchunk.needs_redraw.store(true, Ordering::Release);
+                }
+            }
+        }
+    }
+
+    pub fn dirty_chunk_count(&self) -> usize {
+        self.chunks.iter().filter(|chunk| chunk.value().is_dirty()).count()
+    }
+
+    /// Unloads all modified chunks, so that they get loaded again from disk without the modifications.
+    /// Unloads the chunks with unsaved changes, so that they're read from disk again, returning their positions.
+    pub fn discard_changes(&self) -> Vec<ChunkPos> {
+        let mut discarded = Vec::new();
+        self.chunks.retain(|pos, chunk| {
+            let dirty = chunk.is_dirty();
+            if dirty {
+                discarded.push(*pos);
+            }
+            !dirty
+        });
+        discarded
+    }
+
     pub(super) fn on_chunk_load(&self, pos: ChunkPos) {
         for delta in (-IVec2::ONE..=IVec2::ONE).iter() {
             if let Some(chunk) = self.get_chunk(pos + delta) {
-                for subchunk in chunk.subchunks.iter().flatten() {
+                for subchunk in chunk.iter_subchunks() {
                     subchunk.needs_redraw.store(true, Ordering::Release);
                 }
             }
@@ -214,14 +675,32 @@ impl Camera {
         self.yaw = (self.yaw + yaw).rem_euclid(360.0);
         self.pitch = (self.pitch + pitch).clamp(-90.0, 90.0);
     }
+
+    pub fn forward(&self) -> glam::DVec3 {
+        let (yaw, pitch) = (self.yaw.to_radians() as f64, self.pitch.to_radians() as f64);
+        glam::DVec3::new(-(pitch.cos() * yaw.sin()), pitch.sin(), -(pitch.cos() * yaw.cos()))
+    }
 }
 
 pub struct World {
     pub camera: RwLock<Camera>,
-    pub(super) level_dat: LevelDat,
+    pub(super) level_dat: RwLock<LevelDat>,
+    level_dat_dirty: AtomicBool,
+    // edits to players in `playerdata` that haven't been saved yet, keyed by UUID
+    pub(super) edited_players: FastDashMap<u128, PlayerData>,
     pub(super) path: PathBuf,
+    pub(super) region_format: RegionFormat,
     pub resources: Arc<resources::Resources>,
     pub renderer: WorldRenderer,
+    pub history: Mutex<EditHistory>,
+    pub selection: RwLock<Option<Selection>>,
+    pub paste_preview: RwLock<Option<PastePreview>>,
+    // set while a region tool rewrites the region files, which the world mustn't be edited or saved over meanwhile
+    pub(super) region_tool_running: AtomicBool,
+    // held by saves and region tools for as long as they write region files, so that they never run at the same time
+    region_files_lock: Mutex<()>,
+    // the compression of the first chunk read, 0 until then
+    detected_chunk_compression: AtomicU8,
     dimensions: FastDashMap<FName, Arc<Dimension>>,
 }
 
@@ -232,10 +711,17 @@ impl World {
         if resources_zip.exists() && resources_zip.is_file() {
             resource_packs.push(&resources_zip);
         }
-        let level_dat = path.join("level.dat");
-        let level_dat_version = get_level_dat_version(&mut nbt::de::Decoder::new(read::GzDecoder::new(File::open(&level_dat)?)))?;
-        let level_dat: LevelDat = VersionedSerde::deserialize(level_dat_version, level_dat_version, &mut nbt::de::Decoder::new(read::GzDecoder::new(File::open(&level_dat)?)))?;
-        let mc_version = level_dat.data.version.name.clone();
+        let level_dat = read_level_dat(&path.join("level.dat"))?;
+        let level_dat_version = level_dat.data.version.id;
+        let region_format = match level_dat.data._extra.get("version") {
+            Some(nbt::Value::Int(version)) => RegionFormat::from_level_dat_version(*version),
+            _ => RegionFormat::Anvil,
+        };
+        let mut mc_version = level_dat.data.version.name.clone();
+        // chunks from before the flattening are flattened as they're read, so render them with flattened models
+        if level_dat_version < data_versions::V1_13 {
+            mc_version = "1.13.2".to_owned();
+        }
         let resources = match resources::loader::load(&mc_version, &resource_packs, interaction_handler) {
             Some(r) => Arc::new(r),
             None => return Err(io::Error::new(io::ErrorKind::Other, "Failed to load resources")),
@@ -248,16 +734,26 @@ impl World {
                 yaw: 0.0,
                 pitch: 0.0,
             }),
-            level_dat,
+            level_dat: RwLock::new(level_dat),
+            level_dat_dirty: AtomicBool::new(false),
+            edited_players: make_fast_dash_map(),
             path,
+            region_format,
             resources,
             renderer,
+            history: Mutex::new(EditHistory::default()),
+            selection: RwLock::new(None),
+            paste_preview: RwLock::new(None),
+            region_tool_running: AtomicBool::new(false),
+            region_files_lock: Mutex::new(()),
+            detected_chunk_compression: AtomicU8::new(0),
             dimensions: make_fast_dash_map()
         };
         let mut overworld = Dimension::new(CommonFNames.OVERWORLD.clone());
+        // the overworld grew to -64..319 in 1.18, and hasn't changed height since
         if level_dat_version > data_versions::V1_17_1 {
             overworld.min_y = -64;
-            overworld.max_y = 383;
+            overworld.max_y = 319;
         }
         world.dimensions.insert(CommonFNames.OVERWORLD.clone(), Arc::new(overworld));
         world.dimensions.insert(CommonFNames.THE_NETHER.clone(), Arc::new(Dimension::new(CommonFNames.THE_NETHER.clone())));
@@ -271,4 +767,107 @@ impl World {
         pub fn get_dimension(&self, id: &FName) -> Option<Arc<Dimension>> {
         self.dimensions.get(id).map(|d| d.clone())
     }
+
+    pub fn dimensions(&self) -> Vec<Arc<Dimension>> {
+        self.dimensions.iter().map(|d| d.value().clone()).collect()
+    }
+
+    /// The data version chunks and other files are written with.
+    pub fn data_version(&self) -> u32 {
+        self.level_dat.read().unwrap().data.version.id
+    }
+
+    /// McRegion worlds can be viewed, but not saved.
+    pub fn is_read_only(&self) -> bool {
+        self.region_format == RegionFormat::McRegion
+    }
+
+    /// Whether a region tool is running, during which the world can't be edited or saved.
+    pub fn is_region_tool_running(&self) -> bool {
+        self.region_tool_running.load(Ordering::Acquire)
+    }
+
+    /// Waits for the save or region tool that's writing region files to finish, and keeps others from starting until
+    /// the guard is dropped.
+    pub(super) fn lock_region_files(&self) -> MutexGuard<'_, ()> {
+        // a panicking writer leaves nothing behind that needs to be checked
+        self.region_files_lock.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// A copy of the world properties in level.dat.
+    pub fn level_dat(&self) -> LevelDatData {
+        self.level_dat.read().unwrap().data.clone()
+    }
+
+    /// Edits the world properties in level.dat, which get written when the world is saved.
+    pub fn edit_level_dat<R>(&self, f: impl FnOnce(&mut LevelDatData) -> R) -> R {
+        let result = f(&mut self.level_dat.write().unwrap().data);
+        self.level_dat_dirty.store(true, Ordering::Release);
+        result
+    }
+
+    /// The compression to save chunks with. This is the compression configured for this world, or else the
+    /// compression of the chunks already in the world, so that e.g. LZ4 worlds stay LZ4.
+    pub fn chunk_compression(&self) -> ChunkCompression {
+        if let Some(compression) = crate::get_config().world_chunk_compression.get(&self.path) {
+            return *compression;
+        }
+        ChunkCompression::from_id(self.detected_chunk_compression.load(Ordering::Relaxed)).unwrap_or(ChunkCompression::Zlib)
+    }
+
+    pub(super) fn detect_chunk_compression(&self, compression: ChunkCompression) {
+        let _ = self.detected_chunk_compression.compare_exchange(0, compression.id(), Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    pub fn dirty_chunk_count(&self) -> usize {
+        self.dimensions.iter().map(|dimension| dimension.value().dirty_chunk_count()).sum()
+    }
+
+    pub fn has_unsaved_changes(&self) -> bool {
+        self.level_dat_dirty.load(Ordering::Acquire) || !self.edited_players.is_empty() || self.dimensions.iter().any(|dimension| dimension.value().chunks.iter().any(|chunk| chunk.value().is_dirty()))
+    }
+
+    pub fn discard_changes(&self) {
+        if self.level_dat_dirty.swap(false, Ordering::AcqRel) {
+            match read_level_dat(&self.path.join("level.dat")) {
+                Ok(level_dat) => *self.level_dat.write().unwrap() = level_dat,
+                Err(e) => warn!("Failed to reload level.dat: {}", e),
+            }
+        }
+        self.edited_players.clear();
+        for dimension in self.dimensions() {
+            for pos in dimension.discard_changes() {
+                // the subchunks the edits created would otherwise keep their geometry
+                self.renderer.mark_chunk_for_redraw(&dimension.id, pos);
+            }
+        }
+        self.history.lock().unwrap().clear();
+    }
+
+    /// Writes all dirty chunks in all dimensions back to their region files, returning the number of chunks saved.
+        pub fn save(&self) -> io::Result<usize> {
+        if self.is_read_only() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "Saving McRegion worlds isn't supported"));
+        }
+        if self.is_region_tool_running() {
+            return Err(io::Error::new(io::ErrorKind::Other, "Can't save while a region tool is running"));
+        }
+        let _region_files = self.lock_region_files();
+        // a region tool may have been started while waiting for another save to finish
+        if self.is_region_tool_running() {
+            return Err(io::Error::new(io::ErrorKind::Other, "Can't save while a region tool is running"));
+        }
+        let mut saved_chunks = 0;
+        for dimension in self.dimensions() {
+            saved_chunks += dimension.save_dirty_chunks(self)?;
+        }
+        if self.level_dat_dirty.swap(false, Ordering::AcqRel) {
+            if let Err(e) = self.save_level_dat() {
+                self.level_dat_dirty.store(true, Ordering::Release);
+                return Err(e);
+            }
+        }
+        self.save_players()?;
+        Ok(saved_chunks)
+    }
 }
\ No newline at end of file
@@ -1,11 +1,15 @@
 use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io;
 use ahash::AHashMap;
 use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
 use crate::{CommonFNames, convert, fname};
-use crate::convert::{data_versions, registries};
+use crate::convert::{ConvertInto, data_versions, flattening, registries};
 use crate::make_a_bi_map;
 use crate::fname::FName;
 use crate::util::ABiMap;
+use crate::world::{BlockState, IBlockState};
 use crate::world::io::*;
 
 thread_local! {
@@ -121,18 +125,262 @@ convert::variants! {
 
         #[serde(rename = "Biomes")]
         #[serde(default)]
+        #[serde(serialize_with = "nbt::i32_array")]
         pub(super) biomes: Vec<i32>,
+
+        #[serde(rename = "TileEntities")]
+        #[serde(default)]
+        #[variants]
+        pub(super) tile_entities: Vec<SerializedBlockEntity>,
+
+        #[serde(rename = "Entities")]
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        #[variants]
+        pub(super) entities: Vec<SerializedEntity>,
+
+        #[serde(rename = "TileTicks")]
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        #[variants]
+        pub(super) tile_ticks: Vec<SerializedTick>,
+
+        #[serde(rename = "LiquidTicks")]
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        #[variants]
+        pub(super) liquid_ticks: Vec<SerializedTick>,
+
+        #[serde(rename = "Heightmaps")]
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty", serialize_with = "serialize_heightmaps")]
+        pub(super) heightmaps: BTreeMap<String, Vec<i64>>,
+
+        #[serde(rename = "Status")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub(super) status: Option<String>,
+
+        #[serde(rename = "InhabitedTime")]
+        #[serde(default)]
+        pub(super) inhabited_time: i64,
+
+        #[serde(rename = "LastUpdate")]
+        #[serde(default)]
+        pub(super) last_update: i64,
+    }
+    fn up(older: Self::UpInput, prevailing_version: u32) -> Self::UpResult {
+        Ok(Self::UpOutput {
+            sections: older.sections.convert_into(prevailing_version)?,
+            biomes: older.biomes.iter().map(|biome| *biome as u8 as i32).collect(),
+            tile_entities: older.tile_entities.convert_into(prevailing_version)?,
+            entities: older.entities.convert_into(prevailing_version)?,
+            tile_ticks: older.tile_ticks.convert_into(prevailing_version)?,
+            liquid_ticks: older.liquid_ticks.convert_into(prevailing_version)?,
+            heightmaps: older.heightmaps,
+            status: older.status,
+            inhabited_time: older.inhabited_time,
+            last_update: older.last_update,
+            _extra: older._extra,
+        })
+    }
+    fn down(newer: Self::DownInput, prevailing_version: u32) -> Self::DownResult {
+        Ok(Self::DownOutput {
+            sections: newer.sections.convert_into(prevailing_version)?,
+            biomes: biomes_2d_down(&newer.biomes),
+            tile_entities: newer.tile_entities.convert_into(prevailing_version)?,
+            entities: newer.entities.convert_into(prevailing_version)?,
+            tile_ticks: newer.tile_ticks.convert_into(prevailing_version)?,
+            liquid_ticks: newer.liquid_ticks.convert_into(prevailing_version)?,
+            heightmaps: newer.heightmaps,
+            status: newer.status,
+            inhabited_time: newer.inhabited_time,
+            last_update: newer.last_update,
+            _extra: newer._extra,
+        })
+    }
+    // biomes are bytes before the flattening
+    1,12,2 => {
+        #[serde(rename = "Sections")]
+        #[variants]
+        sections: Vec<SerializedChunkSection17>,
+
+        #[serde(rename = "Biomes")]
+        #[serde(default)]
+        #[serde(serialize_with = "nbt::i8_array")]
+        biomes: Vec<i8>,
+
+        #[serde(rename = "TileEntities")]
+        #[serde(default)]
+        #[variants]
+        tile_entities: Vec<SerializedBlockEntity>,
+
+        #[serde(rename = "Entities")]
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        #[variants]
+        entities: Vec<SerializedEntity>,
+
+        #[serde(rename = "TileTicks")]
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        #[variants]
+        tile_ticks: Vec<SerializedTick>,
+
+        #[serde(rename = "LiquidTicks")]
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        #[variants]
+        liquid_ticks: Vec<SerializedTick>,
+
+        #[serde(rename = "Heightmaps")]
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty", serialize_with = "serialize_heightmaps")]
+        heightmaps: BTreeMap<String, Vec<i64>>,
+
+        #[serde(rename = "Status")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        status: Option<String>,
+
+        #[serde(rename = "InhabitedTime")]
+        #[serde(default)]
+        inhabited_time: i64,
+
+        #[serde(rename = "LastUpdate")]
+        #[serde(default)]
+        last_update: i64,
     }
 }
 
+/// Samples the 3D biomes of 1.15 and later at sea level, for the biome of each column before 1.13.
+fn biomes_2d_down(biomes: &[i32]) -> Vec<i8> {
+    // the layer of 4x4x4 biome cells at y 64
+    let layer = match biomes.len() {
+        256 => return biomes.iter().map(|biome| *biome as i8).collect(),
+        1024 => 16,
+        1536 => 32,
+        _ => return Vec::new(),
+    };
+    (0..256).map(|index| biomes[layer * 16 + (index >> 6) * 4 + ((index & 15) >> 2)] as i8).collect()
+}
+
 convert::variants! {
     pub(super) struct SerializedChunkSection17 {
         #[serde(rename = "Palette")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[variants]
+        pub(super) palette: Option<Vec<SerializedBlockState>>,
+
+        #[serde(rename = "BlockStates")]
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Vec::is_empty", serialize_with = "nbt::i64_array")]
+        pub(super) block_states: Vec<i64>,
+
+        #[serde(rename = "Y")]
+        pub(super) y: i32,
+    }
+    #[variants(SerializedBlockState)]
+    fn up(older: Self::UpInput, prevailing_version: u32) -> Self::UpResult {
+        if older.blocks.len() != 4096 || older.data.len() != 2048 {
+            return Err(convert::Error::new(format!("Expected 4096 block IDs and 2048 data bytes, found {} and {}", older.blocks.len(), older.data.len())));
+        }
+        let air = IBlockState::new(BlockState::new(&CommonFNames.AIR));
+        let mut states: Vec<IBlockState> = (0..4096).map(|index| {
+            let id = older.blocks[index] as u8 as u16 | (get_nibble(&older.add, index) as u16) << 8;
+            flattening::upgrade_legacy_block_state(id, get_nibble(&older.data, index), prevailing_version).unwrap_or_else(|| air.clone())
+        }).collect();
+        // doors and tall plants split across two sections keep the state each half was flattened to
+        for index in 0..4096 - 256 {
+            if let Some((lower, upper)) = flattening::fix_split_block(&states[index], &states[index + 256]) {
+                states[index] = lower;
+                states[index + 256] = upper;
+            }
+        }
+        let (palette, block_states) = pack_block_states(&states, true);
+        Ok(Self::UpOutput {
+            palette: Some(palette.iter().map(|state| SerializedBlockState {
+                name: state.block.clone(),
+                properties: block_properties_to_nbt(state),
+                _extra: Default::default(),
+            }).collect()),
+            block_states,
+            y: older.y,
+            _extra: older._extra,
+        })
+    }
+    fn down(newer: Self::DownInput, prevailing_version: u32) -> Self::DownResult {
+        let mut palette: Vec<IBlockState> = newer.palette.iter().flatten()
+            .map(|state| block_state_from_nbt(&state.name, &state.properties))
+            .collect();
+        if palette.is_empty() {
+            palette.push(IBlockState::new(BlockState::new(&CommonFNames.AIR)));
+        }
+        let indices = if newer.block_states.is_empty() {
+            vec![0; 4096]
+        } else {
+            unpack_indices(&newer.block_states, 4096, bits_per_block_17(palette.len()), true)?
+        };
+        let mut blocks = vec![0; 4096];
+        let mut data = vec![0; 2048];
+        let mut add = vec![0; 2048];
+        let mut has_add = false;
+        for (index, palette_index) in indices.into_iter().enumerate() {
+            let state = palette.get(palette_index)
+                .ok_or_else(|| convert::Error::new(format!("Palette index is out of bounds, {} >= {}", palette_index, palette.len())))?;
+            let (id, meta) = flattening::downgrade_to_legacy_block_state(state, prevailing_version);
+            blocks[index] = id as u8 as i8;
+            set_nibble(&mut data, index, meta);
+            if id > 255 {
+                set_nibble(&mut add, index, (id >> 8) as u8);
+                has_add = true;
+            }
+        }
+        Ok(Self::DownOutput {
+            blocks,
+            data,
+            add: if has_add { add } else { Vec::new() },
+            y: newer.y,
+            _extra: newer._extra,
+        })
+    }
+    // numeric block IDs and data values before the flattening, indexed by y, z, x
+    1,12,2 => {
+        #[serde(rename = "Blocks")]
+        #[serde(serialize_with = "nbt::i8_array")]
+        blocks: Vec<i8>,
+
+        #[serde(rename = "Data")]
+        #[serde(serialize_with = "nbt::i8_array")]
+        data: Vec<i8>,
+
+        // the upper 4 bits of block IDs above 255
+        #[serde(rename = "Add")]
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Vec::is_empty", serialize_with = "nbt::i8_array")]
+        add: Vec<i8>,
+
+        #[serde(rename = "Y")]
+        y: i32,
+    }
+    fn up(older: Self::UpInput, prevailing_version: u32) -> Self::UpResult {
+        let palette_len = older.palette.as_ref().map_or(0, Vec::len);
+        Ok(Self::UpOutput {
+            palette: older.palette.convert_into(prevailing_version)?,
+            block_states: repack_block_states(&older.block_states, palette_len, true)?,
+            y: older.y,
+            _extra: older._extra,
+        })
+    }
+    fn down(newer: Self::DownInput, prevailing_version: u32) -> Self::DownResult {
+        let palette_len = newer.palette.as_ref().map_or(0, Vec::len);
+        Ok(Self::DownOutput {
+            palette: newer.palette.convert_into(prevailing_version)?,
+            block_states: repack_block_states(&newer.block_states, palette_len, false)?,
+            y: newer.y,
+            _extra: newer._extra,
+        })
+    }
+    // block states span across two longs when they don't fit in the rest of the first one before 1.16
+    1,15,2 => {
+        #[serde(rename = "Palette")]
+        #[serde(skip_serializing_if = "Option::is_none")]
         #[variants]
         pub(super) palette: Option<Vec<SerializedBlockState>>,
 
         #[serde(rename = "BlockStates")]
         #[serde(default)]
+        #[serde(skip_serializing_if = "Vec::is_empty", serialize_with = "nbt::i64_array")]
         pub(super) block_states: Vec<i64>,
 
         #[serde(rename = "Y")]
@@ -140,6 +388,195 @@ convert::variants! {
     }
 }
 
+/// A chunk from a McRegion file, before 1.2.
+#[derive(Deserialize)]
+struct SerializedMcRegionChunk {
+    #[serde(rename = "Level")]
+    level: SerializedMcRegionLevel,
+}
+
+#[derive(Deserialize)]
+struct SerializedMcRegionLevel {
+    // the whole 128 high chunk, indexed by x, z, y
+    #[serde(rename = "Blocks")]
+    blocks: Vec<i8>,
+    #[serde(rename = "Data")]
+    data: Vec<i8>,
+    #[serde(rename = "TileEntities")]
+    #[serde(default)]
+    tile_entities: Vec<Variant_SerializedBlockEntity_1_8>,
+    #[serde(rename = "Entities")]
+    #[serde(default)]
+    entities: Vec<Variant_SerializedEntity_1_8>,
+    #[serde(rename = "LastUpdate")]
+    #[serde(default)]
+    last_update: i64,
+    #[serde(flatten)]
+    _extra: BTreeMap<String, nbt::Value>,
+}
+
+impl SerializedMcRegionChunk {
+    fn into_anvil(self) -> convert::Result<Variant_SerializedChunk_1_8> {
+        let level = self.level;
+        if level.blocks.len() != 32768 || level.data.len() != 16384 {
+            return Err(convert::Error::new(format!("Expected 32768 block IDs and 16384 data bytes, found {} and {}", level.blocks.len(), level.data.len())));
+        }
+        let sections = (0..8).filter_map(|section_y| {
+            let mut blocks = vec![0; 4096];
+            let mut data = vec![0; 2048];
+            for index in 0..4096 {
+                let mcregion_index = (index & 15) << 11 | ((index >> 4) & 15) << 7 | (section_y << 4 | index >> 8);
+                blocks[index] = level.blocks[mcregion_index];
+                set_nibble(&mut data, index, get_nibble(&level.data, mcregion_index));
+            }
+            if blocks.iter().all(|block| *block == 0) {
+                return None;
+            }
+            Some(Variant_SerializedChunkSection17_1_8 {
+                blocks,
+                data,
+                add: Vec::new(),
+                y: section_y as i32,
+                _extra: Default::default(),
+            })
+        }).collect();
+        let mut extra = level._extra;
+        // ticks have numeric block IDs, and the light and height map are laid out for 128 high chunks
+        for key in ["TileTicks", "SkyLight", "BlockLight", "HeightMap"] {
+            extra.remove(key);
+        }
+        Ok(Variant_SerializedChunk_1_8 {
+            level: Variant_SerializedChunkLevel_1_8 {
+                sections,
+                biomes: Vec::new(),
+                tile_entities: level.tile_entities,
+                entities: level.entities,
+                tile_ticks: Vec::new(),
+                liquid_ticks: Vec::new(),
+                heightmaps: BTreeMap::new(),
+                status: None,
+                inhabited_time: 0,
+                last_update: level.last_update,
+                _extra: extra,
+            },
+            _extra: Default::default(),
+        })
+    }
+}
+
+/// Rearranges the NBT of a McRegion chunk into the NBT of an Anvil chunk of the oldest supported version.
+pub(super) fn mcregion_to_anvil(nbt_data: impl io::Read) -> io::Result<Vec<u8>> {
+    let chunk = SerializedMcRegionChunk::deserialize(&mut nbt::de::Decoder::new(nbt_data))?;
+    let mut anvil_chunk = chunk.into_anvil().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.msg()))?;
+    anvil_chunk._extra.insert("DataVersion".to_owned(), nbt::Value::Int(data_versions::V1_8 as i32));
+    let mut result = Vec::new();
+    anvil_chunk.serialize(&mut nbt::ser::Encoder::new(&mut result, None))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(result)
+}
+
+fn get_nibble(array: &[i8], index: usize) -> u8 {
+    let byte = array.get(index >> 1).map_or(0, |byte| *byte as u8);
+    if index & 1 == 0 {
+        byte & 15
+    } else {
+        byte >> 4
+    }
+}
+
+fn set_nibble(array: &mut [i8], index: usize, value: u8) {
+    let byte = &mut array[index >> 1];
+    *byte = if index & 1 == 0 {
+        (*byte as u8 & 0xf0 | value & 15) as i8
+    } else {
+        (*byte as u8 & 0x0f | value << 4) as i8
+    };
+}
+
+/// Packs block states into a palette and longs the way sections are stored before 1.18, see `pack_indices`. Sections
+/// always have data before 1.18, even if there's only one block state.
+fn pack_block_states(states: &[IBlockState], spanning: bool) -> (Vec<IBlockState>, Vec<i64>) {
+    let mut palette = Vec::new();
+    let mut inv_palette = AHashMap::new();
+    let indices: Vec<usize> = states.iter().map(|state| {
+        *inv_palette.entry(state.clone()).or_insert_with(|| {
+            palette.push(state.clone());
+            palette.len() - 1
+        })
+    }).collect();
+    let data = pack_indices(&indices, bits_per_block_17(palette.len()), spanning);
+    (palette, data)
+}
+
+/// The bits per block of a section's palette indices before 1.18, which is never less than 4.
+fn bits_per_block_17(palette_len: usize) -> usize {
+    if palette_len <= 1 {
+        4
+    } else {
+        (((palette_len - 1).ilog2() + 1) as usize).max(4)
+    }
+}
+
+/// Packs palette indices into longs. From 1.13 to 1.15 an index that doesn't fit in the rest of a long continues in the
+/// next one (`spanning`), since 1.16 each long holds as many whole indices as fit and the remaining bits are unused.
+fn pack_indices(indices: &[usize], bits_per_entry: usize, spanning: bool) -> Vec<i64> {
+    if spanning {
+        let mut data = vec![0_u64; (indices.len() * bits_per_entry).div_ceil(64)];
+        for (i, index) in indices.iter().enumerate() {
+            let (word, offset) = (i * bits_per_entry / 64, i * bits_per_entry % 64);
+            data[word] |= (*index as u64) << offset;
+            if offset + bits_per_entry > 64 {
+                data[word + 1] |= (*index as u64) >> (64 - offset);
+            }
+        }
+        data.into_iter().map(|word| word as i64).collect()
+    } else {
+        let entries_per_long = 64 / bits_per_entry;
+        let mut data = vec![0_i64; indices.len().div_ceil(entries_per_long)];
+        for (i, index) in indices.iter().enumerate() {
+            data[i / entries_per_long] |= (*index as i64) << (i % entries_per_long * bits_per_entry);
+        }
+        data
+    }
+}
+
+/// The inverse of `pack_indices`.
+fn unpack_indices(data: &[i64], count: usize, bits_per_entry: usize, spanning: bool) -> convert::Result<Vec<usize>> {
+    let expected_len = if spanning {
+        (count * bits_per_entry).div_ceil(64)
+    } else {
+        count.div_ceil(64 / bits_per_entry)
+    };
+    if data.len() != expected_len {
+        return Err(convert::Error::new(format!("Expected {} block state words, got {}", expected_len, data.len())));
+    }
+    let mask = (1_u64 << bits_per_entry) - 1;
+    Ok((0..count).map(|i| {
+        if spanning {
+            let (word, offset) = (i * bits_per_entry / 64, i * bits_per_entry % 64);
+            let mut value = data[word] as u64 >> offset;
+            if offset + bits_per_entry > 64 {
+                value |= (data[word + 1] as u64) << (64 - offset);
+            }
+            (value & mask) as usize
+        } else {
+            let entries_per_long = 64 / bits_per_entry;
+            (data[i / entries_per_long] as u64 >> (i % entries_per_long * bits_per_entry) & mask) as usize
+        }
+    }).collect())
+}
+
+/// Converts the block states of a section between the 1.13 and 1.16 layouts, see `pack_indices`.
+fn repack_block_states(data: &[i64], palette_len: usize, from_spanning: bool) -> convert::Result<Vec<i64>> {
+    // sections without blocks
+    if data.is_empty() {
+        return Ok(Vec::new());
+    }
+    let bits_per_block = bits_per_block_17(palette_len);
+    let indices = unpack_indices(data, 4096, bits_per_block, from_spanning)?;
+    Ok(pack_indices(&indices, bits_per_block, !from_spanning))
+}
+
 pub(super) fn fix_17_sections(sections: Vec<Variant_SerializedChunkSection17_1_17_1>, prevailing_version: u32) -> convert::Result<Vec<Variant_SerializedChunkSection17_1_17_1>> {
     let cur_dim = CURRENT_DIMENSION.with(|c| c.borrow().clone());
     let will_be_shifted = cur_dim == CommonFNames.OVERWORLD && prevailing_version > data_versions::V1_17_1;
@@ -190,6 +627,10 @@ pub(super) fn biomes_17_up(biomes: &[i32], prevailing_version: u32) -> convert::
             }
         }
         Ok(result)
+    } else if biomes.len() == 256 {
+        // 2D biomes from before 1.15, each 4x4 cell takes the biome of its corner column
+        let column_biome = |index: usize| biomes[((index >> 2) & 3) * 64 + (index & 3) * 4] & 255;
+        (0..if shift { 24 } else { 16 }).map(|_| biomes_17_up_subchunk(column_biome, prevailing_version)).collect()
     } else {
         let len = if shift { 24 } else { 16 };
         Ok(vec![Variant_SerializedBiomes_1_18 {
@@ -274,4 +715,51 @@ pub(super) fn biomes_17_down(sections: &[Variant_SerializedChunkSection_1_18], p
     }
 
     Ok(result)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spanning_indices_continue_in_the_next_long() {
+        let indices: Vec<usize> = (0..4096).map(|i| i % 20).collect();
+        let data = pack_indices(&indices, 5, true);
+        assert_eq!(data.len(), 320);
+        // the 13th index starts at bit 60 of the first long and ends in the second
+        assert_eq!((data[0] as u64 >> 60) | ((data[1] as u64 & 1) << 4), 12);
+        assert_eq!(unpack_indices(&data, 4096, 5, true).unwrap(), indices);
+    }
+
+    #[test]
+    fn non_spanning_indices_leave_bits_unused() {
+        let indices: Vec<usize> = (0..4096).map(|i| i % 20).collect();
+        let data = pack_indices(&indices, 5, false);
+        assert_eq!(data.len(), 342);
+        assert_eq!(data[0] as u64 >> 60, 0);
+        assert_eq!(data[1] as u64 & 31, 12);
+        assert_eq!(unpack_indices(&data, 4096, 5, false).unwrap(), indices);
+    }
+
+    #[test]
+    fn unpack_indices_rejects_the_wrong_layout_length() {
+        let data = pack_indices(&vec![3; 4096], 5, false);
+        assert!(unpack_indices(&data, 4096, 5, true).is_err());
+    }
+
+    #[test]
+    fn pack_block_states_keeps_data_for_one_state() {
+        let stone = IBlockState::new(BlockState::new(&fname::from_str("stone")));
+        let (palette, data) = pack_block_states(&vec![stone.clone(); 4096], true);
+        assert!(palette == vec![stone]);
+        assert_eq!(data, vec![0; 256]);
+    }
+
+    #[test]
+    fn repack_block_states_converts_between_layouts() {
+        let indices: Vec<usize> = (0..4096).map(|i| i * 7 % 20).collect();
+        let spanning = pack_indices(&indices, 5, true);
+        let non_spanning = repack_block_states(&spanning, 20, true).unwrap();
+        assert_eq!(non_spanning, pack_indices(&indices, 5, false));
+        assert_eq!(repack_block_states(&non_spanning, 20, false).unwrap(), spanning);
+    }
+}
@@ -2,6 +2,7 @@ use std::collections::VecDeque;
 use std::ops::Deref;
 use std::sync::{Arc, Condvar, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use glam::{IVec2, Vec3Swizzles};
 use lazy_static::lazy_static;
 use crate::fname::FName;
@@ -100,11 +101,16 @@ pub fn worker_yield() {
     drop(GLOBAL_TICK_VAR.wait(GLOBAL_TICK_MUTEX.lock().unwrap()).unwrap());
 }
 
+/// How often the chunk loader looks for chunks that were loaded outside the render distance by edits.
+const STRAY_CHUNK_SCAN_INTERVAL: Duration = Duration::from_secs(5);
+
 pub(super) fn chunk_loader(world: Arc<World>, stop: &dyn Fn() -> bool) {
     let render_distance = crate::get_config().render_distance() as i32;
+    let mut last_stray_chunk_scan = Instant::now();
     let mut prev_dimension: Option<FName> = None;
     let mut prev_chunk_pos: Option<IVec2> = None;
     let mut chunks_to_unload = VecDeque::new();
+    let mut kept_dirty_chunks: Vec<(FName, IVec2)> = Vec::new();
 
     'outer_loop:
     while !stop() {
@@ -137,9 +143,29 @@ pub(super) fn chunk_loader(world: Arc<World>, stop: &dyn Fn() -> bool) {
             prev_chunk_pos = Some(chunk_pos);
         }
 
+        // dirty chunks that were kept loaded can be unloaded once they have been saved
+        kept_dirty_chunks.retain(|(kept_dimension, kept_pos)| {
+            if *kept_dimension == dimension && (*kept_pos - chunk_pos).abs().max_element() <= render_distance {
+                return false;
+            }
+            let still_dirty = world.get_dimension(kept_dimension)
+                .and_then(|dimension| dimension.get_chunk(*kept_pos))
+                .map(|chunk| chunk.is_dirty())
+                .unwrap_or(false);
+            if !still_dirty {
+                chunks_to_unload.push_back((kept_dimension.clone(), *kept_pos));
+            }
+            still_dirty
+        });
+
         while !chunks_to_unload.is_empty() {
-            let (dimension, chunk_pos) = chunks_to_unload.pop_front().unwrap();
-            if let Some(dimension) = world.get_dimension(&dimension) {
+            let (dimension_id, chunk_pos) = chunks_to_unload.pop_front().unwrap();
+            if let Some(dimension) = world.get_dimension(&dimension_id) {
+                if dimension.get_chunk(chunk_pos).map(|chunk| chunk.is_dirty()).unwrap_or(false) {
+                    // never drop unsaved edits
+                    kept_dirty_chunks.push((dimension_id, chunk_pos));
+                    continue;
+                }
                 if dimension.unload_chunk(&world, chunk_pos) {
                     break;
                 }
@@ -163,6 +189,21 @@ pub(super) fn chunk_loader(world: Arc<World>, stop: &dyn Fn() -> bool) {
             }
         }
 
+        // operations, pastes and undos load chunks outside the render distance, which the camera never moves away from
+        if chunks_to_unload.is_empty() && last_stray_chunk_scan.elapsed() >= STRAY_CHUNK_SCAN_INTERVAL {
+            last_stray_chunk_scan = Instant::now();
+            for other_dimension in world.dimensions() {
+                for entry in other_dimension.chunks.iter() {
+                    let cp = *entry.key();
+                    let in_view = other_dimension.id == dimension.id && (cp - chunk_pos).abs().max_element() <= render_distance;
+                    let kept = kept_dirty_chunks.iter().any(|(kept_dimension, kept_pos)| *kept_dimension == other_dimension.id && *kept_pos == cp);
+                    if !in_view && !kept {
+                        chunks_to_unload.push_back((other_dimension.id.clone(), cp));
+                    }
+                }
+            }
+        }
+
         worker_yield();
     }
 }
\ No newline at end of file